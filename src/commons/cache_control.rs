@@ -0,0 +1,60 @@
+// (c) Copyright 2019-2024 OLX
+
+use std::time::{Duration, SystemTime};
+
+use super::config::Configuration;
+
+// the value of the Cache-Control header of the processed images, none when nothing is configured
+pub fn cache_control(config: &Configuration) -> Option<String> {
+    let mut directives = vec![];
+    if let Some(max_age) = config.cache_max_age_secs {
+        directives.push(format!("max-age={}", max_age));
+    }
+    if let Some(s_maxage) = config.cache_s_maxage_secs {
+        directives.push(format!("s-maxage={}", s_maxage));
+    }
+    if let Some(stale) = config.cache_stale_while_revalidate_secs {
+        directives.push(format!("stale-while-revalidate={}", stale));
+    }
+    if config.cache_immutable.unwrap_or(false) {
+        directives.push("immutable".to_string());
+    }
+    if directives.is_empty() {
+        return None;
+    }
+    directives.insert(0, "public".to_string());
+    Some(directives.join(", "))
+}
+
+// the Expires date matching the max-age, for the http/1.0 caches
+pub fn expires(config: &Configuration, now: SystemTime) -> Option<SystemTime> {
+    config
+        .cache_max_age_secs
+        .map(|max_age| now + Duration::from_secs(max_age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_cache_control() {
+        let mut config = Configuration::for_tests(serde_json::json!({}));
+        assert_eq!(cache_control(&config), None);
+        assert_eq!(expires(&config, UNIX_EPOCH), None);
+
+        config.cache_max_age_secs = Some(3600);
+        config.cache_s_maxage_secs = Some(86400);
+        config.cache_stale_while_revalidate_secs = Some(60);
+        config.cache_immutable = Some(true);
+        assert_eq!(
+            cache_control(&config).as_deref(),
+            Some("public, max-age=3600, s-maxage=86400, stale-while-revalidate=60, immutable")
+        );
+        assert_eq!(
+            expires(&config, UNIX_EPOCH),
+            Some(UNIX_EPOCH + Duration::from_secs(3600))
+        );
+    }
+}
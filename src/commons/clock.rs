@@ -0,0 +1,27 @@
+// (c) Copyright 2019-2024 OLX
+
+use std::time::SystemTime;
+
+// source of the current time for everything that reasons about expiry or freshness, so that the
+// behaviour can be tested with a clock which doesn't move on its own
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[cfg(test)]
+pub struct FixedClock(pub SystemTime);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
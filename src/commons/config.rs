@@ -17,6 +17,105 @@ pub struct Configuration {
     pub reqwest_connection_timeout_millis: Option<u16>,
     pub reqwest_pool_max_idle_per_host: Option<u16>,
     pub reqwest_pool_idle_timeout_millis: Option<u16>,
+    pub mmap_threshold_bytes: Option<u64>,
+    pub preflight_hosts: Option<Vec<String>>,
+    pub max_source_size_bytes: Option<u64>,
+    pub autorotate_bypass_models: Option<Vec<String>>,
+    pub origin_base_urls: Option<Vec<String>>,
+    pub origin_weights: Option<Vec<u32>>,
+    pub purge_tombstone_ttl_secs: Option<u64>,
+    pub embed_provenance: Option<bool>,
+    pub accept_ch: Option<Vec<String>>,
+    pub critical_ch: Option<Vec<String>>,
+    pub accept_ch_lifetime_secs: Option<u64>,
+    pub provider: Option<String>,
+    pub provider_routes: Option<Vec<String>>,
+    pub s3_region: Option<String>,
+    pub s3_key: Option<String>,
+    pub s3_secret: Option<String>,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_prefix: Option<String>,
+    pub gcs_bucket: Option<String>,
+    pub gcs_prefix: Option<String>,
+    pub gcs_service_account_key_path: Option<String>,
+    pub sftp_host: Option<String>,
+    pub sftp_port: Option<u16>,
+    pub sftp_username: Option<String>,
+    pub sftp_password: Option<String>,
+    pub sftp_private_key_path: Option<String>,
+    pub sftp_known_hosts_path: Option<String>,
+    pub sftp_base_path: Option<String>,
+    pub sftp_timeout_millis: Option<u32>,
+    pub redis_url: Option<String>,
+    pub redis_ttl_secs: Option<u64>,
+    pub redis_key_prefix: Option<String>,
+    pub redis_max_object_bytes: Option<u64>,
+    pub memory_cache_max_bytes: Option<u64>,
+    pub disk_cache_max_bytes: Option<u64>,
+    pub disk_cache_ttl_secs: Option<u64>,
+    pub disk_cache_eviction_interval_secs: Option<u64>,
+    pub circuit_breaker_failure_threshold: Option<u32>,
+    pub circuit_breaker_open_secs: Option<u64>,
+    pub origin_allowlist: Option<Vec<String>>,
+    pub block_internal_origins: Option<bool>,
+    pub origin_headers: Option<Vec<String>>,
+    pub sigv4_hosts: Option<Vec<String>>,
+    pub sigv4_region: Option<String>,
+    pub sigv4_service: Option<String>,
+    pub sigv4_access_key: Option<String>,
+    pub sigv4_secret_key: Option<String>,
+    pub mirror_revalidate_after_secs: Option<u64>,
+    pub max_upload_size_bytes: Option<usize>,
+    pub origin_max_requests_per_sec: Option<u32>,
+    pub origin_max_concurrent_requests: Option<usize>,
+    pub origin_proxy_url: Option<String>,
+    pub origin_no_proxy: Option<Vec<String>>,
+    pub origin_proxy_overrides: Option<Vec<String>>,
+    pub hot_refresh_min_hits: Option<u64>,
+    pub hot_refresh_max_sources: Option<usize>,
+    pub hot_refresh_ahead_secs: Option<u64>,
+    pub hot_refresh_interval_secs: Option<u64>,
+    pub sink: Option<String>,
+    pub sink_path: Option<String>,
+    pub sink_base_url: Option<String>,
+    pub sink_s3_bucket: Option<String>,
+    pub sink_s3_prefix: Option<String>,
+    pub avif_effort: Option<i32>,
+    pub avif_chroma_subsampling: Option<String>,
+    pub tiff_compression: Option<String>,
+    pub tiff_tile_size: Option<i32>,
+    pub strip_metadata: Option<bool>,
+    pub enlarge: Option<bool>,
+    pub watermark_cache_ttl_secs: Option<u64>,
+    pub max_dpr: Option<f64>,
+    pub max_source_megapixels: Option<u64>,
+    pub processing_timeout_millis: Option<u64>,
+    pub flatten_background: Option<String>,
+    pub pdf_dpi: Option<f64>,
+    pub ffmpeg_path: Option<String>,
+    pub webp_effort: Option<i32>,
+    pub webp_smart_subsample: Option<bool>,
+    pub webp_near_lossless: Option<bool>,
+    pub webp_alpha_q: Option<i32>,
+    pub heic_effort: Option<i32>,
+    pub avif_encoder: Option<String>,
+    pub heif_bitdepth: Option<i32>,
+    pub cache_max_age_secs: Option<u64>,
+    pub cache_s_maxage_secs: Option<u64>,
+    pub cache_stale_while_revalidate_secs: Option<u64>,
+    pub cache_immutable: Option<bool>,
+    pub imgproxy_prefix: Option<String>,
+    pub imgproxy_key: Option<String>,
+    pub imgproxy_salt: Option<String>,
+    pub thumbor_prefix: Option<String>,
+    pub thumbor_security_key: Option<String>,
+    pub thumbor_allow_unsafe: Option<bool>,
+    pub memory_cache_ttl_secs: Option<u64>,
+    pub passthrough_unchanged_sources: Option<bool>,
+    pub ffmpeg_timeout_secs: Option<u64>,
+    pub origin_max_wait_secs: Option<u64>,
+    pub sftp_pool_size: Option<usize>,
 }
 
 impl fmt::Display for Configuration {
@@ -31,8 +130,38 @@ impl Configuration {
         let s = Config::builder()
             .add_source(File::with_name("config/default").required(false))
             .add_source(File::with_name(&format!("config/{}", run_mode)).required(false))
-            .add_source(Environment::default())
+            .add_source(
+                Environment::default()
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("preflight_hosts")
+                    .with_list_parse_key("autorotate_bypass_models")
+                    .with_list_parse_key("origin_base_urls")
+                    .with_list_parse_key("origin_weights")
+                    .with_list_parse_key("accept_ch")
+                    .with_list_parse_key("critical_ch")
+                    .with_list_parse_key("provider_routes")
+                    .with_list_parse_key("origin_allowlist")
+                    .with_list_parse_key("origin_headers")
+                    .with_list_parse_key("sigv4_hosts")
+                    .with_list_parse_key("origin_no_proxy")
+                    .with_list_parse_key("origin_proxy_overrides"),
+            )
             .build()?;
         s.try_deserialize()
     }
+
+    // the required fields, along with the ones set by the test
+    #[cfg(test)]
+    pub fn for_tests(fields: serde_json::Value) -> Self {
+        let mut config = serde_json::json!({
+            "app_port": 8080,
+            "health_port": 8081,
+            "public_img_path": "/public",
+        });
+        if let serde_json::Value::Object(fields) = fields {
+            config.as_object_mut().unwrap().extend(fields);
+        }
+        serde_json::from_value(config).unwrap()
+    }
 }
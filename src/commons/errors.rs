@@ -1,6 +1,6 @@
 // (c) Copyright 2019-2024 OLX
 
-use crate::commons::Size;
+use super::model::Size;
 use std::convert::From;
 use std::error::Error;
 use std::fmt;
@@ -40,9 +40,3 @@ impl From<InvalidSizeError> for String {
         format!("InvalidSizeError: {}", error)
     }
 }
-
-impl From<InvalidSizeError> for libvips::error::Error {
-    fn from(_error: InvalidSizeError) -> Self {
-        libvips::error::Error::InitializationError("Invalid size")
-    }
-}
@@ -0,0 +1,48 @@
+// (c) Copyright 2019-2024 OLX
+
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+// the file metadata the mirror and the Last-Modified logic rely on, abstracted so the expiry and
+// 304 behaviour can be tested without depending on the mtimes of real files
+#[async_trait]
+pub trait FileSystem: Send + Sync {
+    async fn exists(&self, path: &Path) -> bool;
+    async fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+}
+
+pub struct LocalFileSystem;
+
+#[async_trait]
+impl FileSystem for LocalFileSystem {
+    async fn exists(&self, path: &Path) -> bool {
+        tokio::fs::try_exists(path).await.unwrap_or(false)
+    }
+
+    async fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        tokio::fs::metadata(path).await?.modified()
+    }
+}
+
+#[cfg(test)]
+pub struct InMemoryFileSystem {
+    pub files: std::collections::HashMap<std::path::PathBuf, SystemTime>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl FileSystem for InMemoryFileSystem {
+    async fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    async fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.files
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
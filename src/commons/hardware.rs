@@ -0,0 +1,56 @@
+// (c) Copyright 2019-2024 OLX
+
+use libvips::{bindings, ops, VipsApp};
+use log::*;
+use serde::Serialize;
+use std::time::Instant;
+
+// side of the synthetic image used to time a resize at startup. big enough to make the
+// difference between a vectorized and a scalar libvips build visible in the logs.
+const PROBE_IMAGE_SIDE: i32 = 2048;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HardwareInfo {
+    pub libvips_version: String,
+    pub vector_enabled: bool,
+    pub probe_duration_millis: Option<u128>,
+}
+
+pub fn detect(vips_app: &VipsApp) -> HardwareInfo {
+    let libvips_version = vips_app.version_string().unwrap_or("unknown").to_string();
+    let vector_enabled = unsafe { bindings::vips_vector_isenabled() != 0 };
+    let probe_duration_millis = run_resize_probe();
+
+    if vector_enabled {
+        info!(
+            "libvips {} is running with SIMD vectorization enabled. resize probe took {:?}ms",
+            libvips_version, probe_duration_millis
+        );
+    } else {
+        warn!(
+            "libvips {} is running WITHOUT SIMD vectorization (highway/orc), image processing will be considerably slower. resize probe took {:?}ms",
+            libvips_version, probe_duration_millis
+        );
+    }
+
+    HardwareInfo {
+        libvips_version,
+        vector_enabled,
+        probe_duration_millis,
+    }
+}
+
+fn run_resize_probe() -> Option<u128> {
+    let start = Instant::now();
+    // libvips is lazy, computing the average forces the whole pipeline to be evaluated
+    let result = ops::black(PROBE_IMAGE_SIDE, PROBE_IMAGE_SIDE)
+        .and_then(|image| ops::resize(&image, 0.5))
+        .and_then(|image| ops::avg(&image));
+    match result {
+        Ok(_) => Some(start.elapsed().as_millis()),
+        Err(e) => {
+            warn!("failed to run the libvips resize probe. error: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// (c) Copyright 2019-2024 OLX
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::clock::Clock;
+use super::filesystem::FileSystem;
+
+pub async fn get_last_modified(
+    filesystem: &dyn FileSystem,
+    clock: &dyn Clock,
+    path: &Path,
+) -> Option<SystemTime> {
+    let modified = filesystem.modified(path).await.ok()?;
+    // a Last-Modified date later than the date of the response is not valid
+    Some(modified.min(clock.now()))
+}
+
+pub fn is_not_modified(if_modified_since: Option<&str>, last_modified: SystemTime) -> bool {
+    let Some(since) = if_modified_since.and_then(|value| httpdate::parse_http_date(value).ok())
+    else {
+        return false;
+    };
+    // http dates only have a precision of seconds
+    let last_modified_secs = last_modified
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(last_modified_secs) <= since
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::clock::FixedClock;
+    use crate::commons::filesystem::InMemoryFileSystem;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[tokio::test]
+    async fn test_get_last_modified() {
+        let filesystem = InMemoryFileSystem {
+            files: HashMap::from([
+                (PathBuf::from("/public/old.jpg"), at(1_000)),
+                (PathBuf::from("/public/future.jpg"), at(5_000)),
+            ]),
+        };
+        let clock = FixedClock(at(2_000));
+        assert_eq!(
+            get_last_modified(&filesystem, &clock, Path::new("/public/old.jpg")).await,
+            Some(at(1_000))
+        );
+        assert_eq!(
+            get_last_modified(&filesystem, &clock, Path::new("/public/future.jpg")).await,
+            Some(at(2_000))
+        );
+        assert_eq!(
+            get_last_modified(&filesystem, &clock, Path::new("/public/missing.jpg")).await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let last_modified = at(1_700_000_000) + Duration::from_millis(250);
+        let same_second = httpdate::fmt_http_date(at(1_700_000_000));
+        let later = httpdate::fmt_http_date(at(1_700_000_100));
+        let earlier = httpdate::fmt_http_date(at(1_699_999_900));
+        assert!(is_not_modified(Some(&same_second), last_modified));
+        assert!(is_not_modified(Some(&later), last_modified));
+        assert!(!is_not_modified(Some(&earlier), last_modified));
+        assert!(!is_not_modified(Some("not a date"), last_modified));
+        assert!(!is_not_modified(None, last_modified));
+    }
+}
@@ -0,0 +1,72 @@
+// (c) Copyright 2019-2024 OLX
+
+// the local paths are built from the image addresses of the requests, so they are resolved
+// within the configured root and anything which would end up outside of it is rejected, be it
+// through `..` or through a symlink pointing elsewhere.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// resolves the relative path within the root, or returns `None` when it escapes the root
+pub fn contained_path(root: &str, relative: &str) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(relative.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    let path = Path::new(root).join(normalized);
+
+    // the path may not exist yet (e.g. an image which is about to be mirrored), so the deepest
+    // existing ancestor is the one which has to stay within the root once the symlinks resolve
+    if let Ok(canonical_root) = fs::canonicalize(root) {
+        let resolved = path
+            .ancestors()
+            .find_map(|ancestor| fs::canonicalize(ancestor).ok());
+        if resolved.is_some_and(|resolved| !resolved.starts_with(&canonical_root)) {
+            return None;
+        }
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contained_path() {
+        assert_eq!(
+            contained_path("/srv/img", "/photos/a.jpg"),
+            Some(PathBuf::from("/srv/img/photos/a.jpg"))
+        );
+        assert_eq!(
+            contained_path("/srv/img", "photos/../b.jpg"),
+            Some(PathBuf::from("/srv/img/b.jpg"))
+        );
+        assert_eq!(contained_path("/srv/img", "../../etc/passwd"), None);
+        assert_eq!(contained_path("/srv/img", "photos/../../secret.jpg"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_escaping_the_root_are_rejected() {
+        let root = std::env::temp_dir().join(format!("dali-local-path-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let link = root.join("outside");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink("/", &link).unwrap();
+        let root_str = root.to_str().unwrap();
+        assert_eq!(contained_path(root_str, "outside/etc/passwd"), None);
+        assert_eq!(contained_path(root_str, "outside/not-there/a.jpg"), None);
+        assert!(contained_path(root_str, "inside/a.jpg").is_some());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,2407 @@
+// (c) Copyright 2019-2024 OLX
+
+use super::errors::InvalidSizeError;
+use log::*;
+use serde::de::{self, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProcessImageRequest {
+    pub image_address: String,
+    #[serde(default)]
+    pub size: Size,
+    #[serde(default)]
+    pub fit: Fit,
+    // whether the image may be upscaled to the requested size, `enlarge` when missing
+    #[serde(default)]
+    pub enlarge: Option<bool>,
+    #[serde(default)]
+    pub kernel: Kernel,
+    // the device pixel ratio the size is multiplied by, from 1 up to the configured maximum
+    #[serde(default)]
+    pub dpr: Option<f64>,
+    // the color of the bars added by `fit=contain` and of the padding added by `square`
+    #[serde(default)]
+    pub background: Color,
+    #[serde(default)]
+    pub format: ImageFormat,
+    // 80 when missing, the source may then be served as it is, see `is_passthrough`
+    #[serde(default)]
+    pub quality: Option<Quality>,
+    // the encoder effort for the formats which have one, the format default when missing
+    #[serde(default)]
+    pub effort: Option<i32>,
+    // lossless compression of the Webp images, instead of the `quality` driven one
+    #[serde(default)]
+    pub lossless: bool,
+    // the Webp tuning, the `webp_*` settings when missing
+    #[serde(default)]
+    pub smart_subsample: Option<bool>,
+    #[serde(default)]
+    pub near_lossless: Option<bool>,
+    #[serde(default)]
+    pub alpha_q: Option<i32>,
+    // progressive Jpeg and Adam7 interlaced Png images, the format default when missing
+    #[serde(default)]
+    pub interlace: Option<bool>,
+    // 8-bit palette Png images, quantized to `png_colors` colors at most
+    #[serde(default)]
+    pub png_palette: bool,
+    #[serde(default)]
+    pub png_colors: Option<i32>,
+    #[serde(default)]
+    pub png_dither: Option<f64>,
+    // the zlib compression level of the Png images
+    #[serde(default)]
+    pub compression: Option<i32>,
+    // the bits per channel of the Png, Heic and Avif images, 8 unless a deeper one is asked for
+    #[serde(default)]
+    pub bitdepth: Option<i32>,
+    // removes the EXIF, XMP and IPTC metadata from the image, `strip_metadata` when missing
+    #[serde(default)]
+    pub strip: Option<bool>,
+    // keeps the embedded ICC profile instead of converting the colors to sRGB
+    #[serde(default)]
+    pub keep_profile: bool,
+    // the color space the image is converted to, with its ICC profile embedded
+    #[serde(default)]
+    pub colorspace: Option<ColorSpace>,
+    // the frame of an animation or the page of a multi-page document the image is made of, from 0
+    #[serde(default)]
+    pub page: Option<i32>,
+    // whether an animation keeps all of its frames, when the format can carry them
+    #[serde(default)]
+    pub animated: Option<bool>,
+    // the position of the poster frame of a video source, in seconds from its start
+    #[cfg(feature = "video")]
+    #[serde(default)]
+    pub t: Option<f64>,
+    #[serde(default)]
+    pub watermarks: Vec<Watermark>,
+    #[serde(default)]
+    pub texts: Vec<TextWatermark>,
+    #[serde(default)]
+    pub rotation: Option<Rotation>,
+    // mirrors the image upside down
+    #[serde(default)]
+    pub flip: bool,
+    // mirrors the image left to right
+    #[serde(default)]
+    pub flop: bool,
+    // the sigma of the gaussian blur, applied before the watermarks
+    #[serde(default)]
+    pub blur: Option<f64>,
+    #[serde(default)]
+    pub sharpen: Option<Sharpen>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    // factors of the brightness, the contrast and the saturation of the image, 1 keeping it as is
+    #[serde(default)]
+    pub brightness: Option<f64>,
+    #[serde(default)]
+    pub contrast: Option<f64>,
+    #[serde(default)]
+    pub saturation: Option<f64>,
+    // applied to the final image, watermarks included
+    #[serde(default)]
+    pub gamma: Option<f64>,
+    #[serde(default)]
+    pub crop: Crop,
+    // cuts the uniform borders of the image, the ones of the `trim_background` color or else of
+    // the color of the top left pixel
+    #[serde(default)]
+    pub trim: bool,
+    #[serde(default)]
+    pub trim_threshold: Option<f64>,
+    #[serde(default)]
+    pub trim_background: Option<Color>,
+    // the focal point the crops are centered on, as fractions of the width and the height
+    #[serde(default)]
+    pub fx: Option<f64>,
+    #[serde(default)]
+    pub fy: Option<f64>,
+    #[serde(default = "default_square")]
+    pub square: bool,
+    #[serde(default)]
+    pub border: Option<Border>,
+    // rounds the corners of the image, for the formats with an alpha channel
+    #[serde(default)]
+    pub radius: Option<Radius>,
+    #[serde(default = "default_autorotate")]
+    pub autorotate: bool,
+    // regions of the final image turned into mosaics, e.g. license plates and faces
+    #[serde(default)]
+    pub pixelate: Vec<Pixelate>,
+    // the widths of the variants returned at once, e.g. for a srcset
+    #[serde(default)]
+    pub widths: Option<Widths>,
+    // the operations applied in the given order, after the crop, the resizing and the color
+    // adjustments of the fixed pipeline, and in place of its watermarks
+    #[serde(default, rename = "ops")]
+    pub operations: Option<Operations>,
+    #[serde(default)]
+    pub trace: bool,
+    // returns the steps of the pipeline in the `X-Dali-Trace` header
+    #[serde(default)]
+    pub debug: bool,
+}
+
+impl ProcessImageRequest {
+    // whether the request leaves the source of the given format as it is, in which case the
+    // source is served rather than decoded and encoded again. the size related parameters only
+    // matter along with a size.
+    pub fn is_passthrough(&self, source_format: Option<&str>) -> bool {
+        let ProcessImageRequest {
+            image_address: _,
+            size,
+            fit: _,
+            enlarge: _,
+            kernel: _,
+            dpr: _,
+            background: _,
+            format,
+            quality,
+            effort,
+            lossless,
+            smart_subsample,
+            near_lossless,
+            alpha_q,
+            interlace,
+            png_palette,
+            png_colors: _,
+            png_dither: _,
+            compression,
+            bitdepth,
+            strip,
+            keep_profile: _,
+            colorspace,
+            page,
+            animated,
+            #[cfg(feature = "video")]
+                t: _,
+            watermarks,
+            texts,
+            rotation,
+            flip,
+            flop,
+            blur,
+            sharpen,
+            filter,
+            brightness,
+            contrast,
+            saturation,
+            gamma,
+            crop,
+            trim,
+            trim_threshold: _,
+            trim_background: _,
+            fx: _,
+            fy: _,
+            square,
+            border,
+            radius,
+            autorotate: _,
+            pixelate,
+            widths,
+            operations,
+            trace,
+            debug,
+        } = self;
+        // the HEIF signature is the one of the AVIF images too
+        !matches!(format, ImageFormat::Heic)
+            && source_format == Some(format.to_string().as_str())
+            && size.width.is_none()
+            && size.height.is_none()
+            && quality.is_none()
+            && effort.is_none()
+            && !lossless
+            && smart_subsample.is_none()
+            && near_lossless.is_none()
+            && alpha_q.is_none()
+            && interlace.is_none()
+            && !png_palette
+            && compression.is_none()
+            && bitdepth.is_none()
+            && *strip != Some(true)
+            && colorspace.is_none()
+            && page.is_none()
+            && *animated != Some(false)
+            && watermarks.is_empty()
+            && texts.is_empty()
+            && rotation.is_none()
+            && !flip
+            && !flop
+            && blur.is_none()
+            && sharpen.is_none()
+            && filter.is_none()
+            && brightness.is_none()
+            && contrast.is_none()
+            && saturation.is_none()
+            && gamma.is_none()
+            && crop.w.is_none()
+            && crop.h.is_none()
+            && crop.rect.is_none()
+            && !trim
+            && !square
+            && border.is_none()
+            && radius.is_none()
+            && pixelate.is_empty()
+            && widths.is_none()
+            && operations.is_none()
+            && !trace
+            && !debug
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Watermark {
+    // empty for the watermarks uploaded along with the image
+    #[serde(default)]
+    pub image_address: String,
+    #[serde(default)]
+    pub position: Point,
+    #[serde(default)]
+    pub alpha: f64,
+    #[serde(default = "default_watermark_size")]
+    pub size: f64,
+    #[serde(default)]
+    pub blend: Blend,
+    // the watermark is skipped on the images smaller than these
+    #[serde(default)]
+    pub min_width: Option<i32>,
+    #[serde(default)]
+    pub min_height: Option<i32>,
+}
+
+impl Watermark {
+    pub fn fits(&self, image_width: i32, image_height: i32) -> bool {
+        !matches!(self.min_width, Some(min) if image_width < min)
+            && !matches!(self.min_height, Some(min) if image_height < min)
+    }
+
+    // the name of the watermark in the `ops` chain, its file name without the extension
+    pub fn is_named(&self, name: &str) -> bool {
+        let file = self.image_address.rsplit('/').next().unwrap_or_default();
+        file.split('.').next() == Some(name)
+    }
+}
+
+// how the watermark is blended with the image, as in the usual image editors
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Blend {
+    #[default]
+    Over,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColourDodge,
+    ColourBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+}
+
+// a line of text rendered with Pango and composited over the image like the watermarks
+#[derive(Debug, Deserialize, Clone)]
+pub struct TextWatermark {
+    pub text: String,
+    #[serde(default = "default_text_font")]
+    pub font: String,
+    // in pixels
+    #[serde(default = "default_text_size")]
+    pub size: i32,
+    #[serde(default)]
+    pub color: Color,
+    #[serde(default)]
+    pub position: Point,
+    #[serde(default = "default_text_alpha")]
+    pub alpha: f64,
+}
+
+// several images laid out side by side in a single one, e.g. for the multi-photo previews
+#[derive(Debug, Deserialize, Clone)]
+pub struct CollageRequest {
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub layout: Layout,
+    // the size of every cell, which the images cover. square when only the width is given
+    #[serde(default)]
+    pub size: Size,
+    // in pixels, between the cells
+    #[serde(default)]
+    pub spacing: i32,
+    #[serde(default)]
+    pub background: Color,
+    #[serde(default)]
+    pub format: ImageFormat,
+    #[serde(default = "default_quality")]
+    pub quality: Quality,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum Layout {
+    // as many columns as rows, the last row being the one left incomplete
+    #[default]
+    #[serde(rename = "grid")]
+    Grid,
+    // up to 4 images, 2 per row
+    #[serde(rename = "2x2")]
+    TwoByTwo,
+    // a single row
+    #[serde(rename = "strip")]
+    Strip,
+}
+
+impl Layout {
+    pub fn max_images(&self) -> Option<usize> {
+        match self {
+            Layout::TwoByTwo => Some(4),
+            _ => None,
+        }
+    }
+
+    pub fn columns(&self, images: usize) -> usize {
+        match self {
+            Layout::Grid => (1..)
+                .find(|columns| columns * columns >= images)
+                .unwrap_or(1),
+            Layout::TwoByTwo => 2,
+            Layout::Strip => images.max(1),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Size {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+// an unsharp mask, the sigma being the radius of the mask and the amount how much the edges
+// are sharpened
+#[derive(Debug, Deserialize, Clone)]
+pub struct Sharpen {
+    #[serde(default = "default_sharpen_sigma")]
+    pub sigma: f64,
+    #[serde(default = "default_sharpen_amount")]
+    pub amount: f64,
+}
+
+// how the image is fitted into the requested size when both its width and height are given
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // scaled down to fit within the size, keeping the aspect ratio. never upscaled
+    #[default]
+    Inside,
+    // scaled to cover the size, keeping the aspect ratio, so one side may be bigger
+    Outside,
+    // scaled to cover the size, then cropped to it around the center
+    Cover,
+    // scaled to fit within the size, then extended to it with the background color
+    Contain,
+    // stretched to the size, ignoring the aspect ratio
+    Fill,
+}
+
+// the interpolation kernel the image is resized with
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kernel {
+    // the sharpest, meant for photos
+    #[default]
+    Lanczos3,
+    Mitchell,
+    Cubic,
+    // keeps the hard edges of pixel art and charts
+    Nearest,
+}
+
+// the output color spaces, CMYK being only written by the Jpeg and Tiff formats
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    Srgb,
+    // the wide gamut Display P3 of the recent screens
+    P3,
+    Gray,
+    Cmyk,
+}
+
+// a hexadecimal RGB color such as `ffffff`, optionally followed by its alpha such as `ffffff80`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    pub fn is_opaque(&self) -> bool {
+        self.0[3] == u8::MAX
+    }
+
+    pub fn opaque(self) -> Color {
+        let [r, g, b, _] = self.0;
+        Color([r, g, b, u8::MAX])
+    }
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color([255, 255, 255, 255])
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').unwrap_or(&value);
+        let (rgb, alpha) = match hex.len() {
+            8 if hex.is_ascii() => hex.split_at(6),
+            _ => (hex, "ff"),
+        };
+        let [r, g, b] = parse_hex_color(rgb)?;
+        let alpha =
+            u8::from_str_radix(alpha, 16).map_err(|_| format!("invalid color `{}`", value))?;
+        Ok(Color([r, g, b, alpha]))
+    }
+}
+
+// `<width>,<color>`, a solid frame added around the image, black when the color is missing
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Border {
+    pub width: i32,
+    pub color: Color,
+}
+
+impl TryFrom<String> for Border {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (width, color) = match value.split_once(',') {
+            Some((width, color)) => (width, Color::try_from(String::from(color))?),
+            None => (value.as_str(), Color([0, 0, 0, 255])),
+        };
+        match width.trim().parse::<i32>() {
+            Ok(width) if width >= 0 => Ok(Border { width, color }),
+            _ => Err(format!("invalid border `{}`", value)),
+        }
+    }
+}
+
+// `<x>,<y>,<width>,<height>,<size>`, a region of the final image turned into a mosaic of
+// `size` pixels wide blocks
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Pixelate {
+    pub rect: CropRect,
+    pub size: i32,
+}
+
+impl TryFrom<String> for Pixelate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let values = value
+            .split(',')
+            .map(|v| v.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid pixelated region `{}`", value))?;
+        match values[..] {
+            [x, y, width, height, size]
+                if x >= 0 && y >= 0 && width > 0 && height > 0 && size > 0 =>
+            {
+                Ok(Pixelate {
+                    rect: CropRect {
+                        x,
+                        y,
+                        width,
+                        height,
+                    },
+                    size,
+                })
+            }
+            _ => Err(format!("invalid pixelated region `{}`", value)),
+        }
+    }
+}
+
+// `<value>` from 1 to 100, or `auto`, which picks the value according to the details of the image
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Quality {
+    Value(i32),
+    Auto,
+}
+
+impl TryFrom<String> for Quality {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "auto" => Ok(Quality::Auto),
+            quality => quality
+                .parse::<i32>()
+                .map(Quality::Value)
+                .map_err(|_| format!("invalid quality `{}`", value)),
+        }
+    }
+}
+
+// `<width>,<width>,...`, from the largest to the smallest once parsed
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Widths(pub Vec<i32>);
+
+const MAX_WIDTHS: usize = 8;
+
+impl TryFrom<String> for Widths {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut widths = value
+            .split(',')
+            .map(|width| match width.trim().parse::<i32>() {
+                Ok(width) if width > 0 => Ok(width),
+                _ => Err(format!("invalid widths `{}`", value)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        widths.sort_unstable_by(|a, b| b.cmp(a));
+        widths.dedup();
+        if widths.len() > MAX_WIDTHS {
+            return Err(format!("at most {} widths are allowed", MAX_WIDTHS));
+        }
+        Ok(Widths(widths))
+    }
+}
+
+impl Widths {
+    // the size the largest variant is processed at. the height follows the width when both are
+    // requested, so that the variants keep the requested aspect ratio.
+    pub fn largest_size(&self, size: &Size) -> Size {
+        let largest = self.0[0];
+        Size {
+            width: Some(largest),
+            height: match (size.width, size.height) {
+                (Some(width), Some(height)) if width > 0 => {
+                    Some((f64::from(height) * f64::from(largest) / f64::from(width)).round() as i32)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+// `<pixels>` or `max`, which makes a circle out of a square image
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Radius {
+    Pixels(i32),
+    Max,
+}
+
+impl TryFrom<String> for Radius {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "max" => Ok(Radius::Max),
+            pixels => match pixels.parse::<i32>() {
+                Ok(pixels) if pixels >= 0 => Ok(Radius::Pixels(pixels)),
+                _ => Err(format!("invalid radius `{}`", value)),
+            },
+        }
+    }
+}
+
+// `greyscale`, `sepia` or `duotone:<dark>:<light>`, the colors of the duotone being hexadecimal
+// RGB ones such as `1e3264`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Filter {
+    Greyscale,
+    Sepia,
+    Duotone { dark: [u8; 3], light: [u8; 3] },
+}
+
+impl TryFrom<String> for Filter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = value.split(':').collect();
+        match parts[..] {
+            ["greyscale"] | ["grayscale"] => Ok(Filter::Greyscale),
+            ["sepia"] => Ok(Filter::Sepia),
+            ["duotone", dark, light] => Ok(Filter::Duotone {
+                dark: parse_hex_color(dark)?,
+                light: parse_hex_color(light)?,
+            }),
+            _ => Err(format!("unknown filter `{}`", value)),
+        }
+    }
+}
+
+// a step of the `ops` chain: `resize:<width>x<height>`, either of which may be left out,
+// `blur:<sigma>`, `rotate:<90|180|270>`, `flip`, `flop`, `watermark:<name>` or a filter. the
+// watermarks are named after their file without its extension, or by their index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Resize(Option<i32>, Option<i32>),
+    Blur(f64),
+    Rotate(Rotation),
+    Flip,
+    Flop,
+    Watermark(String),
+    Filter(Filter),
+}
+
+// `<operation>,<operation>,...`, applied in this order
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Operations(pub Vec<Operation>);
+
+const MAX_OPERATIONS: usize = 16;
+
+impl TryFrom<String> for Operations {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let operations = value
+            .split(',')
+            .map(|operation| Operation::try_from(operation.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if operations.len() > MAX_OPERATIONS {
+            return Err(format!("at most {} operations are allowed", MAX_OPERATIONS));
+        }
+        Ok(Operations(operations))
+    }
+}
+
+impl TryFrom<&str> for Operation {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid operation `{}`", value);
+        let dimension = |dimension: &str| match dimension {
+            "" => Ok(None),
+            dimension => match dimension.parse::<i32>() {
+                Ok(dimension) if dimension > 0 => Ok(Some(dimension)),
+                _ => Err(invalid()),
+            },
+        };
+        match value.split_once(':') {
+            Some(("resize", size)) => {
+                let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+                match (dimension(width)?, dimension(height)?) {
+                    (None, None) => Err(invalid()),
+                    (width, height) => Ok(Operation::Resize(width, height)),
+                }
+            }
+            Some(("blur", sigma)) => match sigma.parse::<f64>() {
+                Ok(sigma) if sigma > 0.0 => Ok(Operation::Blur(sigma)),
+                _ => Err(invalid()),
+            },
+            Some(("rotate", "90")) => Ok(Operation::Rotate(Rotation::R90)),
+            Some(("rotate", "180")) => Ok(Operation::Rotate(Rotation::R180)),
+            Some(("rotate", "270")) => Ok(Operation::Rotate(Rotation::R270)),
+            Some(("watermark", name)) if !name.is_empty() => {
+                Ok(Operation::Watermark(name.to_string()))
+            }
+            None if value == "flip" => Ok(Operation::Flip),
+            None if value == "flop" => Ok(Operation::Flop),
+            _ => Filter::try_from(value.to_string())
+                .map(Operation::Filter)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+pub fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("invalid color `{}`", value));
+    }
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid color `{}`", value))?;
+    }
+    Ok(color)
+}
+
+// either the size of the smart crop, as `crop[w]=..&crop[h]=..`, or the exact rectangle to
+// extract from the original image, as `crop=x,y,w,h`
+#[derive(Debug, Clone, Default)]
+pub struct Crop {
+    pub w: Option<i32>,
+    pub h: Option<i32>,
+    pub rect: Option<CropRect>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl CropRect {
+    // the part of the rectangle within the image, if any
+    pub fn clamp_to(&self, image_width: i32, image_height: i32) -> Option<CropRect> {
+        let (x, y) = (self.x.min(image_width), self.y.min(image_height));
+        let width = self.width.min(image_width - x);
+        let height = self.height.min(image_height - y);
+        (width > 0 && height > 0).then_some(CropRect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Crop {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(CropVisitor)
+    }
+}
+
+struct CropVisitor;
+
+impl<'de> Visitor<'de> for CropVisitor {
+    type Value = Crop;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a crop size or an `x,y,w,h` rectangle")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Crop, E> {
+        let invalid = || E::custom(format!("invalid crop rectangle `{}`", value));
+        let measures = value
+            .split(',')
+            .map(|measure| measure.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        match measures[..] {
+            [x, y, width, height] if x >= 0 && y >= 0 && width > 0 && height > 0 => Ok(Crop {
+                rect: Some(CropRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                }),
+                ..Crop::default()
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Crop, A::Error> {
+        let mut crop = Crop::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "w" => crop.w = map.next_value()?,
+                "h" => crop.h = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(crop)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub enum WatermarkPosition {
+    Center,
+    #[default]
+    Point,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum Rotation {
+    R90,
+    R180,
+    R270,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Point {
+    x: HorizontalPosition,
+    y: VerticalPosition,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "origin", content = "pos")]
+pub enum HorizontalPosition {
+    Left(i32),
+    Right(i32),
+    Center,
+    // a percentage of the width from the left, or from the right when negative
+    Percent(f64),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "origin", content = "pos")]
+pub enum VerticalPosition {
+    Top(i32),
+    Bottom(i32),
+    Center,
+    // a percentage of the height from the top, or from the bottom when negative
+    Percent(f64),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    #[default]
+    Jpeg,
+    Webp,
+    Heic,
+    Avif,
+    Gif,
+    Jxl,
+    Tiff,
+}
+
+fn default_square() -> bool {
+    false
+}
+
+fn default_autorotate() -> bool {
+    true
+}
+
+fn default_quality() -> Quality {
+    Quality::Value(80)
+}
+
+fn default_watermark_size() -> f64 {
+    10.0
+}
+
+fn default_text_font() -> String {
+    String::from("sans")
+}
+
+fn default_text_size() -> i32 {
+    24
+}
+
+fn default_text_alpha() -> f64 {
+    1.0
+}
+
+fn default_sharpen_sigma() -> f64 {
+    0.5
+}
+
+fn default_sharpen_amount() -> f64 {
+    3.0
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(width), Some(height)) = (self.w, self.h) {
+            write!(f, "w: {}, h: {}", width, height)
+        } else {
+            write!(f, "w: null, h: null")
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Heic => "heic",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Jxl => "jxl",
+            ImageFormat::Tiff => "tiff",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x: {}, y: {}", self.x, self.y)
+    }
+}
+
+impl fmt::Display for HorizontalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            HorizontalPosition::Center => "Center".to_owned(),
+            HorizontalPosition::Left(x) => format!("Left({})", x),
+            HorizontalPosition::Right(x) => format!("Right({})", x),
+            HorizontalPosition::Percent(x) => format!("Percent({})", x),
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for VerticalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            VerticalPosition::Center => "Center".to_owned(),
+            VerticalPosition::Top(x) => format!("Top({})", x),
+            VerticalPosition::Bottom(x) => format!("Bottom({})", x),
+            VerticalPosition::Percent(x) => format!("Percent({})", x),
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl Default for Point {
+    fn default() -> Self {
+        Point {
+            x: HorizontalPosition::Left(0),
+            y: VerticalPosition::Top(0),
+        }
+    }
+}
+
+// the percentages of the image size resolved into pixels from the closest edges
+fn percent_of(measure: i32, percent: f64) -> i32 {
+    (f64::from(measure) * percent.abs().min(100.0) / 100.0).round() as i32
+}
+
+fn get_ratio(desired_measure: i32, original_measure: i32, opposite_orig_measure: i32) -> i32 {
+    let ratio = desired_measure as f32 / original_measure as f32;
+    // a very elongated image could otherwise be resized to nothing
+    ((opposite_orig_measure as f32 * ratio) as i32).max(1)
+}
+
+fn is_negative_or_zero(size: &Size) -> bool {
+    (size.height.is_some() && size.height.unwrap() <= 0)
+        || (size.width.is_some() && size.width.unwrap() <= 0)
+}
+
+pub fn get_target_size(
+    original_width: i32,
+    original_height: i32,
+    desired_size: &Size,
+) -> Result<(i32, i32), InvalidSizeError> {
+    match &desired_size {
+        Size {
+            width: None,
+            height: None,
+        } => Ok((original_width, original_height)),
+        s if is_negative_or_zero(s) => Err(InvalidSizeError::new(desired_size)),
+        Size {
+            width: Some(w),
+            height: Some(h),
+        } if *h > original_height && *w > original_width => Ok((original_width, original_height)),
+        Size {
+            width: Some(w),
+            height: Some(h),
+        } => {
+            let diff_height = *h as f32 / original_height as f32;
+            let diff_width = *w as f32 / original_width as f32;
+
+            if diff_height < diff_width && diff_height <= 1.0 {
+                Ok((get_ratio(*h, original_height, original_width), *h))
+            } else {
+                Ok((*w, get_ratio(*w, original_width, original_height)))
+            }
+        }
+        Size {
+            width: None,
+            height: Some(h),
+        } => {
+            if *h > original_height {
+                Ok((original_width, original_height))
+            } else {
+                Ok((get_ratio(*h, original_height, original_width), *h))
+            }
+        }
+        Size {
+            width: Some(w),
+            height: None,
+        } => {
+            if *w > original_width {
+                Ok((original_width, original_height))
+            } else {
+                Ok((*w, get_ratio(*w, original_width, original_height)))
+            }
+        }
+    }
+}
+
+// the size the image is scaled to, and the size it's then cropped or extended to for the `cover`
+// and `contain` fits
+#[derive(Debug, PartialEq)]
+pub struct FitSize {
+    pub scaled: (i32, i32),
+    pub target: (i32, i32),
+}
+
+// only `inside` makes sense without both the width and the height, so it's what the other fits
+// fall back to. `inside` never upscales, the other fits only when enlarging is allowed, otherwise
+// the requested size is capped to the original one.
+pub fn get_fit_size(
+    original_width: i32,
+    original_height: i32,
+    desired_size: &Size,
+    fit: Fit,
+    enlarge: bool,
+) -> Result<FitSize, InvalidSizeError> {
+    let (width, height) = match desired_size {
+        Size {
+            width: Some(w),
+            height: Some(h),
+        } if fit != Fit::Inside && *w > 0 && *h > 0 => (*w, *h),
+        _ => {
+            let size = get_target_size(original_width, original_height, desired_size)?;
+            return Ok(FitSize {
+                scaled: size,
+                target: size,
+            });
+        }
+    };
+    let (width, height) = if enlarge {
+        (width, height)
+    } else {
+        (width.min(original_width), height.min(original_height))
+    };
+    let scale_width = f64::from(width) / f64::from(original_width);
+    let scale_height = f64::from(height) / f64::from(original_height);
+    let scaled = |scale: f64| {
+        (
+            ((f64::from(original_width) * scale).round() as i32).max(1),
+            ((f64::from(original_height) * scale).round() as i32).max(1),
+        )
+    };
+    let (scaled, target) = match fit {
+        Fit::Fill => ((width, height), (width, height)),
+        Fit::Cover => {
+            let (w, h) = scaled(scale_width.max(scale_height));
+            ((w.max(width), h.max(height)), (width, height))
+        }
+        Fit::Contain => {
+            let (w, h) = scaled(scale_width.min(scale_height));
+            ((w.min(width), h.min(height)), (width, height))
+        }
+        Fit::Outside => {
+            let (w, h) = scaled(scale_width.max(scale_height));
+            let size = (w.max(width), h.max(height));
+            (size, size)
+        }
+        Fit::Inside => unreachable!(),
+    };
+    Ok(FitSize { scaled, target })
+}
+
+// the top left corner of a crop centered on the focal point, given as fractions of the width and
+// the height of the image. the crop is moved back inside the image when the point is close to its
+// edges.
+pub fn get_focal_crop_origin(
+    image_width: i32,
+    image_height: i32,
+    crop_width: i32,
+    crop_height: i32,
+    (fx, fy): (f64, f64),
+) -> (i32, i32) {
+    let origin = |image: i32, crop: i32, focus: f64| {
+        let center = (f64::from(image) * focus.clamp(0.0, 1.0)).round() as i32;
+        (center - crop / 2).clamp(0, (image - crop).max(0))
+    };
+    (
+        origin(image_width, crop_width, fx),
+        origin(image_height, crop_height, fy),
+    )
+}
+
+// the factor libjpeg shrinks a JPEG by while decoding it, the largest of 2, 4 and 8 which keeps
+// the decoded image at least as large as its scaled size, so that libvips only resizes the rest
+pub fn get_shrink_on_load(
+    original_width: i32,
+    original_height: i32,
+    scaled_width: i32,
+    scaled_height: i32,
+) -> i32 {
+    let factor = (original_width / scaled_width.max(1)).min(original_height / scaled_height.max(1));
+    [8, 4, 2]
+        .into_iter()
+        .find(|shrink| factor >= *shrink)
+        .unwrap_or(1)
+}
+
+// the quality picked by `quality=auto` out of the mean gradient of the image, from 0 for a flat
+// image to 255. the busy images hide the compression artifacts, so they're compressed the most,
+// while the smooth gradients and flat areas band and block as soon as they are.
+pub fn get_auto_quality(detail: f64) -> i32 {
+    const SMOOTH_QUALITY: f64 = 85.0;
+    const BUSY_QUALITY: f64 = 65.0;
+    const BUSY_DETAIL: f64 = 40.0;
+    let busyness = (detail / BUSY_DETAIL).clamp(0.0, 1.0);
+    (SMOOTH_QUALITY - busyness * (SMOOTH_QUALITY - BUSY_QUALITY)).round() as i32
+}
+
+// some devices write bogus orientation tags, the EXIF based auto-rotation is skipped for the
+// camera models configured to be bypassed
+pub fn is_autorotation_bypassed(camera_model: Option<&str>, bypass_models: &[String]) -> bool {
+    match camera_model.map(|model| model.trim_matches(|c: char| c.is_whitespace() || c == '\0')) {
+        Some(model) if !model.is_empty() => bypass_models
+            .iter()
+            .any(|bypassed| bypassed.trim().eq_ignore_ascii_case(model)),
+        _ => false,
+    }
+}
+
+// browsers advertised with Accept-CH send the width of the image slot in physical pixels and the
+// device pixel ratio. the width hint is used when the request has no explicit size, otherwise the
+// explicit size (given in css pixels) is scaled by the device pixel ratio.
+pub fn apply_client_hints(size: &Size, width_hint: Option<i32>, dpr: Option<f64>) -> Size {
+    match (size, width_hint, dpr) {
+        (
+            Size {
+                width: None,
+                height: None,
+            },
+            Some(width),
+            _,
+        ) if width > 0 => Size {
+            width: Some(width),
+            height: None,
+        },
+        (size, _, Some(dpr)) if dpr.is_finite() && dpr > 0.0 => {
+            let scale = |measure: i32| (f64::from(measure) * dpr).round() as i32;
+            Size {
+                width: size.width.map(scale),
+                height: size.height.map(scale),
+            }
+        }
+        (size, _, _) => size.clone(),
+    }
+}
+
+// the format of `format=auto`: the smallest one the `Accept` header of the client lists, the
+// browsers which don't send it getting a Jpeg image
+pub fn negotiate_format(accept: &str) -> ImageFormat {
+    let accepts = |media_type: &str| {
+        accept.split(',').any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            params.next() == Some(media_type)
+                && params.all(|param| match param.strip_prefix("q=") {
+                    Some(q) => q.parse::<f64>().is_ok_and(|q| q > 0.0),
+                    None => true,
+                })
+        })
+    };
+    if accepts("image/avif") {
+        ImageFormat::Avif
+    } else if accepts("image/webp") {
+        ImageFormat::Webp
+    } else {
+        ImageFormat::Jpeg
+    }
+}
+
+pub fn get_watermark_target_size(
+    image_width: i32,
+    image_height: i32,
+    wm_width: i32,
+    wm_height: i32,
+    percentage: f64,
+) -> Result<(i32, i32), InvalidSizeError> {
+    if percentage <= 0.0 || percentage > 100.0 {
+        Err(InvalidSizeError::new(&Size::default()))
+    } else {
+        let desired_width = f64::from(image_width) * (percentage / 100.0);
+        let desired_height = f64::from(image_height) * (percentage / 100.0);
+        debug!(
+            "Desired watermark size: {}x{}",
+            desired_width, desired_height
+        );
+        let (target_width, target_height) =
+            if f64::from(wm_width) / desired_width >= f64::from(wm_height) / desired_height {
+                (
+                    desired_width as i32,
+                    (f64::from(wm_height) * desired_width / f64::from(wm_width)) as i32,
+                )
+            } else {
+                (
+                    (f64::from(wm_width) * desired_height / f64::from(wm_height)) as i32,
+                    desired_height as i32,
+                )
+            };
+        // the watermark would be resized to nothing on small images
+        Ok((target_width.max(1), target_height.max(1)))
+    }
+}
+
+pub fn get_watermark_borders(
+    width: i32,
+    height: i32,
+    wm_width: i32,
+    wm_height: i32,
+    point: &Point,
+) -> (i32, i32, i32, i32) {
+    debug!(
+        "Watermark parameters: original images {}x{}, watermark: {}x{}, params: {}",
+        width, height, wm_width, wm_height, point
+    );
+    let x = match point.x {
+        HorizontalPosition::Percent(x) if x < 0.0 => {
+            HorizontalPosition::Right(percent_of(width, x))
+        }
+        HorizontalPosition::Percent(x) => HorizontalPosition::Left(percent_of(width, x)),
+        ref x => x.clone(),
+    };
+    let y = match point.y {
+        VerticalPosition::Percent(y) if y < 0.0 => VerticalPosition::Bottom(percent_of(height, y)),
+        VerticalPosition::Percent(y) => VerticalPosition::Top(percent_of(height, y)),
+        ref y => y.clone(),
+    };
+    let (left, right) = match x {
+        HorizontalPosition::Center => {
+            let left = (width / 2) - (wm_width / 2);
+            (left, left + (width % 2))
+        }
+        HorizontalPosition::Left(x) => {
+            let right = width - x - wm_width;
+            let left = x + if right < 0 { right } else { 0 };
+            (left, if right > 0 { right } else { 0 })
+        }
+        HorizontalPosition::Right(x) => {
+            let left = width - x - wm_width;
+            let right = x + if left < 0 { left } else { 0 };
+            (if left > 0 { left } else { 0 }, right)
+        }
+        HorizontalPosition::Percent(_) => unreachable!(),
+    };
+
+    let (top, bottom) = match y {
+        VerticalPosition::Center => {
+            let top = (height / 2) - (wm_height / 2);
+            (top, top + (height % 2))
+        }
+        VerticalPosition::Top(y) => {
+            let bottom = height - y - wm_height;
+            let top = y + if bottom < 0 { bottom } else { 0 };
+            (top, if bottom > 0 { bottom } else { 0 })
+        }
+        VerticalPosition::Bottom(y) => {
+            let top = height - y - wm_height;
+            let bottom = y + if top < 0 { top } else { 0 };
+            (if top > 0 { top } else { 0 }, bottom)
+        }
+        VerticalPosition::Percent(_) => unreachable!(),
+    };
+    (left, top, right, bottom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_size() {
+        assert!(get_target_size(
+            100,
+            100,
+            &Size {
+                width: Some(-1),
+                height: Some(-1)
+            }
+        )
+        .is_err());
+        assert!(get_target_size(
+            100,
+            100,
+            &Size {
+                width: Some(-1),
+                height: Some(1)
+            }
+        )
+        .is_err());
+        assert!(get_target_size(
+            100,
+            100,
+            &Size {
+                width: Some(1),
+                height: Some(-1)
+            }
+        )
+        .is_err());
+        assert!(get_target_size(
+            100,
+            100,
+            &Size {
+                width: None,
+                height: Some(-1)
+            }
+        )
+        .is_err());
+        assert!(get_target_size(
+            100,
+            100,
+            &Size {
+                width: Some(-1),
+                height: None
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_size_square_img() {
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(100)
+                }
+            ),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(10),
+                    height: Some(10)
+                }
+            ),
+            Ok((10, 10))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(10),
+                    height: Some(20)
+                }
+            ),
+            Ok((10, 10))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(20),
+                    height: Some(10)
+                }
+            ),
+            Ok((10, 10))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(50)
+                }
+            ),
+            Ok((50, 50))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(50),
+                    height: Some(100)
+                }
+            ),
+            Ok((50, 50))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(120),
+                    height: Some(100)
+                }
+            ),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(120)
+                }
+            ),
+            Ok((100, 100))
+        );
+    }
+
+    #[test]
+    fn test_size_rectangular_img() {
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(100),
+                    height: Some(150)
+                }
+            ),
+            Ok((100, 150))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(100),
+                    height: Some(100)
+                }
+            ),
+            Ok((66, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(120),
+                    height: Some(100)
+                }
+            ),
+            Ok((66, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(100),
+                    height: Some(50)
+                }
+            ),
+            Ok((33, 50))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(50),
+                    height: Some(100)
+                }
+            ),
+            Ok((50, 75))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(200),
+                    height: Some(200)
+                }
+            ),
+            Ok((100, 150))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(200),
+                    height: Some(150)
+                }
+            ),
+            Ok((100, 150))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: Some(100),
+                    height: Some(200)
+                }
+            ),
+            Ok((100, 150))
+        );
+    }
+
+    #[test]
+    fn test_size_rectangular_img2() {
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(150),
+                    height: Some(100)
+                }
+            ),
+            Ok((150, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(100)
+                }
+            ),
+            Ok((100, 66))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(120),
+                    height: Some(100)
+                }
+            ),
+            Ok((120, 80))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(50)
+                }
+            ),
+            Ok((75, 50))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(50),
+                    height: Some(100)
+                }
+            ),
+            Ok((50, 33))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(200),
+                    height: Some(200)
+                }
+            ),
+            Ok((150, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(200),
+                    height: Some(150)
+                }
+            ),
+            Ok((150, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: Some(200)
+                }
+            ),
+            Ok((100, 66))
+        );
+    }
+
+    #[test]
+    fn test_size_optional() {
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: None
+                }
+            ),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: None,
+                    height: Some(100)
+                }
+            ),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                50,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: None
+                }
+            ),
+            Ok((50, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                50,
+                &Size {
+                    width: None,
+                    height: Some(100)
+                }
+            ),
+            Ok((100, 50))
+        );
+        assert_eq!(
+            get_target_size(
+                150,
+                100,
+                &Size {
+                    width: Some(100),
+                    height: None
+                }
+            ),
+            Ok((100, 66))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                150,
+                &Size {
+                    width: None,
+                    height: Some(100)
+                }
+            ),
+            Ok((66, 100))
+        );
+        assert_eq!(
+            get_target_size(
+                100,
+                100,
+                &Size {
+                    width: None,
+                    height: None
+                }
+            ),
+            Ok((100, 100))
+        );
+    }
+
+    #[test]
+    fn test_autorotation_bypass() {
+        let bypass_models = vec!["Bogus Cam X1".to_string(), " FaultyPhone ".to_string()];
+        assert!(is_autorotation_bypassed(
+            Some("Bogus Cam X1"),
+            &bypass_models
+        ));
+        assert!(is_autorotation_bypassed(
+            Some("bogus cam x1\0"),
+            &bypass_models
+        ));
+        assert!(is_autorotation_bypassed(
+            Some("FaultyPhone"),
+            &bypass_models
+        ));
+        assert!(!is_autorotation_bypassed(
+            Some("Canon EOS 5D"),
+            &bypass_models
+        ));
+        assert!(!is_autorotation_bypassed(Some(""), &bypass_models));
+        assert!(!is_autorotation_bypassed(None, &bypass_models));
+        assert!(!is_autorotation_bypassed(Some("Bogus Cam X1"), &[]));
+    }
+
+    #[test]
+    fn test_client_hints() {
+        let no_size = Size::default();
+        let explicit = Size {
+            width: Some(100),
+            height: Some(50),
+        };
+        assert_eq!(
+            apply_client_hints(&no_size, Some(640), Some(2.0)).width,
+            Some(640)
+        );
+        assert_eq!(apply_client_hints(&no_size, None, Some(2.0)).width, None);
+        let scaled = apply_client_hints(&explicit, Some(640), Some(1.5));
+        assert_eq!((scaled.width, scaled.height), (Some(150), Some(75)));
+        let unchanged = apply_client_hints(&explicit, None, Some(-1.0));
+        assert_eq!((unchanged.width, unchanged.height), (Some(100), Some(50)));
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        assert_eq!(
+            negotiate_format("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"),
+            ImageFormat::Avif
+        );
+        assert_eq!(
+            negotiate_format("image/webp, image/avif;q=0, */*"),
+            ImageFormat::Webp
+        );
+        assert_eq!(negotiate_format("image/*,*/*;q=0.8"), ImageFormat::Jpeg);
+        assert_eq!(negotiate_format(""), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_center_watermark() {
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Center,
+                    y: VerticalPosition::Center
+                },
+            ),
+            (45, 45, 45, 45)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                101,
+                101,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Center,
+                    y: VerticalPosition::Center
+                },
+            ),
+            (45, 45, 46, 46)
+        );
+    }
+
+    #[test]
+    fn test_left_top_watermark() {
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Left(10),
+                    y: VerticalPosition::Top(10)
+                }
+            ),
+            (10, 10, 80, 80)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Left(95),
+                    y: VerticalPosition::Top(10)
+                }
+            ),
+            (90, 10, 0, 80)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Left(10),
+                    y: VerticalPosition::Top(95)
+                }
+            ),
+            (10, 90, 80, 0)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Left(95),
+                    y: VerticalPosition::Top(95)
+                }
+            ),
+            (90, 90, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_right_bottom_watermark() {
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Right(10),
+                    y: VerticalPosition::Bottom(10)
+                }
+            ),
+            (80, 80, 10, 10)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Right(95),
+                    y: VerticalPosition::Bottom(10)
+                }
+            ),
+            (0, 80, 90, 10)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Right(10),
+                    y: VerticalPosition::Bottom(95)
+                }
+            ),
+            (80, 0, 10, 90)
+        );
+        assert_eq!(
+            get_watermark_borders(
+                100,
+                100,
+                10,
+                10,
+                &Point {
+                    x: HorizontalPosition::Right(95),
+                    y: VerticalPosition::Bottom(95)
+                }
+            ),
+            (0, 0, 90, 90)
+        );
+    }
+
+    #[test]
+    fn test_sharpen() {
+        let request: ProcessImageRequest = serde_qs::from_str("image_address=a.jpg").unwrap();
+        assert!(request.sharpen.is_none());
+        let request: ProcessImageRequest =
+            serde_qs::from_str("image_address=a.jpg&sharpen[amount]=5").unwrap();
+        let sharpen = request.sharpen.unwrap();
+        assert_eq!((sharpen.sigma, sharpen.amount), (0.5, 5.0));
+    }
+
+    #[test]
+    fn test_fit_size() {
+        let size = Size {
+            width: Some(100),
+            height: Some(100),
+        };
+        assert_eq!(
+            get_fit_size(400, 200, &size, Fit::Inside, true).unwrap(),
+            FitSize {
+                scaled: (100, 50),
+                target: (100, 50)
+            }
+        );
+        assert_eq!(
+            get_fit_size(400, 200, &size, Fit::Outside, true).unwrap(),
+            FitSize {
+                scaled: (200, 100),
+                target: (200, 100)
+            }
+        );
+        assert_eq!(
+            get_fit_size(400, 200, &size, Fit::Cover, true).unwrap(),
+            FitSize {
+                scaled: (200, 100),
+                target: (100, 100)
+            }
+        );
+        assert_eq!(
+            get_fit_size(400, 200, &size, Fit::Contain, true).unwrap(),
+            FitSize {
+                scaled: (100, 50),
+                target: (100, 100)
+            }
+        );
+        assert_eq!(
+            get_fit_size(400, 200, &size, Fit::Fill, true).unwrap(),
+            FitSize {
+                scaled: (100, 100),
+                target: (100, 100)
+            }
+        );
+        // the exact fits upscale
+        assert_eq!(
+            get_fit_size(50, 50, &size, Fit::Cover, true).unwrap(),
+            FitSize {
+                scaled: (100, 100),
+                target: (100, 100)
+            }
+        );
+        // without both measures, the aspect ratio is kept as with `inside`
+        let width_only = Size {
+            width: Some(100),
+            height: None,
+        };
+        assert_eq!(
+            get_fit_size(400, 200, &width_only, Fit::Fill, true).unwrap(),
+            FitSize {
+                scaled: (100, 50),
+                target: (100, 50)
+            }
+        );
+        assert!(get_fit_size(
+            400,
+            200,
+            &Size {
+                width: Some(-1),
+                height: Some(100)
+            },
+            Fit::Cover,
+            true
+        )
+        .is_err());
+        // without enlarging, the size is capped to the original one
+        let big = Size {
+            width: Some(2000),
+            height: Some(2000),
+        };
+        for fit in [Fit::Outside, Fit::Cover, Fit::Contain, Fit::Fill] {
+            assert_eq!(
+                get_fit_size(800, 600, &big, fit, false).unwrap(),
+                FitSize {
+                    scaled: (800, 600),
+                    target: (800, 600)
+                }
+            );
+        }
+        assert_eq!(
+            get_fit_size(
+                800,
+                600,
+                &Size {
+                    width: Some(400),
+                    height: Some(2000)
+                },
+                Fit::Cover,
+                false
+            )
+            .unwrap(),
+            FitSize {
+                scaled: (800, 600),
+                target: (400, 600)
+            }
+        );
+    }
+
+    #[test]
+    fn test_crop() {
+        let crop = |query: &str| serde_qs::from_str::<ProcessImageRequest>(query).map(|r| r.crop);
+        let size = crop("image_address=a.jpg&crop[w]=100&crop[h]=50").unwrap();
+        assert_eq!((size.w, size.h, size.rect), (Some(100), Some(50), None));
+        let rect = crop("image_address=a.jpg&crop=10,20,100,50").unwrap();
+        assert_eq!(
+            rect.rect,
+            Some(CropRect {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 50
+            })
+        );
+        assert!(crop("image_address=a.jpg&crop=10,20,100").is_err());
+        assert!(crop("image_address=a.jpg&crop=10,20,0,50").is_err());
+        // the rectangle is cut to the image
+        let rect = rect.rect.unwrap();
+        assert_eq!(
+            rect.clamp_to(60, 60),
+            Some(CropRect {
+                x: 10,
+                y: 20,
+                width: 50,
+                height: 40
+            })
+        );
+        assert_eq!(rect.clamp_to(10, 60), None);
+    }
+
+    #[test]
+    fn test_shrink_on_load() {
+        assert_eq!(get_shrink_on_load(8000, 5000, 300, 188), 8);
+        assert_eq!(get_shrink_on_load(1200, 800, 300, 200), 4);
+        assert_eq!(get_shrink_on_load(1200, 800, 500, 333), 2);
+        // the most shrunk side bounds the factor
+        assert_eq!(get_shrink_on_load(1200, 800, 300, 400), 2);
+        assert_eq!(get_shrink_on_load(1200, 800, 1000, 667), 1);
+        assert_eq!(get_shrink_on_load(1200, 800, 2400, 1600), 1);
+    }
+
+    #[test]
+    fn test_quality() {
+        let quality = |value: &str| Quality::try_from(String::from(value));
+        assert_eq!(quality("auto"), Ok(Quality::Auto));
+        assert_eq!(quality("70"), Ok(Quality::Value(70)));
+        assert!(quality("high").is_err());
+
+        assert_eq!(get_auto_quality(0.0), 85);
+        assert_eq!(get_auto_quality(20.0), 75);
+        assert_eq!(get_auto_quality(40.0), 65);
+        assert_eq!(get_auto_quality(200.0), 65);
+    }
+
+    #[test]
+    fn test_collage_layout() {
+        let request: CollageRequest =
+            serde_qs::from_str("images[0]=a.jpg&images[1]=b.jpg&layout=2x2").unwrap();
+        assert_eq!(request.images, vec!["a.jpg", "b.jpg"]);
+        assert_eq!(request.layout, Layout::TwoByTwo);
+
+        assert_eq!(Layout::Grid.columns(1), 1);
+        assert_eq!(Layout::Grid.columns(4), 2);
+        assert_eq!(Layout::Grid.columns(5), 3);
+        assert_eq!(Layout::TwoByTwo.columns(3), 2);
+        assert_eq!(Layout::Strip.columns(3), 3);
+    }
+
+    #[test]
+    fn test_widths() {
+        let widths = |value: &str| Widths::try_from(String::from(value));
+        assert_eq!(widths("200,800,400,800"), Ok(Widths(vec![800, 400, 200])));
+        assert!(widths("200,0").is_err());
+        assert!(widths("200,large").is_err());
+        assert!(widths("1,2,3,4,5,6,7,8,9").is_err());
+
+        let largest = Widths(vec![800, 400]).largest_size(&Size {
+            width: Some(400),
+            height: Some(300),
+        });
+        assert_eq!((largest.width, largest.height), (Some(800), Some(600)));
+        let largest = Widths(vec![800, 400]).largest_size(&Size {
+            width: None,
+            height: Some(300),
+        });
+        assert_eq!((largest.width, largest.height), (Some(800), None));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let request = |query: &str| serde_qs::from_str::<ProcessImageRequest>(query).unwrap();
+        assert!(request("image_address=a.jpg").is_passthrough(Some("jpeg")));
+        assert!(request("image_address=a.png&format=Png").is_passthrough(Some("png")));
+        assert!(!request("image_address=a.png").is_passthrough(Some("png")));
+        assert!(!request("image_address=a.jpg&quality=80").is_passthrough(Some("jpeg")));
+        assert!(!request("image_address=a.jpg&quality=70").is_passthrough(Some("jpeg")));
+        assert!(!request("image_address=a.jpg&size[width]=100").is_passthrough(Some("jpeg")));
+        assert!(!request("image_address=a.jpg&flip=true").is_passthrough(Some("jpeg")));
+        assert!(!request("image_address=a.heic&format=Heic").is_passthrough(Some("heic")));
+    }
+
+    #[test]
+    fn test_operations() {
+        let operations = |value: &str| Operations::try_from(String::from(value));
+        assert_eq!(
+            operations("resize:300x200,blur:2,grayscale,watermark:logo,rotate:90,flop"),
+            Ok(Operations(vec![
+                Operation::Resize(Some(300), Some(200)),
+                Operation::Blur(2.0),
+                Operation::Filter(Filter::Greyscale),
+                Operation::Watermark(String::from("logo")),
+                Operation::Rotate(Rotation::R90),
+                Operation::Flop,
+            ]))
+        );
+        assert_eq!(
+            operations("resize:x200,duotone:1e3264:f0c8a0"),
+            Ok(Operations(vec![
+                Operation::Resize(None, Some(200)),
+                Operation::Filter(Filter::Duotone {
+                    dark: [0x1e, 0x32, 0x64],
+                    light: [0xf0, 0xc8, 0xa0]
+                }),
+            ]))
+        );
+        assert!(operations("resize:x").is_err());
+        assert!(operations("resize:300").is_err());
+        assert!(operations("blur:-1").is_err());
+        assert!(operations("rotate:45").is_err());
+        assert!(operations("watermark:").is_err());
+        assert!(operations("invert").is_err());
+        assert!(operations(&vec!["flip"; 17].join(",")).is_err());
+
+        let watermark = |address: &str| Watermark {
+            image_address: address.to_string(),
+            ..serde_qs::from_str("alpha=0.5").unwrap()
+        };
+        assert!(watermark("https://cdn.example.com/logo.png").is_named("logo"));
+        assert!(watermark("logo.png").is_named("logo"));
+        assert!(!watermark("logos/badge.png").is_named("logo"));
+    }
+
+    #[test]
+    fn test_focal_crop_origin() {
+        assert_eq!(
+            get_focal_crop_origin(400, 200, 100, 100, (0.5, 0.5)),
+            (150, 50)
+        );
+        assert_eq!(
+            get_focal_crop_origin(400, 200, 100, 100, (0.25, 0.5)),
+            (50, 50)
+        );
+        // the crop stays inside the image
+        assert_eq!(
+            get_focal_crop_origin(400, 200, 100, 100, (0.0, 1.0)),
+            (0, 100)
+        );
+        assert_eq!(
+            get_focal_crop_origin(400, 200, 100, 100, (2.0, -1.0)),
+            (300, 0)
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        let color = |value: &str| Color::try_from(String::from(value));
+        assert_eq!(color("1e3264"), Ok(Color([0x1e, 0x32, 0x64, 0xff])));
+        assert_eq!(color("#1e326480"), Ok(Color([0x1e, 0x32, 0x64, 0x80])));
+        assert!(color("1e32648").is_err());
+        assert!(color("1e3264zz").is_err());
+    }
+
+    #[test]
+    fn test_percent_watermark() {
+        let point = |x: f64, y: f64| Point {
+            x: HorizontalPosition::Percent(x),
+            y: VerticalPosition::Percent(y),
+        };
+        assert_eq!(
+            get_watermark_borders(200, 100, 10, 10, &point(10.0, 20.0)),
+            (20, 20, 170, 70)
+        );
+        // negative percentages are taken from the right and the bottom
+        assert_eq!(
+            get_watermark_borders(200, 100, 10, 10, &point(-10.0, -20.0)),
+            (170, 70, 20, 20)
+        );
+    }
+
+    #[test]
+    fn test_texts() {
+        let request: ProcessImageRequest = serde_qs::from_str(
+            "image_address=a.jpg&texts[0][text]=%C2%A9%20dali&texts[0][color]=000000&texts[0][size]=12",
+        )
+        .unwrap();
+        let text = &request.texts[0];
+        assert_eq!(text.text, "\u{a9} dali");
+        assert_eq!(
+            (text.font.as_str(), text.size, text.alpha),
+            ("sans", 12, 1.0)
+        );
+        assert_eq!(text.color, Color([0, 0, 0, 0xff]));
+    }
+
+    #[test]
+    fn test_border() {
+        let border = |value: &str| Border::try_from(String::from(value));
+        assert_eq!(
+            border("2,cccccc"),
+            Ok(Border {
+                width: 2,
+                color: Color([0xcc, 0xcc, 0xcc, 0xff])
+            })
+        );
+        assert_eq!(
+            border("1"),
+            Ok(Border {
+                width: 1,
+                color: Color([0, 0, 0, 0xff])
+            })
+        );
+        assert!(border("-1,cccccc").is_err());
+        assert!(border("1,ccc").is_err());
+    }
+
+    #[test]
+    fn test_radius() {
+        let radius = |value: &str| Radius::try_from(String::from(value));
+        assert_eq!(radius("max"), Ok(Radius::Max));
+        assert_eq!(radius("12"), Ok(Radius::Pixels(12)));
+        assert!(radius("-1").is_err());
+        assert!(radius("12px").is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = |value: &str| Filter::try_from(String::from(value));
+        assert_eq!(filter("greyscale"), Ok(Filter::Greyscale));
+        assert_eq!(filter("sepia"), Ok(Filter::Sepia));
+        assert_eq!(
+            filter("duotone:1e3264:F0C8A0"),
+            Ok(Filter::Duotone {
+                dark: [0x1e, 0x32, 0x64],
+                light: [0xf0, 0xc8, 0xa0]
+            })
+        );
+        assert!(filter("duotone:1e3264").is_err());
+        assert!(filter("duotone:1e3264:f0c8zz").is_err());
+        assert!(filter("invert").is_err());
+    }
+
+    #[test]
+    fn test_pixelate() {
+        let request: ProcessImageRequest =
+            serde_qs::from_str("image_address=a&pixelate[0]=10,20,100,50,8&pixelate[1]=0,0,5,5,2")
+                .unwrap();
+        assert_eq!(
+            request.pixelate,
+            vec![
+                Pixelate {
+                    rect: CropRect {
+                        x: 10,
+                        y: 20,
+                        width: 100,
+                        height: 50
+                    },
+                    size: 8
+                },
+                Pixelate {
+                    rect: CropRect {
+                        x: 0,
+                        y: 0,
+                        width: 5,
+                        height: 5
+                    },
+                    size: 2
+                }
+            ]
+        );
+        let pixelate = |value: &str| Pixelate::try_from(String::from(value));
+        assert!(pixelate("10,20,100,50").is_err());
+        assert!(pixelate("10,20,100,50,0").is_err());
+        assert!(pixelate("-1,20,100,50,8").is_err());
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn dimension() -> impl Strategy<Value = i32> {
+            1..20_000
+        }
+
+        fn horizontal_position() -> impl Strategy<Value = HorizontalPosition> {
+            prop_oneof![
+                (0..30_000).prop_map(HorizontalPosition::Left),
+                (0..30_000).prop_map(HorizontalPosition::Right),
+                Just(HorizontalPosition::Center),
+            ]
+        }
+
+        fn vertical_position() -> impl Strategy<Value = VerticalPosition> {
+            prop_oneof![
+                (0..30_000).prop_map(VerticalPosition::Top),
+                (0..30_000).prop_map(VerticalPosition::Bottom),
+                Just(VerticalPosition::Center),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn target_size_is_within_bounds_and_keeps_the_aspect(
+                original_width in dimension(),
+                original_height in dimension(),
+                width in proptest::option::of(dimension()),
+                height in proptest::option::of(dimension()),
+            ) {
+                let (target_width, target_height) =
+                    get_target_size(original_width, original_height, &Size { width, height })
+                        .unwrap();
+                prop_assert!(target_width >= 1 && target_width <= original_width);
+                prop_assert!(target_height >= 1 && target_height <= original_height);
+                if let (Some(width), Some(height)) = (width, height) {
+                    if width <= original_width || height <= original_height {
+                        prop_assert!(target_width <= width && target_height <= height);
+                    }
+                }
+                // the rounding of the derived measure can't distort the aspect by more than a pixel
+                let distortion = (i64::from(target_width) * i64::from(original_height)
+                    - i64::from(target_height) * i64::from(original_width))
+                .abs();
+                prop_assert!(distortion <= i64::from(original_width.max(original_height)));
+            }
+
+            #[test]
+            fn elongated_images_are_never_resized_to_nothing(
+                long_side in 1_000..20_000,
+                short_side in 1..3,
+            ) {
+                let size = Size { width: Some(1), height: None };
+                let (_, target_height) = get_target_size(long_side, short_side, &size).unwrap();
+                prop_assert!(target_height >= 1);
+            }
+
+            #[test]
+            fn watermark_fits_inside_the_image(
+                image_width in dimension(),
+                image_height in dimension(),
+                wm_width in dimension(),
+                wm_height in dimension(),
+                percentage in 0.1..=100.0,
+                x in horizontal_position(),
+                y in vertical_position(),
+            ) {
+                let (target_width, target_height) = get_watermark_target_size(
+                    image_width,
+                    image_height,
+                    wm_width,
+                    wm_height,
+                    percentage,
+                )
+                .unwrap();
+                prop_assert!(target_width >= 1 && target_width <= image_width);
+                prop_assert!(target_height >= 1 && target_height <= image_height);
+
+                let (left, top, right, bottom) = get_watermark_borders(
+                    image_width,
+                    image_height,
+                    target_width,
+                    target_height,
+                    &Point { x, y },
+                );
+                prop_assert!(left >= 0 && top >= 0 && right >= 0 && bottom >= 0);
+                prop_assert!(left + target_width <= image_width);
+                prop_assert!(top + target_height <= image_height);
+            }
+        }
+    }
+}
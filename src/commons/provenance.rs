@@ -0,0 +1,48 @@
+// (c) Copyright 2019-2024 OLX
+
+// a short token identifying the pipeline which produced an image: the hash of the source bytes,
+// the hash of the processing parameters and the dali version. it's enough to trace an image served
+// from a cache or a CDN back to the exact source and request that produced it.
+
+use std::fmt;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+pub fn provenance_token(source: &[u8], params: &impl fmt::Debug) -> String {
+    let source_hash = xxh3_64(source);
+    let params_hash = xxh3_64(format!("{:?}", params).as_bytes());
+    format!(
+        "{:016x}-{:08x}-{}",
+        source_hash,
+        params_hash as u32,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::ProcessImageRequest;
+
+    fn request(query: &str) -> ProcessImageRequest {
+        serde_qs::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn test_provenance_token() {
+        let token = provenance_token(b"source", &request("image_address=a.jpg&quality=70"));
+        assert_eq!(
+            token,
+            provenance_token(b"source", &request("image_address=a.jpg&quality=70"))
+        );
+        assert_ne!(
+            token,
+            provenance_token(b"other", &request("image_address=a.jpg&quality=70"))
+        );
+        assert_ne!(
+            token,
+            provenance_token(b"source", &request("image_address=a.jpg&quality=71"))
+        );
+        assert!(token.ends_with(env!("CARGO_PKG_VERSION")));
+    }
+}
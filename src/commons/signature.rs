@@ -0,0 +1,357 @@
+// (c) Copyright 2019-2024 OLX
+
+// recognizes the image formats libvips is expected to load from the first bytes of the sources,
+// so that anything else is rejected before reaching libvips, whose errors don't tell apart a
+// source which isn't an image from one which is corrupted.
+
+const HEIF_BRANDS: [&[u8]; 10] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+// returns the name of the image format the bytes start with
+pub fn sniff_image_format(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpeg")
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+        Some("tiff")
+    } else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && HEIF_BRANDS.contains(&&bytes[8..12]) {
+        Some("heic")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("pdf")
+    } else {
+        None
+    }
+}
+
+// the tags of the first TIFF directory telling the RAW photos apart
+#[cfg(feature = "raw")]
+const MAKE_TAG: u16 = 0x010F;
+#[cfg(feature = "raw")]
+const SUB_IFDS_TAG: u16 = 0x014A;
+#[cfg(feature = "raw")]
+const ASCII_TYPE: u16 = 2;
+#[cfg(feature = "raw")]
+const SHORT_TYPE: u16 = 3;
+#[cfg(feature = "raw")]
+const LONG_TYPE: u16 = 4;
+#[cfg(feature = "raw")]
+const IMAGE_WIDTH_TAG: u16 = 0x0100;
+#[cfg(feature = "raw")]
+const IMAGE_LENGTH_TAG: u16 = 0x0101;
+// bounds the walk of the directories, whose offsets may point back to each other
+#[cfg(feature = "raw")]
+const MAX_DIRECTORIES: usize = 16;
+
+// returns the name of the RAW camera format of the TIFF based photos, told apart by the signature
+// of the Canon files, or by the `Make` tag of the Nikon and Sony ones. the sensor data of those is
+// in a sub-directory, which the TIFF files exported from the photos don't have.
+#[cfg(feature = "raw")]
+pub fn sniff_raw_format(bytes: &[u8]) -> Option<&'static str> {
+    if sniff_image_format(bytes) != Some("tiff") {
+        return None;
+    }
+    if bytes.get(8..11) == Some(b"CR\x02") {
+        return Some("cr2");
+    }
+    let directory = TiffDirectory::first(bytes)?;
+    directory.entry(SUB_IFDS_TAG)?;
+    let make = directory.ascii(MAKE_TAG)?;
+    if make.starts_with(b"NIKON") {
+        Some("nef")
+    } else if make.starts_with(b"SONY") {
+        Some("arw")
+    } else {
+        None
+    }
+}
+
+// the size of the developed RAW photo, which is the one of the largest image of the directories
+// and of their sub-directories, as the sensor data sits next to smaller previews. it's read from
+// the TIFF structure since ImageMagick develops the whole photo to read its header.
+#[cfg(feature = "raw")]
+pub fn raw_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut directories = vec![];
+    let mut next = TiffDirectory::first(bytes);
+    while let Some(directory) = next.filter(|_| directories.len() < MAX_DIRECTORIES) {
+        next = directory.next();
+        directories.extend(directory.sub_directories());
+        directories.push(directory);
+    }
+    directories
+        .iter()
+        .take(MAX_DIRECTORIES)
+        .filter_map(|directory| {
+            Some((
+                directory.number(IMAGE_WIDTH_TAG)?,
+                directory.number(IMAGE_LENGTH_TAG)?,
+            ))
+        })
+        .max_by_key(|(width, height)| u64::from(*width) * u64::from(*height))
+}
+
+// the entries of a TIFF directory, 12 bytes each: the tag, the type, the count of values and the
+// values themselves when they fit in 4 bytes, their offset otherwise
+#[cfg(feature = "raw")]
+struct TiffDirectory<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+    entries: usize,
+    count: usize,
+}
+
+#[cfg(feature = "raw")]
+impl<'a> TiffDirectory<'a> {
+    fn first(bytes: &'a [u8]) -> Option<Self> {
+        let header = Self {
+            bytes,
+            little_endian: bytes.starts_with(b"II"),
+            entries: 0,
+            count: 0,
+        };
+        header.at(header.u32_at(4)? as usize)
+    }
+
+    fn at(&self, offset: usize) -> Option<Self> {
+        Some(Self {
+            bytes: self.bytes,
+            little_endian: self.little_endian,
+            entries: offset.checked_add(2)?,
+            count: usize::from(self.u16_at(offset)?),
+        })
+    }
+
+    // the directory chained after this one, whose offset follows the entries
+    fn next(&self) -> Option<Self> {
+        match self.u32_at(self.entries + self.count * 12)? {
+            0 => None,
+            offset => self.at(offset as usize),
+        }
+    }
+
+    fn sub_directories(&self) -> Vec<Self> {
+        let Some((_, count, offset)) = self.entry(SUB_IFDS_TAG) else {
+            return vec![];
+        };
+        (0..count.min(MAX_DIRECTORIES))
+            .filter_map(|i| self.at(self.u32_at(offset + i * 4)? as usize))
+            .collect()
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .bytes
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .bytes
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    // the type, the count and the offset of the values of the tag
+    fn entry(&self, tag: u16) -> Option<(u16, usize, usize)> {
+        let entry = (0..self.count)
+            .map(|i| self.entries + i * 12)
+            .find(|entry| self.u16_at(*entry) == Some(tag))?;
+        let kind = self.u16_at(entry + 2)?;
+        let count = self.u32_at(entry + 4)? as usize;
+        let size = match kind {
+            ASCII_TYPE => 1,
+            SHORT_TYPE => 2,
+            _ => 4,
+        };
+        let offset = if count.checked_mul(size)? <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        Some((kind, count, offset))
+    }
+
+    fn number(&self, tag: u16) -> Option<u32> {
+        match self.entry(tag)? {
+            (SHORT_TYPE, 1.., offset) => self.u16_at(offset).map(u32::from),
+            (LONG_TYPE, 1.., offset) => self.u32_at(offset),
+            _ => None,
+        }
+    }
+
+    fn ascii(&self, tag: u16) -> Option<&'a [u8]> {
+        match self.entry(tag)? {
+            (ASCII_TYPE, count, offset) => self.bytes.get(offset..offset.checked_add(count)?),
+            _ => None,
+        }
+    }
+}
+
+// the SVG documents are only accepted as watermarks, which are rasterized at their target size
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(1024)];
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let start = head
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(head.len());
+    let head = &head[start..];
+    head.starts_with(b"<svg")
+        || (head.starts_with(b"<?xml") && head.windows(4).any(|window| window == b"<svg"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"));
+        assert!(is_svg(
+            b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<svg width=\"10\" height=\"10\"/>"
+        ));
+        assert!(is_svg(b"\n  <svg/>"));
+        assert!(!is_svg(b"<?xml version=\"1.0\"?><html/>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    // a big endian TIFF file whose first directory has the given ASCII tags, followed by a
+    // `SubIFDs` tag when asked for
+    #[cfg(feature = "raw")]
+    fn tiff(tags: &[(u16, &[u8])], sub_ifds: bool) -> Vec<u8> {
+        let count = tags.len() + usize::from(sub_ifds);
+        let mut data_offset = 8 + 2 + count * 12 + 4;
+        let (mut directory, mut data) = (vec![], vec![]);
+        for (tag, value) in tags {
+            directory.extend(tag.to_be_bytes());
+            directory.extend(ASCII_TYPE.to_be_bytes());
+            directory.extend((value.len() as u32).to_be_bytes());
+            directory.extend((data_offset as u32).to_be_bytes());
+            data.extend_from_slice(value);
+            data_offset += value.len();
+        }
+        if sub_ifds {
+            directory.extend(SUB_IFDS_TAG.to_be_bytes());
+            directory.extend([0, 4, 0, 0, 0, 1, 0, 0, 0, 0]);
+        }
+        let mut bytes = b"MM\0*\0\0\0\x08".to_vec();
+        bytes.extend((count as u16).to_be_bytes());
+        bytes.extend(directory);
+        bytes.extend([0; 4]);
+        bytes.extend(data);
+        bytes
+    }
+
+    // a big endian TIFF directory of numeric tags, one value each
+    #[cfg(feature = "raw")]
+    fn directory(entries: &[(u16, u16, u32)], next: u32) -> Vec<u8> {
+        let mut bytes = (entries.len() as u16).to_be_bytes().to_vec();
+        for (tag, kind, value) in entries {
+            bytes.extend(tag.to_be_bytes());
+            bytes.extend(kind.to_be_bytes());
+            bytes.extend(1u32.to_be_bytes());
+            match *kind {
+                SHORT_TYPE => bytes.extend([(*value as u16).to_be_bytes(), [0, 0]].concat()),
+                _ => bytes.extend(value.to_be_bytes()),
+            }
+        }
+        bytes.extend(next.to_be_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "raw")]
+    #[test]
+    fn test_raw_size() {
+        // a preview in the first directory and the sensor data in its sub-directory at 50
+        let mut photo = b"MM\0*\0\0\0\x08".to_vec();
+        photo.extend(directory(
+            &[
+                (IMAGE_WIDTH_TAG, SHORT_TYPE, 160),
+                (IMAGE_LENGTH_TAG, SHORT_TYPE, 120),
+                (SUB_IFDS_TAG, LONG_TYPE, 50),
+            ],
+            0,
+        ));
+        photo.extend(directory(
+            &[
+                (IMAGE_WIDTH_TAG, LONG_TYPE, 6000),
+                (IMAGE_LENGTH_TAG, LONG_TYPE, 4000),
+            ],
+            0,
+        ));
+        assert_eq!(raw_size(&photo), Some((6000, 4000)));
+        // a directory chained to itself
+        let mut looped = b"MM\0*\0\0\0\x08".to_vec();
+        looped.extend(directory(
+            &[
+                (IMAGE_WIDTH_TAG, SHORT_TYPE, 160),
+                (IMAGE_LENGTH_TAG, SHORT_TYPE, 120),
+            ],
+            8,
+        ));
+        assert_eq!(raw_size(&looped), Some((160, 120)));
+        assert_eq!(raw_size(b"MM\0*\0\0\0\x08\0\0\0\0\0\0"), None);
+    }
+
+    #[cfg(feature = "raw")]
+    #[test]
+    fn test_sniff_raw_format() {
+        assert_eq!(sniff_raw_format(b"II*\0\x10\0\0\0CR\x02\0"), Some("cr2"));
+        let nikon: &[u8] = b"NIKON CORPORATION\0";
+        assert_eq!(
+            sniff_raw_format(&tiff(&[(MAKE_TAG, nikon)], true)),
+            Some("nef")
+        );
+        assert_eq!(
+            sniff_raw_format(&tiff(&[(MAKE_TAG, b"SONY\0")], true)),
+            Some("arw")
+        );
+        // the TIFF files exported from the photos keep the maker but not the sensor data
+        assert_eq!(sniff_raw_format(&tiff(&[(MAKE_TAG, nikon)], false)), None);
+        // the maker has to be the one of the `Make` tag, not any text of the file
+        assert_eq!(sniff_raw_format(&tiff(&[(0x010E, nikon)], true)), None);
+        assert_eq!(sniff_raw_format(b"II*\0\x08\0\0\0\x0f\0"), None);
+        assert_eq!(sniff_raw_format(b"\xFF\xD8\xFFSONY"), None);
+    }
+
+    #[test]
+    fn test_sniff_image_format() {
+        assert_eq!(
+            sniff_image_format(b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+            Some("jpeg")
+        );
+        assert_eq!(
+            sniff_image_format(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("png")
+        );
+        assert_eq!(sniff_image_format(b"GIF89a\x01\0"), Some("gif"));
+        assert_eq!(sniff_image_format(b"RIFF\x24\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniff_image_format(b"MM\0*\0\0\0\x08"), Some("tiff"));
+        assert_eq!(
+            sniff_image_format(b"\0\0\0\x18ftypheic\0\0\0\0"),
+            Some("heic")
+        );
+        assert_eq!(sniff_image_format(b"%PDF-1.7\n%\xE2\xE3"), Some("pdf"));
+        assert_eq!(sniff_image_format(b"\0\0\0\x18ftypisom\0\0\0\0"), None);
+        assert_eq!(sniff_image_format(b"<html><body>"), None);
+        assert_eq!(sniff_image_format(b""), None);
+    }
+}
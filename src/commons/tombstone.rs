@@ -0,0 +1,67 @@
+// (c) Copyright 2019-2024 OLX
+
+// a purged image leaves a tombstone file next to its local copy. while the tombstone is alive
+// the image isn't served anymore, so that replicas and CDN revalidations relying on stale copies
+// can't bring the purged image back before every cache had the chance to drop it. the purge is a
+// soft delete: the local file may be an original, so it's left in place and served again once
+// the tombstone expires.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::clock::Clock;
+use super::filesystem::FileSystem;
+
+pub const DEFAULT_TOMBSTONE_TTL_SECS: u64 = 3600;
+
+pub fn tombstone_path(path: &Path) -> PathBuf {
+    let mut tombstone = OsString::from(path.as_os_str());
+    tombstone.push(".tombstone");
+    PathBuf::from(tombstone)
+}
+
+pub async fn is_purged(
+    filesystem: &dyn FileSystem,
+    clock: &dyn Clock,
+    path: &Path,
+    ttl: Duration,
+) -> bool {
+    match filesystem.modified(&tombstone_path(path)).await {
+        Ok(purged_at) => clock.now() < purged_at + ttl,
+        Err(_) => false,
+    }
+}
+
+// (re)creates the tombstone of the image, which restarts the ttl
+pub async fn purge(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(tombstone_path(path), b"").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::clock::FixedClock;
+    use crate::commons::filesystem::InMemoryFileSystem;
+    use std::collections::HashMap;
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn test_is_purged() {
+        let purged_at = UNIX_EPOCH + Duration::from_secs(1_000);
+        let filesystem = InMemoryFileSystem {
+            files: HashMap::from([(PathBuf::from("/public/a.jpg.tombstone"), purged_at)]),
+        };
+        let ttl = Duration::from_secs(60);
+        let path = Path::new("/public/a.jpg");
+        let before_expiry = FixedClock(purged_at + Duration::from_secs(59));
+        let after_expiry = FixedClock(purged_at + Duration::from_secs(60));
+        assert!(is_purged(&filesystem, &before_expiry, path, ttl).await);
+        assert!(!is_purged(&filesystem, &after_expiry, path, ttl).await);
+        assert!(!is_purged(&filesystem, &before_expiry, Path::new("/public/b.jpg"), ttl).await);
+    }
+}
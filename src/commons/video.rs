@@ -0,0 +1,100 @@
+// (c) Copyright 2019-2024 OLX
+
+// the video sources are replaced by one of their frames, the poster frame, extracted by ffmpeg.
+// the frame is encoded to PNG and then processed like any other source image.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+pub const DEFAULT_FFMPEG_PATH: &str = "ffmpeg";
+pub const DEFAULT_FFMPEG_TIMEOUT_SECS: u64 = 30;
+
+const MP4_BRANDS: [&[u8]; 10] = [
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"M4V ", b"dash",
+];
+
+// returns the name of the video container the bytes start with
+pub fn sniff_video_format(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && MP4_BRANDS.contains(&&bytes[8..12]) {
+        Some("mp4")
+    } else if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("webm")
+    } else {
+        None
+    }
+}
+
+// returns the frame shown `seconds` after the start of the video, encoded to PNG. ffmpeg is killed
+// when it takes longer than `timeout`, as a crafted video can keep it busy for long.
+pub async fn extract_poster_frame(
+    video: &[u8],
+    seconds: f64,
+    ffmpeg: &str,
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    // the MP4 files usually have their index at the end, which ffmpeg can't seek to in a pipe, so
+    // the video is written to a temporary file first. the file is created under a random name,
+    // readable by dali only, and removed once dropped.
+    let file = tempfile::Builder::new()
+        .prefix("dali-")
+        .suffix(".video")
+        .tempfile()
+        .map_err(|e| format!("the video couldn't be written to disk: {}", e))?;
+    tokio::fs::write(file.path(), video)
+        .await
+        .map_err(|e| format!("the video couldn't be written to disk: {}", e))?;
+
+    // seeking before the input jumps to the closest keyframe and decodes from there
+    let ffmpeg_run = Command::new(ffmpeg)
+        .args(["-v", "error", "-ss", &seconds.to_string(), "-i"])
+        .arg(file.path())
+        .args([
+            "-frames:v",
+            "1",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "png",
+            "pipe:1",
+        ])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, ffmpeg_run)
+        .await
+        .map_err(|_| format!("ffmpeg has timed out after {:?}", timeout))?
+        .map_err(|e| format!("ffmpeg couldn't be run: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg has failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // ffmpeg succeeds without writing anything when the video is shorter than the position
+    if output.stdout.is_empty() {
+        return Err(format!("the video has no frame at {} seconds", seconds));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_video_format() {
+        assert_eq!(
+            sniff_video_format(b"\0\0\0\x20ftypisom\0\0\x02\0"),
+            Some("mp4")
+        );
+        assert_eq!(
+            sniff_video_format(b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81"),
+            Some("webm")
+        );
+        assert_eq!(sniff_video_format(b"\0\0\0\x18ftypheic\0\0\0\0"), None);
+        assert_eq!(sniff_video_format(b"\xFF\xD8\xFF\xE0"), None);
+    }
+}
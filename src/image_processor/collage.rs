@@ -0,0 +1,140 @@
+// (c) Copyright 2019-2024 OLX
+
+// lays several images out in a single one, every image covering a cell of the same size
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::*;
+
+const DEFAULT_CELL_SIZE: i32 = 300;
+
+pub fn process_collage(
+    buffers: Vec<ImageBuffer>,
+    request: CollageRequest,
+    config: &Configuration,
+    kill_switch: &KillSwitch,
+) -> Result<ProcessedImage> {
+    let source_hashes: Vec<u8> = buffers
+        .iter()
+        .flat_map(|buffer| xxh3_64(buffer).to_le_bytes())
+        .collect();
+    let provenance = provenance_token(&source_hashes, &request);
+    let mut recorder = OperationsRecorder::new(false);
+
+    let width = request.size.width.unwrap_or(DEFAULT_CELL_SIZE);
+    let cell = Size {
+        width: Some(width),
+        height: Some(request.size.height.unwrap_or(width)),
+    };
+    let mut cells = buffers
+        .iter()
+        .map(|buffer| {
+            recorder.record_image("cell", || {
+                load_cell(buffer, &cell, request.background, config)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let columns = request.layout.columns(cells.len()) as i32;
+    let [r, g, b, _] = request.background.0.map(f64::from);
+    let collage = recorder.record_image("arrayjoin", || {
+        ops::arrayjoin_with_opts(
+            &mut cells,
+            &ops::ArrayjoinOptions {
+                across: columns,
+                shim: request.spacing.max(0),
+                background: vec![r, g, b],
+                ..ops::ArrayjoinOptions::default()
+            },
+        )
+    })?;
+
+    let format = request.format;
+    let quality = match request.quality {
+        Quality::Value(quality) => quality,
+        Quality::Auto if is_lossy_format(format) => recorder.record("auto_quality", || {
+            kill_switch.compute(&collage, || measure_auto_quality(&collage))
+        })?,
+        Quality::Auto => DEFAULT_QUALITY,
+    };
+    let (width, height) = (collage.get_width(), collage.get_height());
+    let bytes = recorder.record("encode", || {
+        kill_switch.compute(&collage, || {
+            save_buffer_fn(
+                format,
+                &collage,
+                &EncodingOptions {
+                    quality,
+                    effort: None,
+                    lossless: false,
+                    smart_subsample: None,
+                    near_lossless: None,
+                    alpha_q: None,
+                    interlace: None,
+                    png_palette: false,
+                    png_colors: None,
+                    png_dither: None,
+                    compression: None,
+                    bitdepth: None,
+                    strip: config.strip_metadata.unwrap_or(false),
+                },
+                config,
+            )
+        })
+    })?;
+    Ok(ProcessedImage {
+        bytes,
+        variants: vec![],
+        width,
+        height,
+        original_width: width,
+        original_height: height,
+        format,
+        provenance,
+        applied_ops: recorder.applied_ops,
+        timings: recorder.timings,
+        trace: None,
+    })
+}
+
+// the cells are joined as opaque sRGB images, whatever the images they're made of
+fn load_cell(
+    buffer: &[u8],
+    cell: &Size,
+    background: Color,
+    config: &Configuration,
+) -> Result<VipsImage> {
+    let options = pdf_dpi_option(buffer, config).map_or(String::new(), |dpi| format!("[{}]", dpi));
+    let image = load_source(buffer, &options)?;
+    let image = if has_icc_profile(&image) {
+        to_srgb(&image)?
+    } else {
+        image
+    };
+    let image = ops::autorot(&image)?;
+    let image = resize_image(
+        image,
+        cell,
+        Fit::Cover,
+        Kernel::default(),
+        true,
+        background,
+        None,
+    )?;
+    let image = if image.get_bands() < 3 {
+        ops::colourspace(&image, ops::Interpretation::Srgb)?
+    } else {
+        image
+    };
+    if !image.image_hasalpha() {
+        return Ok(image);
+    }
+    let [r, g, b, _] = background.0.map(f64::from);
+    ops::flatten_with_opts(
+        &image,
+        &ops::FlattenOptions {
+            background: vec![r, g, b],
+            ..ops::FlattenOptions::default()
+        },
+    )
+}
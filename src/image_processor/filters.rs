@@ -0,0 +1,103 @@
+// (c) Copyright 2019-2024 OLX
+
+// the color filters of the `filter` parameter and the brightness, contrast, saturation and gamma
+// adjustments. they work on the sRGB bands of the image, while the alpha band, if any, is kept as
+// it is.
+
+use libvips::ops;
+use libvips::Result;
+use libvips::VipsImage;
+
+use crate::commons::Filter;
+
+// the adjustments are factors, 1 leaving the image unchanged
+const MAX_ADJUSTMENT: f64 = 4.0;
+
+// the usual sepia toning, as a recombination of the red, green and blue bands
+const SEPIA: [f64; 9] = [
+    0.393, 0.769, 0.189, //
+    0.349, 0.686, 0.168, //
+    0.272, 0.534, 0.131,
+];
+
+// the Rec. 709 luminance, copied to each of the three bands
+const LUMINANCE: [f64; 9] = [
+    0.2126, 0.7152, 0.0722, //
+    0.2126, 0.7152, 0.0722, //
+    0.2126, 0.7152, 0.0722,
+];
+
+pub fn apply_filter(image: &VipsImage, filter: &Filter) -> Result<VipsImage> {
+    match filter {
+        Filter::Greyscale => ops::colourspace(image, ops::Interpretation::BW),
+        Filter::Sepia => map_colors(image, |rgb| {
+            let matrix = VipsImage::new_matrix_from_array(3, 3, &SEPIA)?;
+            ops::recomb(&rgb, &matrix)
+        }),
+        // the luminance is mapped linearly from the dark color to the light one
+        Filter::Duotone { dark, light } => map_colors(image, |rgb| {
+            let matrix = VipsImage::new_matrix_from_array(3, 3, &LUMINANCE)?;
+            let luminance = ops::recomb(&rgb, &matrix)?;
+            let mut scale: Vec<f64> = dark
+                .iter()
+                .zip(light)
+                .map(|(dark, light)| (f64::from(*light) - f64::from(*dark)) / 255.0)
+                .collect();
+            let mut offset: Vec<f64> = dark.iter().map(|dark| f64::from(*dark)).collect();
+            ops::linear(&luminance, &mut scale, &mut offset)
+        }),
+    }
+}
+
+pub fn adjust_colors(
+    mut image: VipsImage,
+    brightness: Option<f64>,
+    contrast: Option<f64>,
+    saturation: Option<f64>,
+) -> Result<VipsImage> {
+    let [brightness, contrast, saturation] = [brightness, contrast, saturation]
+        .map(|factor| factor.unwrap_or(1.0).clamp(0.0, MAX_ADJUSTMENT));
+    if brightness != 1.0 || contrast != 1.0 {
+        // the contrast is stretched around the middle grey, then the brightness scaled
+        image = map_colors(&image, |rgb| {
+            let mut scale = vec![brightness * contrast; 3];
+            let mut offset = vec![brightness * 128.0 * (1.0 - contrast); 3];
+            ops::linear(&rgb, &mut scale, &mut offset)
+        })?;
+    }
+    if saturation != 1.0 {
+        // the chroma is scaled in the LCh space, which keeps the lightness and the hue
+        image = map_colors(&image, |rgb| {
+            let lch = ops::colourspace(&rgb, ops::Interpretation::Lch)?;
+            let lch = ops::linear(&lch, &mut [1.0, saturation, 1.0], &mut [0.0, 0.0, 0.0])?;
+            ops::colourspace(&lch, ops::Interpretation::Srgb)
+        })?;
+    }
+    Ok(image)
+}
+
+// the values are raised to the power of 1 / gamma, so a gamma over 1 brightens the shadows of the
+// images which came out too dark
+pub fn apply_gamma(image: &VipsImage, gamma: f64) -> Result<VipsImage> {
+    let options = ops::GammaOptions {
+        exponent: gamma.clamp(0.1, 10.0),
+    };
+    map_colors(image, |rgb| ops::gamma_with_opts(&rgb, &options))
+}
+
+// runs the operation on the red, green and blue bands of the image and joins the alpha band back
+// to the result, cast back to 8 bits
+fn map_colors(
+    image: &VipsImage,
+    op: impl FnOnce(VipsImage) -> Result<VipsImage>,
+) -> Result<VipsImage> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let rgb = ops::extract_band_with_opts(&image, 0, &ops::ExtractBandOptions { n: 3 })?;
+    let filtered = ops::cast(&op(rgb)?, ops::BandFormat::Uchar)?;
+    if image.image_hasalpha() {
+        let alpha = ops::extract_band(&image, image.get_bands() - 1)?;
+        ops::bandjoin(&mut [filtered, alpha])
+    } else {
+        Ok(filtered)
+    }
+}
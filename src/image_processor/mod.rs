@@ -1,15 +1,271 @@
 // (c) Copyright 2019-2024 OLX
 
+use self::filters::{adjust_colors, apply_filter, apply_gamma};
+use self::watermark_cache::{get_or_prepare, PreparedWatermark, WatermarkKey};
+use crate::commons::config::Configuration;
+use crate::commons::errors::InvalidSizeError;
+use crate::commons::provenance::provenance_token;
+use crate::commons::signature::{is_svg, sniff_image_format};
+#[cfg(feature = "raw")]
+use crate::commons::signature::{raw_size, sniff_raw_format};
 use crate::commons::*;
+use crate::image_provider::ImageBuffer;
 use libvips::bindings;
 use libvips::ops;
+use libvips::ops::Angle;
 use libvips::Result;
 use libvips::VipsImage;
 use log::*;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub mod collage;
+pub mod filters;
+pub mod stats;
+pub mod watermark_cache;
+
+impl From<InvalidSizeError> for libvips::error::Error {
+    fn from(_error: InvalidSizeError) -> Self {
+        libvips::error::Error::InitializationError("Invalid size")
+    }
+}
+
+impl From<Blend> for ops::BlendMode {
+    fn from(val: Blend) -> Self {
+        match val {
+            Blend::Over => ops::BlendMode::Over,
+            Blend::Multiply => ops::BlendMode::Multiply,
+            Blend::Screen => ops::BlendMode::Screen,
+            Blend::Overlay => ops::BlendMode::Overlay,
+            Blend::Darken => ops::BlendMode::Darken,
+            Blend::Lighten => ops::BlendMode::Lighten,
+            Blend::ColourDodge => ops::BlendMode::ColourDodge,
+            Blend::ColourBurn => ops::BlendMode::ColourBurn,
+            Blend::HardLight => ops::BlendMode::HardLight,
+            Blend::SoftLight => ops::BlendMode::SoftLight,
+            Blend::Difference => ops::BlendMode::Difference,
+            Blend::Exclusion => ops::BlendMode::Exclusion,
+        }
+    }
+}
+
+impl From<Kernel> for ops::Kernel {
+    fn from(val: Kernel) -> Self {
+        match val {
+            Kernel::Lanczos3 => ops::Kernel::Lanczos3,
+            Kernel::Mitchell => ops::Kernel::Mitchell,
+            Kernel::Cubic => ops::Kernel::Cubic,
+            Kernel::Nearest => ops::Kernel::Nearest,
+        }
+    }
+}
+
+impl From<Rotation> for Angle {
+    fn from(val: Rotation) -> Self {
+        // we want it inverted as we want it anti-clockwise
+        match val {
+            Rotation::R90 => Angle::D270,
+            Rotation::R180 => Angle::D180,
+            Rotation::R270 => Angle::D90,
+        }
+    }
+}
+
+const EXIF_USER_COMMENT: &str = "exif-ifd2-UserComment";
+const ICC_PROFILE: &str = "icc-profile-data";
+const DEFAULT_QUALITY: i32 = 80;
+const DEFAULT_AVIF_EFFORT: i32 = 4;
+const DEFAULT_WEBP_EFFORT: i32 = 2;
+// the libvips default, the lower efforts encode several times faster
+const DEFAULT_HEIC_EFFORT: i32 = 4;
+const DEFAULT_JXL_EFFORT: i32 = 7;
+const DEFAULT_TRIM_THRESHOLD: f64 = 10.0;
+// the rendering of the text watermarks is bounded, as it's driven by the query string
+const MAX_TEXT_LENGTH: usize = 256;
+const MAX_TEXT_SIZE: i32 = 512;
+// the gaussian blur gets slower as the sigma grows, well past the point it makes any difference
+const MAX_BLUR_SIGMA: f64 = 50.0;
+const DEFAULT_WATERMARK_CACHE_TTL_SECS: u64 = 300;
+const DEFAULT_PDF_DPI: f64 = 72.0;
 
 #[derive(Clone)]
 pub struct VipsOutput(Option<Vec<u8>>);
 
+// the outcome of the processing pipeline. the timings of the operations preceding the encoding
+// mostly account for building the libvips pipeline, as the pixels are only computed when the
+// image is encoded.
+pub struct ProcessedImage {
+    pub bytes: VipsOutput,
+    // the smaller variants asked for along with the image, from the largest to the smallest
+    pub variants: Vec<ProcessedVariant>,
+    pub width: i32,
+    pub height: i32,
+    pub original_width: i32,
+    pub original_height: i32,
+    pub format: ImageFormat,
+    pub provenance: String,
+    pub applied_ops: Vec<&'static str>,
+    pub timings: Vec<(&'static str, Duration)>,
+    // the steps of the pipeline, when the request asks to debug it
+    pub trace: Option<String>,
+}
+
+// lets the request side abort the processing it gave up on. libvips keeps checking the kill flag
+// of the images it reads from while it computes the pixels, which is where the time goes, so the
+// flag is set on the image of every stage pulling pixels: the encoding, but also the measures and
+// the crops which scan the image. the stages in between don't start once it's killed.
+#[derive(Default)]
+pub struct KillSwitch {
+    state: Mutex<KillState>,
+}
+
+const KILLED: libvips::error::Error =
+    libvips::error::Error::OperationError("the processing was killed");
+
+#[derive(Default)]
+struct KillState {
+    killed: bool,
+    // the address of the image being computed, only set while it's alive
+    computing: Option<usize>,
+}
+
+impl KillSwitch {
+    pub fn kill(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.killed = true;
+        if let Some(image) = state.computing {
+            unsafe { bindings::vips_image_set_kill(image as *mut bindings::VipsImage, 1) };
+        }
+    }
+
+    fn compute<T>(&self, image: &VipsImage, compute: impl FnOnce() -> Result<T>) -> Result<T> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.killed {
+                return Err(KILLED);
+            }
+            state.computing = Some(image.as_mut_ptr() as usize);
+        }
+        let result = compute();
+        let mut state = self.state.lock().unwrap();
+        state.computing = None;
+        // a killed `image_write_to_memory` returns no pixels rather than an error
+        if state.killed {
+            return Err(KILLED);
+        }
+        result
+    }
+}
+
+// what the header of a source image tells about it, without decoding its pixels
+pub struct ImageInfo {
+    pub width: i32,
+    pub height: i32,
+    pub pages: i32,
+    pub orientation: i32,
+    pub interpretation: String,
+    pub has_alpha: bool,
+    pub has_profile: bool,
+}
+
+pub struct ProcessedVariant {
+    pub bytes: VipsOutput,
+    pub width: i32,
+    pub height: i32,
+}
+
+// a step of the operation chain as it is dumped when the request asks for tracing
+struct TracedStep {
+    operation: &'static str,
+    // the size of the image the step was given, but for the loading
+    input: Option<(i32, i32)>,
+    width: i32,
+    height: i32,
+    bands: i32,
+    elapsed: Duration,
+}
+
+impl fmt::Display for TracedStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (", self.operation)?;
+        if let Some((width, height)) = self.input {
+            write!(f, "{}x{} -> ", width, height)?;
+        }
+        write!(
+            f,
+            "{}x{}, {} bands, {:.2}ms)",
+            self.width,
+            self.height,
+            self.bands,
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[derive(Default)]
+struct OperationsRecorder {
+    applied_ops: Vec<&'static str>,
+    timings: Vec<(&'static str, Duration)>,
+    trace: Option<Vec<TracedStep>>,
+}
+
+impl OperationsRecorder {
+    fn new(trace: bool) -> Self {
+        Self {
+            trace: trace.then(Vec::new),
+            ..Self::default()
+        }
+    }
+
+    fn record<T>(&mut self, operation: &'static str, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = op()?;
+        self.applied_ops.push(operation);
+        self.timings.push((operation, start.elapsed()));
+        Ok(result)
+    }
+
+    // same as `record`, but it also keeps the dimensions of the resulting image when tracing
+    fn record_image(
+        &mut self,
+        operation: &'static str,
+        op: impl FnOnce() -> Result<VipsImage>,
+    ) -> Result<VipsImage> {
+        let start = Instant::now();
+        let image = self.record(operation, op)?;
+        self.trace_step(operation, &image, start.elapsed());
+        Ok(image)
+    }
+
+    fn trace_step(&mut self, operation: &'static str, image: &VipsImage, elapsed: Duration) {
+        if let Some(steps) = self.trace.as_mut() {
+            let input = steps.last().map(|step| (step.width, step.height));
+            steps.push(TracedStep {
+                operation,
+                input,
+                width: image.get_width(),
+                height: image.get_height(),
+                bands: image.get_bands(),
+                elapsed,
+            });
+        }
+    }
+
+    fn chain(&self) -> Option<String> {
+        self.trace.as_ref().map(|steps| {
+            let chain: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+            chain.join(" -> ")
+        })
+    }
+
+    fn dump_trace(&self, image_address: &str) {
+        if let Some(chain) = self.chain() {
+            info!("libvips operation chain for '{}': {}", image_address, chain);
+        }
+    }
+}
+
 impl From<Vec<u8>> for VipsOutput {
     fn from(buf: Vec<u8>) -> Self {
         Self(Some(buf))
@@ -33,205 +289,1464 @@ impl Drop for VipsOutput {
     }
 }
 
+// the settings of the encoders which can be picked per request
+pub struct EncodingOptions {
+    pub quality: i32,
+    pub effort: Option<i32>,
+    pub lossless: bool,
+    pub smart_subsample: Option<bool>,
+    pub near_lossless: Option<bool>,
+    pub alpha_q: Option<i32>,
+    pub interlace: Option<bool>,
+    pub png_palette: bool,
+    pub png_colors: Option<i32>,
+    pub png_dither: Option<f64>,
+    pub compression: Option<i32>,
+    pub bitdepth: Option<i32>,
+    pub strip: bool,
+}
+
 pub fn save_buffer_fn(
     format: ImageFormat,
-    final_image: VipsImage,
-    quality: i32,
+    final_image: &VipsImage,
+    encoding: &EncodingOptions,
+    config: &Configuration,
 ) -> Result<VipsOutput> {
+    // the ICC profile is kept, the colors would change without it
+    let keep = if encoding.strip {
+        ops::ForeignKeep::Icc
+    } else {
+        ops::ForeignKeep::All
+    };
     match format {
         ImageFormat::Jpeg => {
+            let flattened = flatten(final_image, config)?;
             let options = ops::JpegsaveBufferOptions {
-                q: quality,
-                background: vec![255.0],
+                q: encoding.quality,
                 optimize_coding: true,
-                interlace: true,
+                interlace: encoding.interlace.unwrap_or(true),
+                keep,
                 ..ops::JpegsaveBufferOptions::default()
             };
-            let out = ops::jpegsave_buffer_with_opts(&final_image, &options).map(|u8| u8.into());
+            let out = ops::jpegsave_buffer_with_opts(&flattened, &options).map(|u8| u8.into());
             final_image.image_set_kill(true);
             out
         }
         ImageFormat::Webp => {
+            // the quality is ignored by the lossless compression, while the near lossless one
+            // takes it as the amount of preprocessing
+            let defaults = ops::WebpsaveBufferOptions::default();
             let options = ops::WebpsaveBufferOptions {
-                q: quality,
-                effort: 2,
-                ..ops::WebpsaveBufferOptions::default()
+                q: encoding.quality,
+                lossless: encoding.lossless,
+                effort: encoding
+                    .effort
+                    .or(config.webp_effort)
+                    .unwrap_or(DEFAULT_WEBP_EFFORT)
+                    .clamp(0, 6),
+                smart_subsample: encoding
+                    .smart_subsample
+                    .or(config.webp_smart_subsample)
+                    .unwrap_or(defaults.smart_subsample),
+                near_lossless: encoding
+                    .near_lossless
+                    .or(config.webp_near_lossless)
+                    .unwrap_or(defaults.near_lossless),
+                alpha_q: encoding
+                    .alpha_q
+                    .or(config.webp_alpha_q)
+                    .map_or(defaults.alpha_q, |alpha_q| alpha_q.clamp(0, 100)),
+                keep,
+                ..defaults
             };
-            let out = ops::webpsave_buffer_with_opts(&final_image, &options).map(|u8| u8.into());
+            let out = ops::webpsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
             final_image.image_set_kill(true);
             out
         }
         ImageFormat::Png => {
+            // the palette images are quantized to the asked number of colors, which libvips
+            // takes as the bit depth of the palette
+            let bitdepth = match encoding.png_colors {
+                Some(colors) if encoding.png_palette && colors <= 2 => 1,
+                Some(colors) if encoding.png_palette && colors <= 4 => 2,
+                Some(colors) if encoding.png_palette && colors <= 16 => 4,
+                _ if !encoding.png_palette
+                    && matches!(encoding.bitdepth, Some(depth) if depth > 8) =>
+                {
+                    16
+                }
+                _ => 8,
+            };
+            let defaults = ops::PngsaveBufferOptions::default();
             let options = ops::PngsaveBufferOptions {
-                q: quality,
-                bitdepth: 8,
-                ..ops::PngsaveBufferOptions::default()
+                q: encoding.quality,
+                bitdepth,
+                interlace: encoding.interlace.unwrap_or(false),
+                palette: encoding.png_palette,
+                dither: encoding
+                    .png_dither
+                    .map_or(defaults.dither, |dither| dither.clamp(0.0, 1.0)),
+                compression: encoding
+                    .compression
+                    .map_or(defaults.compression, |compression| compression.clamp(0, 9)),
+                keep,
+                ..defaults
             };
-            let out = ops::pngsave_buffer_with_opts(&final_image, &options).map(|u8| u8.into());
+            let out = ops::pngsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
             final_image.image_set_kill(true);
             out
         }
         ImageFormat::Heic => {
+            let flattened = flatten(final_image, config)?;
             let options = ops::HeifsaveBufferOptions {
-                q: quality,
+                q: encoding.quality,
+                effort: encoding
+                    .effort
+                    .or(config.heic_effort)
+                    .unwrap_or(DEFAULT_HEIC_EFFORT)
+                    .clamp(0, 9),
+                bitdepth: heif_bitdepth(encoding.bitdepth.or(config.heif_bitdepth)),
+                keep,
                 ..ops::HeifsaveBufferOptions::default()
             };
-            let out = ops::heifsave_buffer_with_opts(&final_image, &options).map(|u8| u8.into());
+            let out = ops::heifsave_buffer_with_opts(&flattened, &options).map(|u8| u8.into());
             final_image.image_set_kill(true);
 
             out
         }
+        ImageFormat::Gif => {
+            // the frames of an animation are kept, as they were loaded `page-height` apart
+            let options = ops::GifsaveBufferOptions {
+                effort: 7,
+                bitdepth: 8,
+                keep,
+                ..ops::GifsaveBufferOptions::default()
+            };
+            let out = ops::gifsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
+            final_image.image_set_kill(true);
+            out
+        }
+        ImageFormat::Avif => {
+            // the chroma is subsampled (4:2:0) unless the full resolution one is asked for
+            let subsample_mode = match config.avif_chroma_subsampling.as_deref() {
+                Some("444") => ops::ForeignSubsample::Off,
+                _ => ops::ForeignSubsample::On,
+            };
+            // libvips picks the first AV1 encoder libheif was built with unless one is named
+            let encoder = match config.avif_encoder.as_deref() {
+                Some("aom") => ops::ForeignHeifEncoder::Aom,
+                Some("rav1e") => ops::ForeignHeifEncoder::Rav1E,
+                Some("svt") => ops::ForeignHeifEncoder::Svt,
+                _ => ops::ForeignHeifEncoder::Auto,
+            };
+            let options = ops::HeifsaveBufferOptions {
+                q: encoding.quality,
+                compression: ops::ForeignHeifCompression::Av1,
+                effort: encoding
+                    .effort
+                    .or(config.avif_effort)
+                    .unwrap_or(DEFAULT_AVIF_EFFORT)
+                    .clamp(0, 9),
+                subsample_mode,
+                encoder,
+                bitdepth: heif_bitdepth(encoding.bitdepth.or(config.heif_bitdepth)),
+                keep,
+                ..ops::HeifsaveBufferOptions::default()
+            };
+            let out = ops::heifsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
+            final_image.image_set_kill(true);
+            out
+        }
+        ImageFormat::Tiff => {
+            let compression = match config.tiff_compression.as_deref() {
+                Some("none") => ops::ForeignTiffCompression::None,
+                Some("deflate") => ops::ForeignTiffCompression::Deflate,
+                Some("jpeg") => ops::ForeignTiffCompression::Jpeg,
+                _ => ops::ForeignTiffCompression::Lzw,
+            };
+            // the images are written in strips unless a tile size is configured, which TIFF
+            // wants to be a multiple of 16
+            let tile_size = config
+                .tiff_tile_size
+                .map(|size| size.clamp(16, 1024) / 16 * 16);
+            let options = ops::TiffsaveBufferOptions {
+                q: encoding.quality,
+                compression,
+                tile: tile_size.is_some(),
+                tile_width: tile_size.unwrap_or(128),
+                tile_height: tile_size.unwrap_or(128),
+                keep,
+                ..ops::TiffsaveBufferOptions::default()
+            };
+            let out = ops::tiffsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
+            final_image.image_set_kill(true);
+            out
+        }
+        ImageFormat::Jxl => {
+            let options = ops::JxlsaveBufferOptions {
+                q: encoding.quality,
+                effort: encoding.effort.unwrap_or(DEFAULT_JXL_EFFORT).clamp(1, 9),
+                keep,
+                ..ops::JxlsaveBufferOptions::default()
+            };
+            let out = ops::jxlsave_buffer_with_opts(final_image, &options).map(|u8| u8.into());
+            final_image.image_set_kill(true);
+            out
+        }
     }
 }
 
 pub fn process_image(
-    buffer: Vec<u8>,
-    wm_buffers: Vec<Vec<u8>>,
+    buffer: ImageBuffer,
+    wm_buffers: Vec<Option<ImageBuffer>>,
     parameters: ProcessImageRequest,
-) -> Result<VipsOutput> {
+    config: &Configuration,
+    kill_switch: &KillSwitch,
+) -> Result<ProcessedImage> {
+    let provenance = provenance_token(&buffer, &parameters);
     let ProcessImageRequest {
-        image_address: _addr,
+        image_address,
         size,
+        fit,
+        kernel,
+        // multiplied into the size before the processing
+        dpr: _,
+        enlarge,
+        background,
         format,
         quality,
+        effort,
+        lossless,
+        smart_subsample,
+        near_lossless,
+        alpha_q,
+        interlace,
+        png_palette,
+        png_colors,
+        png_dither,
+        compression,
+        bitdepth,
+        strip,
+        keep_profile,
+        colorspace,
+        page,
+        animated,
+        #[cfg(feature = "video")]
+            t: _,
         watermarks,
+        texts,
         rotation,
+        flip,
+        flop,
+        blur,
+        sharpen,
+        filter,
+        brightness,
+        contrast,
+        saturation,
+        gamma,
         crop,
+        trim,
+        trim_threshold,
+        trim_background,
+        fx,
+        fy,
         square,
+        border,
+        radius,
+        pixelate,
+        widths,
+        operations,
+        autorotate,
+        trace,
+        debug,
     } = parameters;
-    let needs_rotation = rotation.is_some()
-        || match rexif::parse_buffer_quiet(&buffer[..]).0 {
-            Ok(data) => data.entries.into_iter().any(|e| {
-                e.tag == rexif::ExifTag::Orientation
-                    && e.value.to_i64(0).is_some()
-                    && e.value.to_i64(0).unwrap() != 0
-                    && e.value.to_i64(0).unwrap() != 1
-            }),
-            Err(_) => false,
+    let mut recorder = OperationsRecorder::new(trace || debug);
+    let exif = read_exif(&buffer);
+    let autorotate = autorotate && {
+        let bypassed = is_autorotation_bypassed(
+            exif.camera_model.as_deref(),
+            config
+                .autorotate_bypass_models
+                .as_deref()
+                .unwrap_or_default(),
+        );
+        if bypassed {
+            info!(
+                "skipping the EXIF based auto-rotation as the camera model {:?} is bypassed",
+                exif.camera_model
+            );
+        }
+        !bypassed
+    };
+    if !autorotate && exif.orientation.is_some() {
+        debug!(
+            "the EXIF orientation {:?} is ignored for the image",
+            exif.orientation
+        );
+    }
+    let exif_rotation = autorotate && exif.orientation.is_some_and(|o| o != 0 && o != 1);
+    let needs_rotation = rotation.is_some() || exif_rotation;
+    let page = page_to_load(&buffer, page);
+    // the frames are processed one after the other, which doesn't go with a sequential access
+    let animated = keeps_animation(&buffer, format, animated, page);
+    let resizing = size.width.is_some() || size.height.is_some();
+    let enlarge = enlarge.or(config.enlarge).unwrap_or(true);
+    // the JPEGs scaled down enough are decoded at a fraction of their size rather than in full.
+    // the crop rectangles and the trimmed borders are in pixels of the full size image though,
+    // and the decoder averages the pixels, which the nearest neighbour kernel mustn't do.
+    let header = if resizing
+        && kernel != Kernel::Nearest
+        && crop.rect.is_none()
+        && !trim
+        && sniff_image_format(&buffer) == Some("jpeg")
+    {
+        Some(VipsImage::new_from_buffer(&buffer, "")?)
+    } else {
+        None
+    };
+    let shrink = header.as_ref().map_or(1, |header| {
+        // the size is the one of the image once rotated according to its EXIF orientation
+        let (width, height) = match exif.orientation {
+            Some(5..=8) if exif_rotation => (header.get_height(), header.get_width()),
+            _ => (header.get_width(), header.get_height()),
         };
-    let options = if !needs_rotation {
-        "[access=VIPS_ACCESS_SEQUENTIAL]"
+        get_fit_size(width, height, &size, fit, enlarge).map_or(1, |fit_size| {
+            let (scaled_width, scaled_height) = fit_size.scaled;
+            get_shrink_on_load(width, height, scaled_width, scaled_height)
+        })
+    });
+    let shrink_option = format!("shrink={}", shrink);
+    let page_option = page.map(|page| format!("page={}", page));
+    let dpi_option = pdf_dpi_option(&buffer, config);
+
+    let mut options = vec![];
+    // looking for the borders to trim reads the image before it's cut, once more, and every
+    // variant reads the image again, the source being only decoded once with a random access
+    if !needs_rotation && !animated && !trim && widths.is_none() {
+        options.push("access=VIPS_ACCESS_SEQUENTIAL");
+    }
+    if animated {
+        options.push("n=-1");
+    }
+    if let Some(page_option) = &page_option {
+        options.push(page_option);
+    }
+    if let Some(dpi_option) = &dpi_option {
+        options.push(dpi_option);
+    }
+    if shrink > 1 {
+        debug!("Shrinking the JPEG by {} while decoding it", shrink);
+        options.push(&shrink_option);
+    }
+    let options = if options.is_empty() {
+        String::new()
     } else {
-        ""
+        format!("[{}]", options.join(","))
+    };
+    let mut final_image = recorder.record_image("load", || load_source(&buffer, &options))?;
+    let (original_width, original_height) = match &header {
+        Some(header) => (header.get_width(), header.get_height()),
+        None => (final_image.get_width(), frame_height(&final_image)),
     };
-    let mut final_image = VipsImage::new_from_buffer(&buffer.as_slice(), options)?;
+
+    // the CMYK images are converted whatever `keep_profile`, as most formats would take their four
+    // bands for RGBA ones and the colors would come out inverted
+    if (!keep_profile && has_icc_profile(&final_image)) || is_cmyk(&final_image)? {
+        final_image = recorder.record_image("icc_transform", || to_srgb(&final_image))?;
+    }
+
+    if exif_rotation {
+        debug!(
+            "Rotating according to the EXIF orientation {:?}",
+            exif.orientation
+        );
+        final_image = recorder.record_image("autorot", || ops::autorot(&final_image))?;
+    }
+
+    // the translucent backgrounds are only kept by the formats with an alpha channel
+    let background = if supports_transparency(format) {
+        background
+    } else {
+        background.opaque()
+    };
+
+    if let Some(rect) = crop.rect {
+        match rect.clamp_to(final_image.get_width(), frame_height(&final_image)) {
+            Some(CropRect {
+                x,
+                y,
+                width,
+                height,
+            }) => {
+                debug!("Cropping: {:?}", rect);
+                final_image = recorder.record_image("crop", || {
+                    map_frames(final_image, |frame| {
+                        ops::extract_area(&frame, x, y, width, height)
+                    })
+                })?;
+            }
+            None => debug!("the crop {:?} is outside of the image, skipping it", rect),
+        }
+    }
+
+    if trim {
+        let threshold = trim_threshold.unwrap_or(DEFAULT_TRIM_THRESHOLD).max(0.0);
+        final_image = recorder.record_image("trim", || {
+            trim_image(final_image, threshold, trim_background, kill_switch)
+        })?;
+    }
+
+    let focus = match (fx, fy) {
+        (None, None) => None,
+        (fx, fy) => Some((fx.unwrap_or(0.5), fy.unwrap_or(0.5))),
+    };
+
+    if resizing {
+        final_image = recorder.record_image("resize", || {
+            map_frames(final_image, |frame| {
+                resize_image(frame, &size, fit, kernel, enlarge, background, focus)
+            })
+        })?;
+    }
 
     if crop.w.is_some() && crop.h.is_some() {
         debug!("Smart crop: {}", crop);
         if let (Some(width), Some(height)) = (crop.w, crop.h) {
-            let (fw, fh) = (final_image.get_height(), final_image.get_width());
+            let (fw, fh) = (frame_height(&final_image), final_image.get_width());
             // 只在url的w和h小于原图的情况下处理
             if fw >= width && fh >= height {
-                final_image = ops::smartcrop_with_opts(
-                    &final_image,
-                    width,
-                    height,
-                    &libvips::ops::SmartcropOptions {
-                        interesting: ops::Interesting::Centre,
-                        attention_x: 0,
-                        attention_y: 0,
-                        premultiplied: false,
-                    },
-                )?;
+                final_image = match focus {
+                    Some(focus) => recorder.record_image("focalcrop", || {
+                        map_frames(final_image, |frame| {
+                            let (left, top) = get_focal_crop_origin(
+                                frame.get_width(),
+                                frame.get_height(),
+                                width,
+                                height,
+                                focus,
+                            );
+                            ops::extract_area(&frame, left, top, width, height)
+                        })
+                    })?,
+                    None => recorder.record_image("smartcrop", || {
+                        map_frames(final_image, |frame| {
+                            kill_switch.compute(&frame, || {
+                                ops::smartcrop_with_opts(
+                                    &frame,
+                                    width,
+                                    height,
+                                    &libvips::ops::SmartcropOptions {
+                                        interesting: ops::Interesting::Centre,
+                                        attention_x: 0,
+                                        attention_y: 0,
+                                        premultiplied: false,
+                                    },
+                                )
+                            })
+                        })
+                    })?,
+                };
             }
         }
     }
 
-    let image_width = final_image.get_width();
-    let image_height = final_image.get_height();
+    if let Some(sigma) = blur.filter(|sigma| *sigma > 0.0) {
+        let sigma = sigma.min(MAX_BLUR_SIGMA);
+        debug!("Blurring with sigma: {}", sigma);
+        final_image = recorder.record_image("gaussblur", || {
+            map_frames(final_image, |frame| ops::gaussblur(&frame, sigma))
+        })?;
+    }
+
+    if let Some(sharpen) = sharpen {
+        debug!("Sharpening: {:?}", sharpen);
+        let options = ops::SharpenOptions {
+            sigma: sharpen.sigma.clamp(0.1, 10.0),
+            m_2: sharpen.amount.clamp(0.0, 20.0),
+            ..ops::SharpenOptions::default()
+        };
+        final_image = recorder.record_image("sharpen", || {
+            map_frames(final_image, |frame| {
+                ops::sharpen_with_opts(&frame, &options)
+            })
+        })?;
+    }
 
-    for (i, wm_buffer) in wm_buffers.iter().enumerate() {
-        let watermark = &watermarks[i];
-        debug!("Applying watermark: {:?}", watermark);
-        let wm = VipsImage::new_from_buffer(&wm_buffer[..], "[access=VIPS_ACCESS_SEQUENTIAL]")?;
+    if brightness.is_some() || contrast.is_some() || saturation.is_some() {
+        debug!(
+            "Adjusting the brightness {:?}, contrast {:?} and saturation {:?}",
+            brightness, contrast, saturation
+        );
+        final_image = recorder.record_image("adjust", || {
+            adjust_colors(final_image, brightness, contrast, saturation)
+        })?;
+    }
 
-        let wm_width = wm.get_width();
-        let wm_height = wm.get_height();
+    if let Some(filter) = &filter {
+        debug!("Applying the filter: {:?}", filter);
+        final_image = recorder.record_image("filter", || apply_filter(&final_image, filter))?;
+    }
 
-        let (wm_target_width, wm_target_height) = get_watermark_target_size(
-            image_width,
-            image_height,
-            wm_width,
-            wm_height,
-            watermark.size,
-        )?;
+    // the composited watermarks read their pixels in place, up until the image is encoded
+    let mut prepared_watermarks = Vec::with_capacity(wm_buffers.len());
+    let wm_cache_ttl = Duration::from_secs(
+        config
+            .watermark_cache_ttl_secs
+            .unwrap_or(DEFAULT_WATERMARK_CACHE_TTL_SECS),
+    );
 
-        let target_smaller = wm_width * wm_height > wm_target_width * wm_target_height;
-        let wm = if target_smaller {
-            ops::resize(&wm, f64::from(wm_target_width) / f64::from(wm_width))?
-        } else {
-            wm
+    // the watermarks are only applied where the chain names them, when there's one
+    for operation in operations
+        .as_ref()
+        .map_or(&[][..], |operations| &operations.0)
+    {
+        debug!("Applying the operation: {:?}", operation);
+        final_image = match operation {
+            Operation::Resize(width, height) => {
+                let size = Size {
+                    width: *width,
+                    height: *height,
+                };
+                recorder.record_image("resize", || {
+                    map_frames(final_image, |frame| {
+                        resize_image(frame, &size, fit, kernel, enlarge, background, focus)
+                    })
+                })?
+            }
+            Operation::Blur(sigma) => {
+                let sigma = sigma.min(MAX_BLUR_SIGMA);
+                recorder.record_image("gaussblur", || {
+                    map_frames(final_image, |frame| ops::gaussblur(&frame, sigma))
+                })?
+            }
+            Operation::Rotate(rotation) => {
+                let angle = Angle::from(rotation.clone());
+                recorder.record_image("rotate", || {
+                    map_frames(final_image, |frame| ops::rot(&frame, angle))
+                })?
+            }
+            Operation::Flip => recorder.record_image("flip", || {
+                map_frames(final_image, |frame| {
+                    ops::flip(&frame, ops::Direction::Vertical)
+                })
+            })?,
+            Operation::Flop => recorder.record_image("flop", || {
+                map_frames(final_image, |frame| {
+                    ops::flip(&frame, ops::Direction::Horizontal)
+                })
+            })?,
+            Operation::Filter(filter) => {
+                recorder.record_image("filter", || apply_filter(&final_image, filter))?
+            }
+            Operation::Watermark(name) => {
+                let index = name
+                    .parse::<usize>()
+                    .ok()
+                    .or_else(|| watermarks.iter().position(|wm| wm.is_named(name)));
+                // the watermarks which couldn't be downloaded are skipped
+                let fetched =
+                    index.and_then(|i| Some((wm_buffers.get(i)?.as_ref()?, watermarks.get(i)?)));
+                match fetched {
+                    Some((wm_buffer, watermark)) => {
+                        let (image, prepared) = composite_watermark(
+                            &mut recorder,
+                            final_image,
+                            wm_buffer,
+                            watermark,
+                            wm_cache_ttl,
+                            kill_switch,
+                        )?;
+                        prepared_watermarks.extend(prepared);
+                        image
+                    }
+                    None => {
+                        debug!("there's no watermark `{}`, skipping it", name);
+                        final_image
+                    }
+                }
+            }
         };
+    }
 
-        let mut alpha = [1.0, 1.0, 1.0, watermark.alpha];
-        let mut add = [0.0, 0.0, 0.0, 0.0];
+    let image_width = final_image.get_width();
+    let image_height = frame_height(&final_image);
 
-        let wm = if !wm.image_hasalpha() {
-            ops::bandjoin_const(&wm, &mut [255.0])?
+    if operations.is_none() {
+        let fetched = wm_buffers.iter().zip(&watermarks);
+        for (wm_buffer, watermark) in
+            fetched.filter_map(|(buffer, wm)| Some((buffer.as_ref()?, wm)))
+        {
+            let (image, prepared) = composite_watermark(
+                &mut recorder,
+                final_image,
+                wm_buffer,
+                watermark,
+                wm_cache_ttl,
+                kill_switch,
+            )?;
+            final_image = image;
+            prepared_watermarks.extend(prepared);
+        }
+    }
+
+    for text in texts.iter().filter(|text| !text.text.is_empty()) {
+        debug!("Applying text watermark: {:?}", text);
+        final_image = recorder.record_image("text", || {
+            let rendered = render_text(text)?;
+            let (left, top, _, _) = get_watermark_borders(
+                image_width,
+                image_height,
+                rendered.get_width(),
+                rendered.get_height(),
+                &text.position,
+            );
+            let options = ops::Composite2Options {
+                x: left,
+                y: top,
+                ..ops::Composite2Options::default()
+            };
+            map_frames(final_image, |frame| {
+                ops::composite_2_with_opts(&frame, &rendered, ops::BlendMode::Over, &options)
+            })
+        })?;
+    }
+
+    if let Some(rotation) = rotation {
+        debug!("Rotating: {:?}", rotation);
+        let angle = Angle::from(rotation);
+        final_image = recorder.record_image("rotate", || {
+            map_frames(final_image, |frame| ops::rot(&frame, angle))
+        })?;
+    }
+
+    if flip {
+        final_image = recorder.record_image("flip", || {
+            map_frames(final_image, |frame| {
+                ops::flip(&frame, ops::Direction::Vertical)
+            })
+        })?;
+    }
+
+    if flop {
+        final_image = recorder.record_image("flop", || {
+            map_frames(final_image, |frame| {
+                ops::flip(&frame, ops::Direction::Horizontal)
+            })
+        })?;
+    }
+
+    if square {
+        final_image = recorder.record_image("square", || {
+            map_frames(final_image, |frame| {
+                let (width, height) = (frame.get_width(), frame.get_height());
+                let size = i32::max(width, height);
+                let image = ops::thumbnail_image(&frame, size)?;
+                extend_image(image, size, size, background)
+            })
+        })?;
+    }
+
+    if let Some(border) = border.filter(|border| border.width > 0) {
+        debug!("Adding the border: {:?}", border);
+        let color = if supports_transparency(format) {
+            border.color
         } else {
-            wm
+            border.color.opaque()
         };
+        final_image = recorder.record_image("border", || {
+            map_frames(final_image, |frame| {
+                let (width, height) = (frame.get_width(), frame.get_height());
+                extend_image(
+                    frame,
+                    width + 2 * border.width,
+                    height + 2 * border.width,
+                    color,
+                )
+            })
+        })?;
+    }
 
-        let wm = ops::linear(&wm, &mut alpha, &mut add)?;
-        let (left, top, right, bottom) = get_watermark_borders(
-            image_width,
-            image_height,
-            wm_target_width,
-            wm_target_height,
-            &watermark.position,
-        );
+    if let Some(radius) = radius {
+        if supports_transparency(format) {
+            debug!("Rounding the corners: {:?}", radius);
+            final_image = recorder.record_image("radius", || {
+                map_frames(final_image, |frame| round_corners(frame, radius))
+            })?;
+        } else {
+            debug!("the radius is ignored for the {} format", format);
+        }
+    }
+
+    if let Some(gamma) = gamma.filter(|gamma| *gamma != 1.0) {
+        debug!("Applying the gamma: {}", gamma);
+        final_image = recorder.record_image("gamma", || apply_gamma(&final_image, gamma))?;
+    }
+
+    for region in &pixelate {
+        debug!("Pixelating the region: {:?}", region);
+        final_image = recorder.record_image("pixelate", || {
+            map_frames(final_image, |frame| pixelate_region(frame, region))
+        })?;
+    }
+
+    match colorspace {
+        Some(ColorSpace::Cmyk) if !matches!(format, ImageFormat::Jpeg | ImageFormat::Tiff) => {
+            debug!("the CMYK color space is ignored for the {} format", format);
+        }
+        Some(colorspace) => {
+            debug!("Converting to the color space: {:?}", colorspace);
+            final_image = recorder.record_image("colorspace", || {
+                convert_colorspace(&final_image, colorspace, config)
+            })?;
+        }
+        None => {}
+    }
+
+    if config.embed_provenance.unwrap_or(false) {
+        // libvips writes the exif-* fields back into the EXIF block when the image is saved
+        final_image.image_set_string(EXIF_USER_COMMENT, &provenance);
+    }
+
+    let (width, height) = (final_image.get_width(), frame_height(&final_image));
+    debug!("Encoding to: {}", format);
+    let quality = match quality.unwrap_or(Quality::Value(DEFAULT_QUALITY)) {
+        Quality::Value(quality) => quality,
+        Quality::Auto if is_lossy_format(format) => recorder.record("auto_quality", || {
+            kill_switch.compute(&final_image, || measure_auto_quality(&final_image))
+        })?,
+        Quality::Auto => DEFAULT_QUALITY,
+    };
+    debug!("Encoding with the quality {}", quality);
+    let encoding = EncodingOptions {
+        quality,
+        effort,
+        lossless,
+        smart_subsample,
+        near_lossless,
+        alpha_q,
+        interlace,
+        png_palette,
+        png_colors,
+        png_dither,
+        compression,
+        bitdepth,
+        strip: strip.or(config.strip_metadata).unwrap_or(false),
+    };
+    let bytes = recorder.record("encode", || {
+        kill_switch.compute(&final_image, || {
+            save_buffer_fn(format, &final_image, &encoding, config)
+        })
+    })?;
+
+    let mut variants = vec![];
+    let smaller_widths = widths.map(|widths| widths.0).unwrap_or_default();
+    for variant_width in smaller_widths.into_iter().filter(|w| *w < width) {
+        debug!("Encoding the variant {} pixels wide", variant_width);
+        let scale = f64::from(variant_width) / f64::from(width);
+        let variant = recorder.record_image("variant", || {
+            map_frames(ops::copy(&final_image)?, |frame| ops::resize(&frame, scale))
+        })?;
+        let bytes = recorder.record("encode", || {
+            kill_switch.compute(&variant, || {
+                save_buffer_fn(format, &variant, &encoding, config)
+            })
+        })?;
+        variants.push(ProcessedVariant {
+            bytes,
+            width: variant.get_width(),
+            height: frame_height(&variant),
+        });
+    }
+    if trace {
+        recorder.dump_trace(&image_address);
+    }
+    Ok(ProcessedImage {
+        bytes,
+        variants,
+        width,
+        height,
+        original_width,
+        original_height,
+        format,
+        provenance,
+        trace: recorder.chain().filter(|_| debug),
+        applied_ops: recorder.applied_ops,
+        timings: recorder.timings,
+    })
+}
+
+fn is_animated_format(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Gif | ImageFormat::Webp)
+}
+
+fn has_icc_profile(image: &VipsImage) -> bool {
+    image.image_get_typeof(ICC_PROFILE).unwrap_or(0) != 0
+}
+
+// the browsers take the images without a profile as sRGB, so the wide gamut sources, such as the
+// Adobe RGB or Display P3 ones, would come out washed out once their profile is lost or ignored
+fn to_srgb(image: &VipsImage) -> Result<VipsImage> {
+    icc_transform(image, "srgb")
+}
+
+// the images without an embedded profile are taken as sRGB ones, or as CMYK ones for the CMYK
+// images, for which libvips has a fallback profile
+fn icc_transform(image: &VipsImage, output_profile: &str) -> Result<VipsImage> {
+    // the 16-bit sources stay 16-bit, they're only reduced to 8 bits by the encoders
+    let depth = match image.get_format()? {
+        ops::BandFormat::Ushort => 16,
+        _ => 8,
+    };
+    let input_profile = if is_cmyk(image)? { "cmyk" } else { "srgb" };
+    let options = ops::IccTransformOptions {
+        embedded: true,
+        input_profile: input_profile.to_string(),
+        intent: ops::Intent::Perceptual,
+        depth,
+        ..ops::IccTransformOptions::default()
+    };
+    ops::icc_transform_with_opts(image, output_profile, &options)
+}
+
+fn is_cmyk(image: &VipsImage) -> Result<bool> {
+    Ok(matches!(
+        image.get_interpretation()?,
+        ops::Interpretation::Cmyk
+    ))
+}
+
+// the profile of the color space is embedded along with the converted pixels, but for the grey
+// images which are taken as such without a profile
+fn convert_colorspace(
+    image: &VipsImage,
+    colorspace: ColorSpace,
+    config: &Configuration,
+) -> Result<VipsImage> {
+    match colorspace {
+        ColorSpace::Srgb => icc_transform(image, "srgb"),
+        ColorSpace::P3 => icc_transform(image, "p3"),
+        // CMYK has no alpha band, the transparent pixels are flattened beforehand
+        ColorSpace::Cmyk => icc_transform(&flatten(image, config)?, "cmyk"),
+        ColorSpace::Gray => {
+            let grey = ops::colourspace(image, ops::Interpretation::BW)?;
+            grey.image_remove(ICC_PROFILE);
+            Ok(grey)
+        }
+    }
+}
+
+// HEIF only knows of 8, 10 and 12 bits per channel
+fn heif_bitdepth(bitdepth: Option<i32>) -> i32 {
+    match bitdepth {
+        Some(depth) if depth >= 12 => 12,
+        Some(depth) if depth >= 10 => 10,
+        _ => 8,
+    }
+}
+
+// libvips loads the frames of an animation stacked vertically, `page-height` apart
+fn frame_height(image: &VipsImage) -> i32 {
+    let (height, page_height) = (image.get_height(), image.image_get_page_height());
+    if page_height > 0 && page_height < height && height % page_height == 0 {
+        page_height
+    } else {
+        height
+    }
+}
+
+// applies the operation to every frame of an animation, or to the image itself when it's not
+// animated, and stacks the resulting frames back together
+fn map_frames(
+    image: VipsImage,
+    mut op: impl FnMut(VipsImage) -> Result<VipsImage>,
+) -> Result<VipsImage> {
+    let (width, page_height) = (image.get_width(), frame_height(&image));
+    let frames = image.get_height() / page_height;
+    if frames <= 1 {
+        return op(image);
+    }
+    let mut processed = (0..frames)
+        .map(|i| {
+            ops::extract_area(&image, 0, i * page_height, width, page_height).and_then(&mut op)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let processed_height = processed[0].get_height();
+    let joined = ops::arrayjoin_with_opts(
+        &mut processed,
+        &ops::ArrayjoinOptions {
+            across: 1,
+            ..ops::ArrayjoinOptions::default()
+        },
+    )?;
+    joined.image_set_int("page-height", processed_height);
+    Ok(joined)
+}
+
+struct ExifInfo {
+    orientation: Option<i64>,
+    camera_model: Option<String>,
+}
+
+// the loaders of the single image formats reject the `page` option
+pub fn page_to_load(buffer: &[u8], page: Option<i32>) -> Option<i32> {
+    page.map(|page| page.max(0)).filter(|_| {
+        matches!(
+            sniff_image_format(buffer),
+            Some("gif") | Some("webp") | Some("tiff") | Some("heic") | Some("pdf")
+        )
+    })
+}
+
+// every frame of an animation is loaded when the output format can keep it animated, unless
+// a single one or a still image is asked for
+pub fn keeps_animation(
+    buffer: &[u8],
+    format: ImageFormat,
+    animated: Option<bool>,
+    page: Option<i32>,
+) -> bool {
+    is_animated_format(format)
+        && animated.unwrap_or(true)
+        && page.is_none()
+        && matches!(sniff_image_format(buffer), Some("gif") | Some("webp"))
+}
+
+// the width and the height of the pages which are loaded, all of them stacked for an animation,
+// read from the header
+pub fn read_loaded_size(
+    buffer: &[u8],
+    config: &Configuration,
+    page: Option<i32>,
+    animated: bool,
+) -> Result<(i32, i32)> {
+    #[cfg(feature = "raw")]
+    if sniff_raw_format(buffer).is_some() {
+        return raw_size(buffer)
+            .and_then(|(width, height)| Some((width.try_into().ok()?, height.try_into().ok()?)))
+            .ok_or(libvips::error::Error::InitializationError(
+                "the RAW photo has no image size",
+            ));
+    }
+    let mut options = vec![];
+    if animated {
+        options.push(String::from("n=-1"));
+    }
+    options.extend(page.map(|page| format!("page={}", page)));
+    options.extend(pdf_dpi_option(buffer, config));
+    let options = if options.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", options.join(","))
+    };
+    let image = load_source(buffer, &options)?;
+    Ok((image.get_width(), image.get_height()))
+}
+
+pub fn read_image_info(buffer: &[u8], config: &Configuration) -> Result<ImageInfo> {
+    let options = pdf_dpi_option(buffer, config).map_or(String::new(), |dpi| format!("[{}]", dpi));
+    let image = load_source(buffer, &options)?;
+    Ok(ImageInfo {
+        width: image.get_width(),
+        height: frame_height(&image),
+        pages: image.image_get_n_pages(),
+        orientation: image.image_get_orientation(),
+        interpretation: format!("{:?}", image.get_interpretation()?).to_lowercase(),
+        has_alpha: image.image_hasalpha(),
+        has_profile: has_icc_profile(&image),
+    })
+}
+
+// libvips only reads the embedded preview of the RAW photos, which are TIFF files, while
+// ImageMagick develops the whole sensor data with libraw
+#[cfg(feature = "raw")]
+fn load_source(buffer: &[u8], options: &str) -> Result<VipsImage> {
+    if let Some(format) = sniff_raw_format(buffer) {
+        debug!("Loading the {} RAW photo with ImageMagick", format);
+        return ops::magickload_buffer_with_opts(buffer, &magick_load_options(options));
+    }
+    VipsImage::new_from_buffer(buffer, options)
+}
+
+// the options of the `[name=value,...]` string of the other loaders which ImageMagick takes too.
+// the JPEG `shrink` and the PDF `dpi` don't apply to the RAW photos.
+#[cfg(feature = "raw")]
+fn magick_load_options(options: &str) -> ops::MagickloadBufferOptions {
+    let mut magick_options = ops::MagickloadBufferOptions::default();
+    let options = options.trim_start_matches('[').trim_end_matches(']');
+    for option in options.split(',') {
+        match option.split_once('=') {
+            Some(("page", page)) => magick_options.page = page.parse().unwrap_or(0),
+            Some(("n", n)) => magick_options.n = n.parse().unwrap_or(1),
+            Some(("access", "VIPS_ACCESS_SEQUENTIAL")) => {
+                magick_options.access = ops::Access::Sequential
+            }
+            _ => {}
+        }
+    }
+    magick_options
+}
+
+#[cfg(not(feature = "raw"))]
+fn load_source(buffer: &[u8], options: &str) -> Result<VipsImage> {
+    VipsImage::new_from_buffer(buffer, options)
+}
+
+// the PDF pages have no size in pixels until they're rasterized at a given density
+fn pdf_dpi_option(buffer: &[u8], config: &Configuration) -> Option<String> {
+    if sniff_image_format(buffer) != Some("pdf") {
+        return None;
+    }
+    let dpi = config
+        .pdf_dpi
+        .filter(|dpi| *dpi > 0.0)
+        .unwrap_or(DEFAULT_PDF_DPI);
+    Some(format!("dpi={}", dpi))
+}
+
+fn read_exif(buffer: &[u8]) -> ExifInfo {
+    let mut exif = ExifInfo {
+        orientation: None,
+        camera_model: None,
+    };
+    if let Ok(data) = rexif::parse_buffer_quiet(buffer).0 {
+        for entry in data.entries {
+            match (entry.tag, &entry.value) {
+                (rexif::ExifTag::Orientation, value) => exif.orientation = value.to_i64(0),
+                (rexif::ExifTag::Model, rexif::TagValue::Ascii(model)) => {
+                    exif.camera_model = Some(model.clone())
+                }
+                _ => {}
+            }
+        }
+    }
+    exif
+}
+
+// composites the watermark over every frame, sized and positioned after the image as it is at this
+// point of the pipeline. the returned watermark has to outlive the encoding.
+fn composite_watermark(
+    recorder: &mut OperationsRecorder,
+    image: VipsImage,
+    wm_buffer: &[u8],
+    watermark: &Watermark,
+    ttl: Duration,
+    kill_switch: &KillSwitch,
+) -> Result<(VipsImage, Option<Arc<PreparedWatermark>>)> {
+    let (image_width, image_height) = (image.get_width(), frame_height(&image));
+    if !watermark.fits(image_width, image_height) {
         debug!(
-            "Watermark position - Padding: top: {}, left: {}, bottom: {}, right: {}",
-            top, left, bottom, right
+            "skipping the watermark {:?} on the {}x{} image",
+            watermark, image_width, image_height
         );
-        let options = ops::Composite2Options {
-            x: left,
-            y: top,
-            ..ops::Composite2Options::default()
+        return Ok((image, None));
+    }
+    debug!("Applying watermark: {:?}", watermark);
+    let prepared = recorder.record("watermark_prepare", || {
+        prepare_watermark(
+            wm_buffer,
+            watermark,
+            image_width,
+            image_height,
+            ttl,
+            kill_switch,
+        )
+    })?;
+    let image = recorder.record_image("watermark", || {
+        map_frames(image, |frame| {
+            apply_watermark(frame, &prepared, watermark, image_width, image_height)
+        })
+    })?;
+    Ok((image, Some(prepared)))
+}
+
+// decodes the watermark and resizes it to its target size, unless it's cached already
+fn prepare_watermark(
+    wm_buffer: &[u8],
+    watermark: &Watermark,
+    image_width: i32,
+    image_height: i32,
+    cache_ttl: Duration,
+    kill_switch: &KillSwitch,
+) -> Result<Arc<PreparedWatermark>> {
+    let svg = is_svg(wm_buffer);
+    let options = if svg {
+        ""
+    } else {
+        "[access=VIPS_ACCESS_SEQUENTIAL]"
+    };
+    let wm = VipsImage::new_from_buffer(wm_buffer, options)?;
+
+    let (wm_target_width, wm_target_height) = get_watermark_target_size(
+        image_width,
+        image_height,
+        wm.get_width(),
+        wm.get_height(),
+        watermark.size,
+    )?;
+    let key = WatermarkKey {
+        address: watermark.image_address.clone(),
+        width: wm_target_width,
+        height: wm_target_height,
+    };
+    get_or_prepare(key, cache_ttl, || {
+        // the vector watermarks are rendered again right at the target size, rather than resized
+        let wm = if svg {
+            let scale = f64::from(wm_target_width) / f64::from(wm.get_width());
+            VipsImage::new_from_buffer(wm_buffer, &format!("[scale={}]", scale))?
+        } else {
+            wm
         };
-        let wm = if !target_smaller {
-            ops::resize(&wm, f64::from(wm_target_width) / f64::from(wm_width))?
+        let wm = if wm.get_width() != wm_target_width {
+            ops::resize(&wm, f64::from(wm_target_width) / f64::from(wm.get_width()))?
         } else {
             wm
         };
-        final_image =
-            ops::composite_2_with_opts(&final_image, &wm, ops::BlendMode::Over, &options)?;
-    }
-
-    if square {
-        let (width, height) = (final_image.get_width(), final_image.get_height());
-        let size = i32::max(width, height);
-        final_image = ops::thumbnail_image(&final_image, size)?;
-        let opts = ops::GravityOptions {
-            extend: ops::Extend::White,
-            background: vec![],
+        let wm = if !wm.image_hasalpha() {
+            ops::bandjoin_const(&wm, &mut [255.0])?
+        } else {
+            wm
         };
-        final_image = ops::gravity_with_opts(
-            &final_image,
-            ops::CompassDirection::Centre,
-            size,
-            size,
-            &opts,
-        )?;
-    }
+        kill_switch.compute(&wm, || PreparedWatermark::new(&wm))
+    })
+}
 
-    debug!("Encoding to: {}", format);
-    save_buffer_fn(format, final_image, quality)
+fn apply_watermark(
+    final_image: VipsImage,
+    prepared: &PreparedWatermark,
+    watermark: &Watermark,
+    image_width: i32,
+    image_height: i32,
+) -> Result<VipsImage> {
+    let mut alpha = [1.0, 1.0, 1.0, watermark.alpha];
+    let mut add = [0.0, 0.0, 0.0, 0.0];
+    let wm = ops::linear(&prepared.to_image()?, &mut alpha, &mut add)?;
+
+    let (left, top, right, bottom) = get_watermark_borders(
+        image_width,
+        image_height,
+        prepared.width(),
+        prepared.height(),
+        &watermark.position,
+    );
+    debug!(
+        "Watermark position - Padding: top: {}, left: {}, bottom: {}, right: {}",
+        top, left, bottom, right
+    );
+    let options = ops::Composite2Options {
+        x: left,
+        y: top,
+        ..ops::Composite2Options::default()
+    };
+    ops::composite_2_with_opts(&final_image, &wm, watermark.blend.into(), &options)
+}
+
+// renders the text in the watermark color, the antialiased glyphs making the alpha band
+fn render_text(text: &TextWatermark) -> Result<VipsImage> {
+    let content: String = text.text.chars().take(MAX_TEXT_LENGTH).collect();
+    // the text is taken as Pango markup
+    let content = content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    let options = ops::TextOptions {
+        font: format!("{} {}px", text.font, text.size.clamp(1, MAX_TEXT_SIZE)),
+        dpi: 72,
+        ..ops::TextOptions::default()
+    };
+    let glyphs = ops::text_with_opts(&content, &options)?;
+    let [r, g, b, alpha] = text.color.0.map(f64::from);
+    let colors = ops::linear(
+        &ops::colourspace(&glyphs, ops::Interpretation::Srgb)?,
+        &mut [0.0, 0.0, 0.0],
+        &mut [r, g, b],
+    )?;
+    let alpha = ops::linear(
+        &glyphs,
+        &mut [text.alpha.clamp(0.0, 1.0) * alpha / 255.0],
+        &mut [0.0],
+    )?;
+    ops::cast(
+        &ops::bandjoin(&mut [colors, alpha])?,
+        ops::BandFormat::Uchar,
+    )
 }
 
-fn resize_image(img: &VipsImage, size: &Size) -> Result<VipsImage> {
-    debug!("Resizing image to {:?}", size);
+fn resize_image(
+    img: VipsImage,
+    size: &Size,
+    fit: Fit,
+    kernel: Kernel,
+    enlarge: bool,
+    background: Color,
+    focus: Option<(f64, f64)>,
+) -> Result<VipsImage> {
     let original_width = img.get_width();
     let original_height = img.get_height();
 
     debug!(
-        "Resizing image. Original size: {}x{}. Desired: {:?}",
-        original_width, original_height, size
+        "Resizing image. Original size: {}x{}. Desired: {:?}, fit: {:?}",
+        original_width, original_height, size, fit
+    );
+
+    let FitSize {
+        scaled: (scaled_width, scaled_height),
+        target: (target_width, target_height),
+    } = get_fit_size(original_width, original_height, size, fit, enlarge)?;
+
+    debug!(
+        "Scaled size: {}x{}. Final size: {}x{}",
+        scaled_width, scaled_height, target_width, target_height
+    );
+
+    let resized = if (scaled_width, scaled_height) == (original_width, original_height) {
+        img
+    } else {
+        let options = ops::ResizeOptions {
+            vscale: f64::from(scaled_height) / f64::from(original_height),
+            kernel: kernel.into(),
+            ..ops::ResizeOptions::default()
+        };
+        ops::resize_with_opts(
+            &img,
+            f64::from(scaled_width) / f64::from(original_width),
+            &options,
+        )?
+    };
+    // libvips rounds the scaled size on its own, which can be a pixel off
+    let (resized_width, resized_height) = (resized.get_width(), resized.get_height());
+    if (resized_width, resized_height) == (target_width, target_height) {
+        return Ok(resized);
+    }
+    match fit {
+        Fit::Cover => {
+            let (width, height) = (
+                target_width.min(resized_width),
+                target_height.min(resized_height),
+            );
+            let (left, top) = get_focal_crop_origin(
+                resized_width,
+                resized_height,
+                width,
+                height,
+                focus.unwrap_or((0.5, 0.5)),
+            );
+            ops::extract_area(&resized, left, top, width, height)
+        }
+        Fit::Contain => extend_image(resized, target_width, target_height, background),
+        _ => Ok(resized),
+    }
+}
+
+// the borders are looked for on the first frame of an animation and cut from every frame alike
+fn trim_image(
+    image: VipsImage,
+    threshold: f64,
+    background: Option<Color>,
+    kill_switch: &KillSwitch,
+) -> Result<VipsImage> {
+    let (width, height) = (image.get_width(), frame_height(&image));
+    let first_frame = ops::extract_area(&image, 0, 0, width, height)?;
+    let bands = first_frame.get_bands();
+    let background = match background {
+        Some(Color([r, g, b, _])) => {
+            let mut color = if bands >= 3 {
+                vec![f64::from(r), f64::from(g), f64::from(b)]
+            } else {
+                vec![(f64::from(r) + f64::from(g) + f64::from(b)) / 3.0]
+            };
+            if first_frame.image_hasalpha() {
+                color.push(255.0);
+            }
+            color
+        }
+        None => ops::getpoint(&first_frame, 0, 0)?,
+    };
+    let options = ops::FindTrimOptions {
+        threshold,
+        background,
+        ..ops::FindTrimOptions::default()
+    };
+    let (left, top, trimmed_width, trimmed_height) = kill_switch.compute(&first_frame, || {
+        ops::find_trim_with_opts(&first_frame, &options)
+    })?;
+    // nothing but the background was found
+    if trimmed_width <= 0 || trimmed_height <= 0 {
+        return Ok(image);
+    }
+    debug!(
+        "Trimming to {}x{} at {}x{}",
+        trimmed_width, trimmed_height, left, top
     );
+    map_frames(image, |frame| {
+        ops::extract_area(&frame, left, top, trimmed_width, trimmed_height)
+    })
+}
 
-    let (target_width, target_height) = get_target_size(original_width, original_height, size)?;
+// the corners are made transparent through an alpha mask rendered from an SVG rounded rectangle
+fn round_corners(image: VipsImage, radius: Radius) -> Result<VipsImage> {
+    let (width, height) = (image.get_width(), image.get_height());
+    let radius = match radius {
+        Radius::Max => width.min(height) / 2,
+        Radius::Pixels(pixels) => pixels.min(width.min(height) / 2),
+    };
+    if radius <= 0 {
+        return Ok(image);
+    }
+    let svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}"><rect width="{width}" height="{height}" rx="{radius}" ry="{radius}" fill="#fff"/></svg>"##
+    );
+    let mask = VipsImage::new_from_buffer(svg.as_bytes(), "")?;
+    let mask = ops::extract_band(&mask, 3)?;
+    let image = if image.get_bands() < 3 {
+        ops::colourspace(&image, ops::Interpretation::Srgb)?
+    } else {
+        image
+    };
+    let format = image.get_format()?;
+    if image.image_hasalpha() {
+        // the transparency the image already has is kept
+        let bands = image.get_bands();
+        let colors =
+            ops::extract_band_with_opts(&image, 0, &ops::ExtractBandOptions { n: bands - 1 })?;
+        let alpha = ops::extract_band(&image, bands - 1)?;
+        let alpha = ops::linear(
+            &ops::multiply(&alpha, &mask)?,
+            &mut [1.0 / 255.0],
+            &mut [0.0],
+        )?;
+        let alpha = ops::cast(&alpha, format)?;
+        ops::bandjoin(&mut [colors, alpha])
+    } else {
+        let mask = ops::linear(&mask, &mut [sample_scale(&image)?], &mut [0.0])?;
+        let mask = ops::cast(&mask, format)?;
+        ops::bandjoin(&mut [image, mask])
+    }
+}
 
-    debug!("Final size: {}x{}", target_width, target_height);
+// averages the blocks of the region, then scales them back up without interpolation
+fn pixelate_region(image: VipsImage, region: &Pixelate) -> Result<VipsImage> {
+    let Some(rect) = region.rect.clamp_to(image.get_width(), image.get_height()) else {
+        return Ok(image);
+    };
+    let size = region.size.min(rect.width).min(rect.height);
+    if size <= 1 {
+        return Ok(image);
+    }
+    let area = ops::extract_area(&image, rect.x, rect.y, rect.width, rect.height)?;
+    let blocks = ops::shrink(&area, f64::from(size), f64::from(size))?;
+    let mosaic = ops::zoom(&blocks, size, size)?;
+    // the blocks on the edges are cut, or repeated when the shrink rounded the region down
+    let mosaic = ops::embed_with_opts(
+        &mosaic,
+        0,
+        0,
+        rect.width,
+        rect.height,
+        &ops::EmbedOptions {
+            extend: ops::Extend::Copy,
+            ..ops::EmbedOptions::default()
+        },
+    )?;
+    ops::insert(&image, &mosaic, rect.x, rect.y)
+}
 
-    ops::resize(&img, f64::from(target_width) / f64::from(original_width))
+// the formats without transparency get the translucent pixels blended over the configured
+// background color, rather than over whatever the encoder would pick
+fn flatten(image: &VipsImage, config: &Configuration) -> Result<VipsImage> {
+    if !image.image_hasalpha() {
+        return ops::copy(image);
+    }
+    let background = config
+        .flatten_background
+        .as_ref()
+        .and_then(|color| Color::try_from(color.clone()).ok())
+        .unwrap_or_default();
+    let scale = sample_scale(image)?;
+    let [r, g, b, _] = background.0.map(|channel| f64::from(channel) * scale);
+    let background = if image.get_bands() < 4 {
+        vec![(r + g + b) / 3.0]
+    } else {
+        vec![r, g, b]
+    };
+    let options = ops::FlattenOptions {
+        background,
+        max_alpha: 255.0 * scale,
+    };
+    ops::flatten_with_opts(image, &options)
+}
+
+// what the 8 bits values of the colors and of the alpha are multiplied by in the image, the 16 bits
+// images having them up to 65535
+fn sample_scale(image: &VipsImage) -> Result<f64> {
+    Ok(match image.get_format()? {
+        ops::BandFormat::Ushort => 257.0,
+        _ => 1.0,
+    })
+}
+
+// measured on a small copy, which costs an extra pass over the pipeline
+fn measure_auto_quality(image: &VipsImage) -> Result<i32> {
+    let scale = 256.0 / f64::from(image.get_width().max(frame_height(image)));
+    let small = if scale < 1.0 {
+        ops::resize(image, scale)?
+    } else {
+        ops::copy(image)?
+    };
+    let grey = ops::colourspace(&small, ops::Interpretation::BW)?;
+    let edges = ops::sobel(&ops::extract_band(&grey, 0)?)?;
+    let detail = ops::avg(&edges)?;
+    debug!("the mean gradient of the image is {}", detail);
+    Ok(get_auto_quality(detail))
+}
+
+// the formats whose size is driven by the quality
+fn is_lossy_format(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Jpeg
+            | ImageFormat::Webp
+            | ImageFormat::Heic
+            | ImageFormat::Avif
+            | ImageFormat::Jxl
+    )
+}
+
+fn supports_transparency(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Webp | ImageFormat::Avif
+    )
+}
+
+// centers the image on a canvas of the given size filled with the background color, which gets
+// an alpha band when the background is translucent
+fn extend_image(image: VipsImage, width: i32, height: i32, background: Color) -> Result<VipsImage> {
+    let image = if image.get_bands() < 3 {
+        ops::colourspace(&image, ops::Interpretation::Srgb)?
+    } else {
+        image
+    };
+    let scale = sample_scale(&image)?;
+    let image = if !background.is_opaque() && !image.image_hasalpha() {
+        ops::bandjoin_const(&image, &mut [255.0 * scale])?
+    } else {
+        image
+    };
+    let [r, g, b, alpha] = background.0.map(|channel| f64::from(channel) * scale);
+    let mut background = vec![r, g, b];
+    if image.image_hasalpha() {
+        background.push(alpha);
+    }
+    let options = ops::GravityOptions {
+        extend: ops::Extend::Background,
+        background,
+    };
+    ops::gravity_with_opts(
+        &image,
+        ops::CompassDirection::Centre,
+        width,
+        height,
+        &options,
+    )
 }
@@ -0,0 +1,80 @@
+// (c) Copyright 2019-2024 OLX
+
+// the statistics of the pixels of an image, band by band, from which an all black or a corrupted
+// upload stands out without looking at it
+
+use super::*;
+
+// the columns of the matrix computed by `stats`, whose first row covers all the bands together
+const MIN: i32 = 0;
+const MAX: i32 = 1;
+const MEAN: i32 = 4;
+const STDDEV: i32 = 5;
+
+pub struct BandStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    // the number of pixels of each of the 256 values
+    pub histogram: Vec<u32>,
+}
+
+pub struct ImageStats {
+    pub width: i32,
+    pub height: i32,
+    pub bands: Vec<BandStats>,
+}
+
+pub fn read_image_stats(
+    buffer: &[u8],
+    config: &Configuration,
+    kill_switch: &KillSwitch,
+) -> Result<ImageStats> {
+    let options = pdf_dpi_option(buffer, config).map_or(String::new(), |dpi| format!("[{}]", dpi));
+    let image = load_source(buffer, &options)?;
+    // the deeper images are reduced to 8 bits, so that every histogram has 256 bins
+    let image = match image.get_format()? {
+        ops::BandFormat::Uchar => image,
+        ops::BandFormat::Ushort => ops::cast(
+            &ops::linear(&image, &mut [1.0 / 257.0], &mut [0.0])?,
+            ops::BandFormat::Uchar,
+        )?,
+        _ => ops::cast(&image, ops::BandFormat::Uchar)?,
+    };
+
+    let stats = kill_switch.compute(&image, || ops::stats(&image))?;
+    let stat =
+        |column: i32, band: i32| -> Result<f64> { Ok(ops::getpoint(&stats, column, band + 1)?[0]) };
+    // one u32 per band and per value, the bands of a value next to each other
+    let bands = image.get_bands();
+    let histogram = kill_switch.compute(&image, || {
+        Ok(ops::hist_find(&image)?.image_write_to_memory())
+    })?;
+    let counts: Vec<u32> = histogram
+        .chunks_exact(4)
+        .map(|count| u32::from_ne_bytes([count[0], count[1], count[2], count[3]]))
+        .collect();
+
+    let bands = (0..bands)
+        .map(|band| {
+            Ok(BandStats {
+                min: stat(MIN, band)?,
+                max: stat(MAX, band)?,
+                mean: stat(MEAN, band)?,
+                stddev: stat(STDDEV, band)?,
+                histogram: counts
+                    .iter()
+                    .skip(band as usize)
+                    .step_by(bands as usize)
+                    .copied()
+                    .collect(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ImageStats {
+        width: image.get_width(),
+        height: image.get_height(),
+        bands,
+    })
+}
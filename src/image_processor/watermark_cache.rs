@@ -0,0 +1,161 @@
+// (c) Copyright 2019-2024 OLX
+
+// keeps the watermarks already decoded and resized to their target size, as the same handful of
+// logos are composited over most of the images. the entries expire after a while so that an
+// updated watermark eventually replaces the cached one.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use libvips::ops::BandFormat;
+use libvips::{Result, VipsImage};
+use lru::LruCache;
+
+const MAX_ENTRIES: usize = 64;
+
+lazy_static! {
+    static ref CACHE: WatermarkCache = WatermarkCache::new(MAX_ENTRIES);
+}
+
+// the uncompressed pixels of a watermark at its target size, which libvips reads in place
+pub struct PreparedWatermark {
+    pixels: Vec<u8>,
+    width: i32,
+    height: i32,
+    bands: i32,
+    format: BandFormat,
+}
+
+impl PreparedWatermark {
+    pub fn new(image: &VipsImage) -> Result<Self> {
+        Ok(Self {
+            pixels: image.image_write_to_memory(),
+            width: image.get_width(),
+            height: image.get_height(),
+            bands: image.get_bands(),
+            format: image.get_format()?,
+        })
+    }
+
+    // the returned image borrows the pixels, so the watermark has to outlive the encoding
+    pub fn to_image(&self) -> Result<VipsImage> {
+        VipsImage::new_from_memory(
+            &self.pixels,
+            self.width,
+            self.height,
+            self.bands,
+            self.format,
+        )
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct WatermarkKey {
+    pub address: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+struct WatermarkCache {
+    entries: Mutex<LruCache<WatermarkKey, (Instant, Arc<PreparedWatermark>)>>,
+}
+
+impl WatermarkCache {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(max_entries).unwrap())),
+        }
+    }
+
+    fn get(
+        &self,
+        key: &WatermarkKey,
+        ttl: Duration,
+        now: Instant,
+    ) -> Option<Arc<PreparedWatermark>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((inserted, watermark)) if now.duration_since(*inserted) < ttl => {
+                Some(watermark.clone())
+            }
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: WatermarkKey, watermark: Arc<PreparedWatermark>, now: Instant) {
+        self.entries.lock().unwrap().put(key, (now, watermark));
+    }
+}
+
+// returns the cached watermark, preparing and caching it when it's missing or expired. nothing is
+// cached when the ttl is zero.
+pub fn get_or_prepare(
+    key: WatermarkKey,
+    ttl: Duration,
+    prepare: impl FnOnce() -> Result<PreparedWatermark>,
+) -> Result<Arc<PreparedWatermark>> {
+    // the uploaded watermarks don't have an address to be cached by
+    let cacheable = !ttl.is_zero() && !key.address.is_empty();
+    let now = Instant::now();
+    if let Some(watermark) = CACHE.get(&key, ttl, now).filter(|_| cacheable) {
+        return Ok(watermark);
+    }
+    let watermark = Arc::new(prepare()?);
+    if cacheable {
+        CACHE.insert(key, watermark.clone(), now);
+    }
+    Ok(watermark)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(address: &str, width: i32) -> WatermarkKey {
+        WatermarkKey {
+            address: address.to_string(),
+            width,
+            height: width,
+        }
+    }
+
+    fn watermark() -> Arc<PreparedWatermark> {
+        Arc::new(PreparedWatermark {
+            pixels: vec![0; 4],
+            width: 1,
+            height: 1,
+            bands: 4,
+            format: BandFormat::Uchar,
+        })
+    }
+
+    #[test]
+    fn test_entries_expire_after_the_ttl() {
+        let cache = WatermarkCache::new(2);
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        cache.insert(key("logo.png", 100), watermark(), now);
+
+        assert!(cache.get(&key("logo.png", 100), ttl, now).is_some());
+        // the same watermark at another size is a different entry
+        assert!(cache.get(&key("logo.png", 50), ttl, now).is_none());
+        assert!(cache
+            .get(&key("logo.png", 100), ttl, now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.get(&key("logo.png", 100), ttl, now).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+// (c) Copyright 2019-2024 OLX
+
+// tracks the consecutive failures of every origin host. once a host fails `failure_threshold`
+// times in a row its circuit opens and the downloads from it fail right away for `open_duration`,
+// instead of piling up timeouts and holding on to the pooled connections. afterwards the requests
+// go through again and a single failure is enough to open the circuit once more, until a download
+// succeeds and resets it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::*;
+
+use crate::commons::clock::Clock;
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+use crate::routes::image::ImageProcessingError::{
+    ImageDownloadFailed, ImageDownloadTimedOut, OriginUnavailable,
+};
+
+const DEFAULT_OPEN_SECS: u64 = 30;
+
+#[derive(Default)]
+struct HostCircuit {
+    consecutive_failures: u32,
+    open_until: Option<SystemTime>,
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    hosts: Mutex<HashMap<String, HostCircuit>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &Configuration, clock: Arc<dyn Clock>) -> Option<CircuitBreaker> {
+        let failure_threshold = config.circuit_breaker_failure_threshold?;
+        Some(Self {
+            failure_threshold: failure_threshold.max(1),
+            open_duration: Duration::from_secs(
+                config
+                    .circuit_breaker_open_secs
+                    .unwrap_or(DEFAULT_OPEN_SECS),
+            ),
+            hosts: Mutex::new(HashMap::new()),
+            clock,
+        })
+    }
+
+    pub fn check(&self, host: &str) -> Result<(), ImageProcessingError> {
+        let hosts = self.hosts.lock().unwrap();
+        match hosts.get(host).and_then(|circuit| circuit.open_until) {
+            Some(open_until) if self.clock.now() < open_until => {
+                debug!(
+                    "the circuit of the origin '{}' is open, not downloading from it",
+                    host
+                );
+                Err(OriginUnavailable(String::from(host)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // only the failures caused by the origin itself count, a 404 says nothing about its health
+    pub fn record<T>(&self, host: &str, result: &Result<T, ImageProcessingError>) {
+        let mut hosts = self.hosts.lock().unwrap();
+        match result {
+            Err(ImageDownloadTimedOut | ImageDownloadFailed) => {
+                let circuit = hosts.entry(String::from(host)).or_default();
+                circuit.consecutive_failures = circuit.consecutive_failures.saturating_add(1);
+                if circuit.consecutive_failures >= self.failure_threshold {
+                    if circuit.consecutive_failures == self.failure_threshold {
+                        warn!(
+                            "the origin '{}' failed {} times in a row, opening its circuit for {:?}",
+                            host, circuit.consecutive_failures, self.open_duration
+                        );
+                    }
+                    circuit.open_until = Some(self.clock.now() + self.open_duration);
+                }
+            }
+            _ => {
+                hosts.remove(host);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::clock::FixedClock;
+    use std::time::UNIX_EPOCH;
+
+    fn create_breaker(now_secs: u64) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: 2,
+            open_duration: Duration::from_secs(30),
+            hosts: Mutex::new(HashMap::new()),
+            clock: Arc::new(FixedClock(UNIX_EPOCH + Duration::from_secs(now_secs))),
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        let breaker = create_breaker(100);
+        let failure: Result<(), _> = Err(ImageDownloadTimedOut);
+        breaker.record("origin", &failure);
+        assert!(breaker.check("origin").is_ok());
+        breaker.record(
+            "origin",
+            &Err::<(), _>(ImageProcessingError::ClientReturnedErrorStatusCode(
+                404,
+                String::from("image.jpg"),
+            )),
+        );
+        breaker.record("origin", &failure);
+        assert!(breaker.check("origin").is_ok());
+        breaker.record("origin", &failure);
+        assert!(matches!(
+            breaker.check("origin"),
+            Err(OriginUnavailable(host)) if host == "origin"
+        ));
+        assert!(breaker.check("other").is_ok());
+
+        // once the circuit is open for long enough, the requests go through again
+        let hosts = breaker.hosts.into_inner().unwrap();
+        let later = CircuitBreaker {
+            hosts: Mutex::new(hosts),
+            ..create_breaker(131)
+        };
+        assert!(later.check("origin").is_ok());
+        later.record("origin", &Ok(()));
+        assert!(later.hosts.lock().unwrap().is_empty());
+    }
+}
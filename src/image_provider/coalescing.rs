@@ -0,0 +1,130 @@
+// (c) Copyright 2019-2024 OLX
+
+// makes the concurrent requests for the same resource share a single fetch from the inner
+// provider, so that a burst of requests for an image which isn't mirrored or cached yet doesn't
+// download it once per request. the fetch is driven by whichever of the waiting requests is
+// polled, so it carries on even if the request which started it goes away.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+use crate::routes::image::ImageProcessingError;
+use crate::routes::image::ImageProcessingError::*;
+
+use super::{ImageBuffer, ImageProvider};
+
+type SharedFetch = Shared<BoxFuture<'static, Result<Bytes, Arc<ImageProcessingError>>>>;
+
+pub struct CoalescingProvider {
+    inner: Arc<dyn ImageProvider>,
+    in_flight: Arc<Mutex<HashMap<String, SharedFetch>>>,
+}
+
+impl CoalescingProvider {
+    pub fn new(inner: Box<dyn ImageProvider>) -> CoalescingProvider {
+        Self {
+            inner: Arc::from(inner),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn fetch(&self, resource: &str) -> SharedFetch {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(fetch) = in_flight.get(resource) {
+            return fetch.clone();
+        }
+        let inner = self.inner.clone();
+        let registry = self.in_flight.clone();
+        let key = resource.to_string();
+        let fetch = async move {
+            let result = inner
+                .get_file(&key)
+                .await
+                .map(ImageBuffer::into_bytes)
+                .map_err(Arc::new);
+            registry.lock().unwrap().remove(&key);
+            result
+        }
+        .boxed()
+        .shared();
+        in_flight.insert(resource.to_string(), fetch.clone());
+        fetch
+    }
+}
+
+// every waiting request gets its own copy of the error of the shared fetch
+fn duplicate(error: &ImageProcessingError) -> ImageProcessingError {
+    match error {
+        InvalidResourceUriProvided(resource) => InvalidResourceUriProvided(resource.clone()),
+        ImageDownloadTimedOut => ImageDownloadTimedOut,
+        ClientReturnedErrorStatusCode(status, resource) => {
+            ClientReturnedErrorStatusCode(*status, resource.clone())
+        }
+        SourceTooLarge(max_size) => SourceTooLarge(*max_size),
+        UnsupportedMediaType(media_type) => UnsupportedMediaType(media_type.clone()),
+        ForbiddenOrigin(host) => ForbiddenOrigin(host.clone()),
+        OriginUnavailable(host) => OriginUnavailable(host.clone()),
+        PathOutsideRoot(resource) => PathOutsideRoot(resource.clone()),
+        Purged(resource) => Purged(resource.clone()),
+        _ => ImageDownloadFailed,
+    }
+}
+
+#[async_trait]
+impl ImageProvider for CoalescingProvider {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        self.fetch(resource)
+            .await
+            .map(ImageBuffer::Shared)
+            .map_err(|e| duplicate(&e))
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        self.inner.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    struct CountingProvider {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ImageProvider for CountingProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(ImageBuffer::from(resource.as_bytes().to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_are_coalesced() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let provider = CoalescingProvider::new(Box::new(CountingProvider {
+            fetches: fetches.clone(),
+        }));
+        let (a, b, c) = tokio::join!(
+            provider.get_file("a.jpg"),
+            provider.get_file("a.jpg"),
+            provider.get_file("b.jpg")
+        );
+        assert_eq!(&*a.unwrap(), b"a.jpg");
+        assert_eq!(&*b.unwrap(), b"a.jpg");
+        assert_eq!(&*c.unwrap(), b"b.jpg");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // once a fetch completes, the next request for the resource fetches it again
+        provider.get_file("a.jpg").await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+}
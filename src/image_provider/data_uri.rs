@@ -0,0 +1,135 @@
+// (c) Copyright 2019-2024 OLX
+
+// serves the images sent inline as `data:image/...;base64,...` URIs, so that small images like
+// avatars can be processed without being hosted anywhere. every other address is handed to the
+// inner provider. the inline images bypass the caches, as they'd only be keyed by themselves.
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::*;
+
+use crate::routes::image::ImageProcessingError;
+use crate::routes::image::ImageProcessingError::{
+    InvalidResourceUriProvided, SourceTooLarge, UnsupportedMediaType,
+};
+
+use super::{is_supported_media_type, ImageBuffer, ImageProvider, DEFAULT_MAX_SOURCE_SIZE_BYTES};
+
+const DATA_URI_SCHEME: &str = "data:";
+
+pub struct DataUriProvider {
+    inner: Box<dyn ImageProvider>,
+    max_source_size_bytes: Option<u64>,
+}
+
+impl DataUriProvider {
+    pub fn new(inner: Box<dyn ImageProvider>, max_source_size_bytes: Option<u64>) -> Self {
+        Self {
+            inner,
+            max_source_size_bytes,
+        }
+    }
+}
+
+pub fn decode_data_uri(
+    uri: &str,
+    max_source_size_bytes: Option<u64>,
+) -> Result<Vec<u8>, ImageProcessingError> {
+    let invalid = || InvalidResourceUriProvided(String::from("data:..."));
+    let (metadata, data) = uri
+        .strip_prefix(DATA_URI_SCHEME)
+        .and_then(|uri| uri.split_once(','))
+        .ok_or_else(invalid)?;
+    let Some(media_type) = metadata.strip_suffix(";base64") else {
+        error!("only the base64 encoded data URIs are supported");
+        return Err(invalid());
+    };
+    // the parameters of the media type, e.g. `;charset=...`, don't matter for images
+    let media_type = media_type.split(';').next().unwrap_or_default();
+    if !is_supported_media_type(media_type) {
+        error!(
+            "the data URI has the media type '{}' which is not supported",
+            media_type
+        );
+        return Err(UnsupportedMediaType(media_type.to_string()));
+    }
+
+    // the decoded size is known upfront (give or take the padding), so an oversized image isn't
+    // even decoded
+    let max_size = max_source_size_bytes.unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+    if (data.len() as u64 / 4 * 3).saturating_sub(2) > max_size {
+        error!("the data URI image exceeds the limit of {} bytes", max_size);
+        return Err(SourceTooLarge(max_size));
+    }
+    // a `+` which wasn't percent encoded in the query string arrives as a space
+    let data: String = data
+        .chars()
+        .filter(|c| *c != '\n' && *c != '\r')
+        .map(|c| if c == ' ' { '+' } else { c })
+        .collect();
+    let decoded = STANDARD.decode(data).map_err(|e| {
+        error!("failed to decode the data URI image. error: {}", e);
+        invalid()
+    })?;
+    if decoded.len() as u64 > max_size {
+        return Err(SourceTooLarge(max_size));
+    }
+    Ok(decoded)
+}
+
+#[async_trait]
+impl ImageProvider for DataUriProvider {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        if resource.starts_with(DATA_URI_SCHEME) {
+            return decode_data_uri(resource, self.max_source_size_bytes).map(ImageBuffer::from);
+        }
+        self.inner.get_file(resource).await
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        self.inner.ready().await
+    }
+
+    async fn evict(&self, resource: &str) {
+        self.inner.evict(resource).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_data_uri() {
+        assert_eq!(
+            decode_data_uri("data:image/png;base64,iVBORw0K", None).unwrap(),
+            b"\x89PNG\r\n"
+        );
+        // a `+` turned into a space by the query string decoding
+        assert_eq!(
+            decode_data_uri("data:image/gif;base64,R0lG a b", None).unwrap(),
+            STANDARD.decode("R0lG+a+b").unwrap()
+        );
+        assert_eq!(
+            decode_data_uri("data:application/pdf;base64,JVBERi0=", None).unwrap(),
+            b"%PDF-"
+        );
+        assert_eq!(
+            decode_data_uri("data:video/mp4;base64,AAAAIGZ0eXA=", None).is_ok(),
+            cfg!(feature = "video")
+        );
+        assert!(matches!(
+            decode_data_uri("data:text/html;base64,PGh0bWw+", None),
+            Err(UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            decode_data_uri("data:image/svg+xml,<svg/>", None),
+            Err(InvalidResourceUriProvided(_))
+        ));
+        assert!(matches!(
+            decode_data_uri("data:image/png;base64,iVBORw0KGgoAAAANSUhEUg==", Some(8)),
+            Err(SourceTooLarge(8))
+        ));
+    }
+}
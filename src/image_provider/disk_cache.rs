@@ -0,0 +1,241 @@
+// (c) Copyright 2019-2024 OLX
+
+// bounds the size and the age of the images mirrored by the FileImageProvider. the mirrored files
+// are recorded in an index next to them, as the public path also holds originals that were never
+// downloaded and must not be evicted. a background task periodically drops the expired files and
+// then the least recently used ones (by access time, or modification time when the filesystem
+// doesn't track accesses) until the mirror fits within its maximum size.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::commons::clock::Clock;
+use crate::commons::config::Configuration;
+use crate::image_provider::validators::validators_path;
+
+const INDEX_FILE_NAME: &str = ".dali-mirror-index";
+const DEFAULT_EVICTION_INTERVAL_SECS: u64 = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+pub struct DiskCache {
+    index_path: PathBuf,
+    // serializes the appends to the index with its rewrite after an eviction
+    index_lock: Mutex<()>,
+    max_bytes: Option<u64>,
+    ttl: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl DiskCache {
+    pub fn new(config: &Configuration, clock: Arc<dyn Clock>) -> Option<DiskCache> {
+        if config.disk_cache_max_bytes.is_none() && config.disk_cache_ttl_secs.is_none() {
+            return None;
+        }
+        Some(Self {
+            index_path: Path::new(&config.public_img_path).join(INDEX_FILE_NAME),
+            index_lock: Mutex::new(()),
+            max_bytes: config.disk_cache_max_bytes,
+            ttl: config.disk_cache_ttl_secs.map(Duration::from_secs),
+            clock,
+        })
+    }
+
+    pub fn spawn_eviction(self: Arc<Self>, config: &Configuration) {
+        let interval = Duration::from_secs(
+            config
+                .disk_cache_eviction_interval_secs
+                .unwrap_or(DEFAULT_EVICTION_INTERVAL_SECS),
+        );
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.evict().await {
+                    error!("failed to evict the mirrored images. error: {}", e);
+                }
+            }
+        });
+    }
+
+    pub async fn record(&self, path: &str) {
+        let _guard = self.index_lock.lock().await;
+        let appended = async {
+            let mut index = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.index_path)
+                .await?;
+            index.write_all(format!("{}\n", path).as_bytes()).await
+        };
+        if let Err(e) = appended.await {
+            error!(
+                "failed to record the mirrored image '{}' in the index. error: {}",
+                path, e
+            );
+        }
+    }
+
+    async fn evict(&self) -> io::Result<()> {
+        let mut entries = vec![];
+        for path in self.read_index().await? {
+            // the files removed by someone else are simply dropped from the index
+            if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                let last_used = metadata.accessed().or_else(|_| metadata.modified())?;
+                entries.push(CacheEntry {
+                    path,
+                    size: metadata.len(),
+                    last_used,
+                });
+            }
+        }
+
+        let evicted = select_evictions(&entries, self.clock.now(), self.ttl, self.max_bytes);
+        let mut evicted_bytes = 0;
+        for entry in &evicted {
+            match tokio::fs::remove_file(&entry.path).await {
+                Ok(_) => {
+                    evicted_bytes += entry.size;
+                    let _ = tokio::fs::remove_file(validators_path(&entry.path)).await;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!(
+                    "failed to evict the mirrored image '{}'. error: {}",
+                    entry.path.display(),
+                    e
+                ),
+            }
+        }
+        if !evicted.is_empty() {
+            info!(
+                "evicted {} mirrored images ({} bytes)",
+                evicted.len(),
+                evicted_bytes
+            );
+        }
+
+        // the images mirrored while the eviction was running are kept in the index
+        let _guard = self.index_lock.lock().await;
+        let evicted: HashSet<&Path> = evicted.iter().map(|entry| entry.path.as_path()).collect();
+        let missing: HashSet<PathBuf> = self
+            .read_index()
+            .await?
+            .into_iter()
+            .filter(|path| !entries.iter().any(|entry| &entry.path == path))
+            .collect();
+        let mut remaining: Vec<String> = entries
+            .iter()
+            .filter(|entry| !evicted.contains(entry.path.as_path()))
+            .map(|entry| entry.path.display().to_string())
+            .collect();
+        remaining.extend(missing.iter().map(|path| path.display().to_string()));
+        remaining.push(String::new());
+        tokio::fs::write(&self.index_path, remaining.join("\n")).await
+    }
+
+    async fn read_index(&self) -> io::Result<Vec<PathBuf>> {
+        let index = match tokio::fs::read_to_string(&self.index_path).await {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut seen = HashSet::new();
+        Ok(index
+            .lines()
+            .filter(|line| !line.is_empty() && seen.insert(*line))
+            .map(PathBuf::from)
+            .collect())
+    }
+}
+
+// the expired entries, followed by the least recently used ones needed to fit within max_bytes
+pub fn select_evictions(
+    entries: &[CacheEntry],
+    now: SystemTime,
+    ttl: Option<Duration>,
+    max_bytes: Option<u64>,
+) -> Vec<CacheEntry> {
+    let is_expired = |entry: &CacheEntry| {
+        ttl.is_some_and(|ttl| {
+            now.duration_since(entry.last_used)
+                .is_ok_and(|age| age > ttl)
+        })
+    };
+    let (mut evicted, mut kept): (Vec<CacheEntry>, Vec<CacheEntry>) =
+        entries.iter().cloned().partition(is_expired);
+
+    if let Some(max_bytes) = max_bytes {
+        kept.sort_by_key(|entry| entry.last_used);
+        let mut total: u64 = kept.iter().map(|entry| entry.size).sum();
+        let mut kept = kept.into_iter();
+        while total > max_bytes {
+            match kept.next() {
+                Some(entry) => {
+                    total -= entry.size;
+                    evicted.push(entry);
+                }
+                None => break,
+            }
+        }
+    }
+    evicted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry(name: &str, size: u64, last_used_secs: u64) -> CacheEntry {
+        CacheEntry {
+            path: PathBuf::from(name),
+            size,
+            last_used: UNIX_EPOCH + Duration::from_secs(last_used_secs),
+        }
+    }
+
+    fn names(entries: Vec<CacheEntry>) -> Vec<PathBuf> {
+        entries.into_iter().map(|entry| entry.path).collect()
+    }
+
+    #[test]
+    fn test_select_evictions() {
+        let entries = vec![
+            entry("old", 10, 100),
+            entry("recent", 50, 900),
+            entry("middle", 50, 500),
+            entry("newest", 50, 950),
+        ];
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let ttl = Some(Duration::from_secs(600));
+        assert_eq!(
+            names(select_evictions(&entries, now, ttl, None)),
+            vec![PathBuf::from("old")]
+        );
+        assert_eq!(
+            names(select_evictions(&entries, now, None, Some(100))),
+            vec![PathBuf::from("old"), PathBuf::from("middle")]
+        );
+        assert_eq!(
+            names(select_evictions(&entries, now, ttl, Some(60))),
+            vec![
+                PathBuf::from("old"),
+                PathBuf::from("middle"),
+                PathBuf::from("recent")
+            ]
+        );
+        assert!(select_evictions(&entries, now, None, None).is_empty());
+    }
+}
@@ -2,22 +2,49 @@ pub mod file {
 
     use std::fs;
     use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
 
+    use crate::commons::clock::{Clock, SystemClock};
     use crate::commons::config::Configuration;
+    use crate::commons::filesystem::FileSystem;
+    use crate::image_provider::circuit_breaker::CircuitBreaker;
+    use crate::image_provider::disk_cache::DiskCache;
+    use crate::image_provider::hot_sources::HotSources;
+    use crate::image_provider::origin::Origins;
+    use crate::image_provider::origin_guard::{self, OriginGuard};
+    use crate::image_provider::origin_headers::OriginHeaders;
+    use crate::image_provider::rate_limit::OriginRateLimiter;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    use crate::image_provider::uring;
+    use crate::image_provider::validators::{needs_revalidation, Validators};
     use crate::image_provider::ImageProcessingError::{
-        ClientReturnedErrorStatusCode, ImageDownloadFailed, ImageDownloadTimedOut,
-        InvalidResourceUriProvided,
+        ClientReturnedErrorStatusCode, ForbiddenOrigin, ImageDownloadFailed, ImageDownloadTimedOut,
+        InvalidResourceUriProvided, OriginUnavailable, SourceTooLarge, UnsupportedMediaType,
     };
-    use crate::image_provider::ImageProvider;
-    use crate::routes::image::ImageProcessingError;
+    use crate::image_provider::{
+        http_client_builder, is_supported_media_type, probe_origins, read_body, ImageBuffer,
+        ImageProvider, DEFAULT_MAX_SOURCE_SIZE_BYTES,
+    };
+    use crate::routes::image::{local_image_path, ImageProcessingError};
     use async_trait::async_trait;
+    use axum::body::Bytes;
 
     use log::*;
-    use reqwest::{Client, Url};
+    use memmap2::Mmap;
+    use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+    use reqwest::{Client, Method, StatusCode, Url};
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
     use tokio::fs::File;
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
     use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 
+    const DEFAULT_MMAP_THRESHOLD_BYTES: u64 = 16 * 1024 * 1024;
+
+    // tells apart the partial files of the downloads of the same image running concurrently
+    static PARTIAL_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
     pub fn create_path_for_file(filepath: &str) {
         // 将路径转换为 Path 对象
         let path = Path::new(filepath);
@@ -38,7 +65,39 @@ pub mod file {
         }
     }
 
-    async fn read_file(path: &str) -> Result<Vec<u8>, ImageProcessingError> {
+    async fn read_file(
+        path: &str,
+        mmap_threshold: u64,
+    ) -> Result<ImageBuffer, ImageProcessingError> {
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if size >= mmap_threshold {
+            debug!("mapping the file '{}' ({} bytes) into memory", path, size);
+            map_file(path)
+        } else {
+            read_whole_file(path).await.map(ImageBuffer::from)
+        }
+    }
+
+    fn map_file(path: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        let file = fs::File::open(path).map_err(|e| {
+            error!("failed to open the file '{}'. error: {}", path, e);
+            ImageDownloadFailed
+        })?;
+        // the files within the public path are never modified in place, a revalidated copy
+        // replaces the previous one with a rename, which is what keeps the mapping valid while
+        // it's in use
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| {
+            error!(
+                "failed to map the file '{}' into memory. error: {}",
+                path, e
+            );
+            ImageDownloadFailed
+        })?;
+        Ok(ImageBuffer::Mapped(mmap))
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    async fn read_whole_file(path: &str) -> Result<Vec<u8>, ImageProcessingError> {
         // 异步打开文件
         let mut file = File::open(path).await.unwrap();
 
@@ -51,38 +110,471 @@ pub mod file {
         Ok(buffer)
     }
 
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    async fn read_whole_file(path: &str) -> Result<Vec<u8>, ImageProcessingError> {
+        uring::read_file(Path::new(path)).await.map_err(|e| {
+            error!(
+                "failed to read the file '{}' with io_uring. error: {}",
+                path, e
+            );
+            ImageDownloadFailed
+        })
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    async fn write_file(path: &str, bytes: Bytes) {
+        let file = File::create(path).await.unwrap();
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes).await.unwrap();
+        writer.flush().await.unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    async fn write_file(path: &str, bytes: Bytes) {
+        if let Err(e) = uring::write_file(Path::new(path), bytes).await {
+            error!(
+                "failed to write the file '{}' with io_uring. error: {}",
+                path, e
+            );
+        }
+    }
+
+    // the outcome of a (conditional) download from an origin, along with the size the preflight
+    // announced for it
+    enum Fetched {
+        Modified(Bytes, Validators, Option<u64>),
+        NotModified,
+    }
+
     pub struct FileImageProvider {
         pub public_img_path: String,
         pub client: Client,
+        pub mmap_threshold: u64,
+        pub preflight_hosts: Vec<String>,
+        pub max_source_size_bytes: Option<u64>,
+        pub filesystem: Arc<dyn FileSystem>,
+        pub origins: Origins,
+        pub origin_guard: OriginGuard,
+        pub origin_headers: OriginHeaders,
+        pub disk_cache: Option<Arc<DiskCache>>,
+        pub circuit_breaker: Option<CircuitBreaker>,
+        pub rate_limiter: Option<OriginRateLimiter>,
+        pub revalidate_after: Option<Duration>,
+        pub hot_sources: Option<HotSources>,
+        pub clock: Arc<dyn Clock>,
     }
 
     impl FileImageProvider {
-        pub async fn new(config: &Configuration) -> FileImageProvider {
-            let reqwest_client = Client::builder()
-                .timeout(Duration::from_millis(u64::from(
-                    config.reqwest_timeout_millis.unwrap_or(2000),
-                )))
-                .connect_timeout(Duration::from_millis(u64::from(
-                    config.reqwest_connection_timeout_millis.unwrap_or(2000),
-                )))
-                .pool_max_idle_per_host(usize::from(
-                    config.reqwest_pool_max_idle_per_host.unwrap_or(10),
-                ))
-                .pool_idle_timeout(Duration::from_millis(u64::from(
-                    config.reqwest_pool_idle_timeout_millis.unwrap_or(60000),
-                )))
-                .build()
-                .unwrap();
+        pub async fn new(
+            config: &Configuration,
+            filesystem: Arc<dyn FileSystem>,
+        ) -> FileImageProvider {
+            let disk_cache = DiskCache::new(config, Arc::new(SystemClock)).map(Arc::new);
+            if let Some(disk_cache) = &disk_cache {
+                disk_cache.clone().spawn_eviction(config);
+            }
+            let origin_guard = OriginGuard::new(config);
             Self {
                 public_img_path: config.public_img_path.clone(),
-                client: reqwest_client,
+                client: http_client_builder(config)
+                    .redirect(origin_guard.redirect_policy())
+                    .dns_resolver(origin_guard.resolver())
+                    .build()
+                    .unwrap(),
+                mmap_threshold: config
+                    .mmap_threshold_bytes
+                    .unwrap_or(DEFAULT_MMAP_THRESHOLD_BYTES),
+                preflight_hosts: config.preflight_hosts.clone().unwrap_or_default(),
+                max_source_size_bytes: config.max_source_size_bytes,
+                filesystem,
+                origin_guard,
+                origin_headers: OriginHeaders::new(config),
+                origins: Origins::new(
+                    config.origin_base_urls.clone().unwrap_or_default(),
+                    config.origin_weights.clone(),
+                ),
+                disk_cache,
+                circuit_breaker: CircuitBreaker::new(config, Arc::new(SystemClock)),
+                rate_limiter: OriginRateLimiter::new(config),
+                revalidate_after: config.mirror_revalidate_after_secs.map(Duration::from_secs),
+                hot_sources: HotSources::new(config),
+                clock: Arc::new(SystemClock),
+            }
+        }
+
+        // revalidates the most requested mirrored images which are about to go stale
+        pub fn spawn_refresh(self: Arc<Self>) {
+            let Some(hot_sources) = &self.hot_sources else {
+                return;
+            };
+            let interval = hot_sources.interval;
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    self.refresh_hot_sources().await;
+                }
+            });
+        }
+
+        async fn refresh_hot_sources(&self) {
+            let (Some(hot_sources), Some(revalidate_after)) =
+                (&self.hot_sources, self.revalidate_after)
+            else {
+                return;
+            };
+            for resource in hot_sources.take_hottest() {
+                let Ok(filepath) = local_image_path(&self.public_img_path, &resource) else {
+                    continue;
+                };
+                let about_to_expire = needs_revalidation(
+                    self.filesystem.as_ref(),
+                    self.clock.as_ref(),
+                    &filepath,
+                    revalidate_after.saturating_sub(hot_sources.ahead),
+                )
+                .await;
+                if !about_to_expire {
+                    continue;
+                }
+                debug!("refreshing the frequently requested image '{}'", resource);
+                let filepathstr = filepath.to_string_lossy();
+                let refreshed = match self.origin_urls(&resource).await {
+                    Ok(urls) => self.revalidate(&urls, &resource, &filepathstr).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = refreshed {
+                    warn!(
+                        "failed to refresh the frequently requested image '{}'. error: {}",
+                        resource, e
+                    );
+                }
+            }
+        }
+
+        // the urls the resource is downloaded from, in failover order
+        async fn origin_urls(&self, resource: &str) -> Result<Vec<String>, ImageProcessingError> {
+            if !resource.starts_with("http://") && !resource.starts_with("https://") {
+                return Ok(self.origins.candidates(resource));
+            }
+            if let Some(path) = self.origins.relative_path(resource) {
+                return Ok(self.origins.candidates(path));
+            }
+            let url = Url::parse(resource).map_err(|_| {
+                error!(
+                    "the provided resource uri is not a valid http url: '{}'",
+                    resource
+                );
+                InvalidResourceUriProvided(String::from(resource))
+            })?;
+            self.origin_guard.check(&url).await?;
+            Ok(vec![String::from(resource)])
+        }
+
+        fn record_hit(&self, resource: &str) {
+            if let Some(hot_sources) = &self.hot_sources {
+                hot_sources.record_hit(resource);
+            }
+        }
+
+        fn needs_preflight(&self, url: &Url) -> bool {
+            url.host_str().is_some_and(|host| {
+                self.preflight_hosts
+                    .iter()
+                    .any(|preflight_host| preflight_host == "*" || preflight_host == host)
+            })
+        }
+
+        // issues a HEAD request to the origin in order to reject the sources which are too big or
+        // aren't images before committing to the full download, and returns their size when it's
+        // announced. a failing HEAD request doesn't block the download as not every origin
+        // supports it.
+        async fn preflight(
+            &self,
+            url: &Url,
+            resource: &str,
+        ) -> Result<Option<u64>, ImageProcessingError> {
+            let response = match self
+                .origin_headers
+                .request(&self.client, Method::HEAD, url)
+                .await
+                .send()
+                .await
+            {
+                Ok(response) if response.status().is_success() => response,
+                Ok(response) => {
+                    warn!(
+                        "the preflight request for the image '{}' returned the status code {}",
+                        resource,
+                        response.status()
+                    );
+                    return Ok(None);
+                }
+                Err(e) => {
+                    warn!(
+                        "the preflight request for the image '{}' has failed. error: {}",
+                        resource, e
+                    );
+                    return Ok(None);
+                }
+            };
+
+            let content_length = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            let max_size = self
+                .max_source_size_bytes
+                .unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+            if let Some(length) = content_length {
+                if length > max_size {
+                    error!(
+                        "the image '{}' has {} bytes which exceeds the limit of {} bytes",
+                        resource, length, max_size
+                    );
+                    return Err(SourceTooLarge(max_size));
+                }
+            }
+
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            if let Some(content_type) = content_type {
+                if !is_supported_media_type(content_type)
+                    && !content_type.starts_with("application/octet-stream")
+                {
+                    error!(
+                        "the image '{}' has the content type '{}' which is not supported",
+                        resource, content_type
+                    );
+                    return Err(UnsupportedMediaType(content_type.to_string()));
+                }
+            }
+            Ok(content_length)
+        }
+
+        async fn is_fresh(&self, filepathstr: &str) -> bool {
+            match self.revalidate_after {
+                Some(revalidate_after) => {
+                    !needs_revalidation(
+                        self.filesystem.as_ref(),
+                        self.clock.as_ref(),
+                        Path::new(filepathstr),
+                        revalidate_after,
+                    )
+                    .await
+                }
+                None => true,
+            }
+        }
+
+        // the mirrored copy is still served when the origin can't be reached, only an origin
+        // answering that the image is gone (4xx) takes precedence over it
+        async fn revalidate(
+            &self,
+            urls: &[String],
+            resource: &str,
+            filepathstr: &str,
+        ) -> Result<ImageBuffer, ImageProcessingError> {
+            let validators = Validators::load(Path::new(filepathstr)).await;
+            match self
+                .download_with_failover(urls, resource, filepathstr, validators.as_ref())
+                .await
+            {
+                Err(e @ ClientReturnedErrorStatusCode(..)) => Err(e),
+                Err(e) => {
+                    warn!(
+                        "failed to revalidate the mirrored image '{}', serving it as it is. error: {}",
+                        resource, e
+                    );
+                    read_file(filepathstr, self.mmap_threshold).await
+                }
+                result => result,
+            }
+        }
+
+        // whether the mirrored copy was written, its validators and its index entry being optional
+        async fn store(&self, filepathstr: &str, bytes: Bytes, validators: Validators) -> bool {
+            create_path_for_file(filepathstr);
+            // the copy is written aside and renamed, so that the readers which mapped the
+            // previous one keep on seeing it whole
+            let partial = format!(
+                "{}.{}.part",
+                filepathstr,
+                PARTIAL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            write_file(&partial, bytes).await;
+            if let Err(e) = tokio::fs::rename(&partial, filepathstr).await {
+                error!(
+                    "failed to move the mirrored image to '{}'. error: {}",
+                    filepathstr, e
+                );
+                return false;
+            }
+            if self.revalidate_after.is_some() {
+                if let Err(e) = validators.store(Path::new(filepathstr)).await {
+                    error!(
+                        "failed to store the validators of the mirrored image '{}'. error: {}",
+                        filepathstr, e
+                    );
+                }
+            }
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.record(filepathstr).await;
+            }
+            true
+        }
+
+        // tries the urls in order, falling back to the next one only when the download failed
+        // because of the origin itself (timeouts, connection errors, 5xx responses or an open
+        // circuit)
+        async fn download_with_failover(
+            &self,
+            urls: &[String],
+            resource: &str,
+            filepathstr: &str,
+            validators: Option<&Validators>,
+        ) -> Result<ImageBuffer, ImageProcessingError> {
+            let mut last_error = ImageDownloadFailed;
+            for (i, url) in urls.iter().enumerate() {
+                match self.download(url, resource, validators).await {
+                    Ok(Fetched::Modified(bytes, validators, announced_size)) => {
+                        let stored = self.store(filepathstr, bytes.clone(), validators).await;
+                        // the sources announced as large by the preflight are served from their
+                        // mapped copy, which releases the downloaded body before the processing
+                        let large = announced_size.is_some_and(|size| size >= self.mmap_threshold);
+                        if stored && large {
+                            debug!("mapping the downloaded image '{}' into memory", resource);
+                            return map_file(filepathstr);
+                        }
+                        return Ok(ImageBuffer::Shared(bytes));
+                    }
+                    Ok(Fetched::NotModified) => {
+                        if let Some(validators) = validators {
+                            if let Err(e) = validators.store(Path::new(filepathstr)).await {
+                                error!(
+                                    "failed to store the validators of the mirrored image '{}'. error: {}",
+                                    filepathstr, e
+                                );
+                            }
+                        }
+                        return read_file(filepathstr, self.mmap_threshold).await;
+                    }
+                    Err(
+                        e @ (ImageDownloadTimedOut | ImageDownloadFailed | OriginUnavailable(_)),
+                    ) => {
+                        if i + 1 < urls.len() {
+                            warn!(
+                                "failed to download the image '{}' from '{}', falling back to the next origin. error: {}",
+                                resource, url, e
+                            );
+                        }
+                        last_error = e;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(last_error)
+        }
+
+        async fn download(
+            &self,
+            url: &str,
+            resource: &str,
+            validators: Option<&Validators>,
+        ) -> Result<Fetched, ImageProcessingError> {
+            let url = Url::parse(url).map_err(|_| {
+                error!(
+                    "the provided resource uri is not a valid http url: '{}'",
+                    url
+                );
+                InvalidResourceUriProvided(String::from(resource))
+            })?;
+            let host = url.host_str().unwrap_or_default().to_string();
+            if let Some(circuit_breaker) = &self.circuit_breaker {
+                circuit_breaker.check(&host)?;
+            }
+            let _permit = match &self.rate_limiter {
+                Some(rate_limiter) => Some(rate_limiter.acquire(&host).await?),
+                None => None,
+            };
+            let result = self.request(url, resource, validators).await;
+            if let Some(circuit_breaker) = &self.circuit_breaker {
+                circuit_breaker.record(&host, &result);
+            }
+            result
+        }
+
+        async fn request(
+            &self,
+            url: Url,
+            resource: &str,
+            validators: Option<&Validators>,
+        ) -> Result<Fetched, ImageProcessingError> {
+            let announced_size = if validators.is_none() && self.needs_preflight(&url) {
+                self.preflight(&url, resource).await?
+            } else {
+                None
+            };
+            let mut request = self
+                .origin_headers
+                .request(&self.client, Method::GET, &url)
+                .await;
+            if let Some(validators) = validators {
+                request = validators.apply(request);
+            }
+            let response = request.send().await.map_err(|e| {
+                if e.is_timeout() {
+                    error!(
+                        "request for downloading the image '{}' timed out. error: {}",
+                        url, e
+                    );
+                    ImageDownloadTimedOut
+                } else if origin_guard::is_blocked(&e) {
+                    ForbiddenOrigin(url.host_str().unwrap_or_default().to_string())
+                } else {
+                    error!("error downloading the image: '{}'. error: {}", url, e);
+                    ImageDownloadFailed
+                }
+            })?;
+
+            let status = response.status();
+            if status == StatusCode::NOT_MODIFIED && validators.is_some() {
+                debug!("the mirrored image '{}' is still up to date", url);
+                Ok(Fetched::NotModified)
+            } else if status.is_success() {
+                let validators = Validators::from_headers(response.headers());
+                let body = read_body(response, self.max_source_size_bytes, url.as_str()).await?;
+                Ok(Fetched::Modified(
+                    Bytes::from(body),
+                    validators,
+                    announced_size,
+                ))
+            } else if status.is_client_error() {
+                error!(
+                    "the requested image '{}' couldn't be downloaded. received status code: {}",
+                    url, status
+                );
+                Err(ClientReturnedErrorStatusCode(
+                    status.as_u16(),
+                    String::from(resource),
+                ))
+            } else {
+                error!(
+                    "failed to download the image '{}'. received status code: {}",
+                    url,
+                    status.as_str()
+                );
+                Err(ImageDownloadFailed)
             }
         }
     }
 
     #[async_trait]
     impl ImageProvider for FileImageProvider {
-        async fn get_file(&self, resource: &str) -> Result<Vec<u8>, ImageProcessingError> {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
             if resource.starts_with("http://") || resource.starts_with("https://") {
                 let url = Url::parse(resource).map_err(|_| {
                     error!(
@@ -91,61 +583,61 @@ pub mod file {
                     );
                     InvalidResourceUriProvided(String::from(resource))
                 })?;
-                let filepathstr = format!("{}{}", self.public_img_path, url.clone().path());
+                let filepathstr = local_image_path(&self.public_img_path, resource)?
+                    .to_string_lossy()
+                    .into_owned();
                 let filepath = Path::new(filepathstr.as_str());
-                if !url.path().is_empty() && filepath.exists() {
-                    return read_file(filepathstr.as_str()).await;
-                }
-                let response = self.client.get(url.clone()).send().await.map_err(|e| {
-                    if e.is_timeout() {
-                        error!(
-                            "request for downloading the image '{}' timed out. error: {}",
-                            resource, e
-                        );
-                        ImageDownloadTimedOut
-                    } else {
-                        error!("error downloading the image: '{}'. error: {}", resource, e);
-                        ImageDownloadFailed
-                    }
-                })?;
+                let mirrored = !url.path().is_empty() && self.filesystem.exists(filepath).await;
+                if mirrored {
+                    self.record_hit(resource);
+                }
+                if mirrored && self.is_fresh(filepathstr.as_str()).await {
+                    return read_file(filepathstr.as_str(), self.mmap_threshold).await;
+                }
+                let urls = self.origin_urls(resource).await?;
+                if mirrored {
+                    return self.revalidate(&urls, resource, filepathstr.as_str()).await;
+                }
+                self.download_with_failover(&urls, resource, filepathstr.as_str(), None)
+                    .await
+            } else {
+                let filepathstr = local_image_path(&self.public_img_path, resource)?
+                    .to_string_lossy()
+                    .into_owned();
+                if self.origins.is_empty() {
+                    return read_file(filepathstr.as_str(), self.mmap_threshold).await;
+                }
+                let mirrored = self
+                    .filesystem
+                    .exists(Path::new(filepathstr.as_str()))
+                    .await;
+                if mirrored {
+                    self.record_hit(resource);
+                }
+                if mirrored && self.is_fresh(filepathstr.as_str()).await {
+                    return read_file(filepathstr.as_str(), self.mmap_threshold).await;
+                }
+                let urls = self.origin_urls(resource).await?;
+                if mirrored {
+                    return self.revalidate(&urls, resource, filepathstr.as_str()).await;
+                }
+                self.download_with_failover(&urls, resource, filepathstr.as_str(), None)
+                    .await
+            }
+        }
 
-                let status = response.status();
-                if status.is_success() {
-                    let bytes = response.bytes().await.map_err(|e| {
-                        error!(
-                            "failed to read the binary payload of the image '{}'. error: {}",
-                            resource, e
-                        );
-                        ImageDownloadFailed
-                    })?;
-                    create_path_for_file(filepathstr.as_str());
-                    let file = File::create(format!("{}{}", self.public_img_path, url.path()))
-                        .await
-                        .unwrap();
-                    let mut writer = BufWriter::new(file);
-                    let bytes_vec = bytes.to_vec();
-                    writer.write_all(bytes_vec.as_slice()).await.unwrap();
-                    writer.flush().await.unwrap();
-                    Ok(bytes_vec)
-                } else if status.is_client_error() {
-                    error!(
-                        "the requested image '{}' couldn't be downloaded. received status code: {}",
-                        resource, status
-                    );
-                    Err(ClientReturnedErrorStatusCode(
-                        status.as_u16(),
-                        String::from(resource),
+        async fn ready(&self) -> Result<(), String> {
+            match tokio::fs::metadata(&self.public_img_path).await {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => return Err(format!("'{}' is not a directory", self.public_img_path)),
+                Err(e) => {
+                    return Err(format!(
+                        "'{}' can't be accessed: {}",
+                        self.public_img_path, e
                     ))
-                } else {
-                    error!(
-                        "failed to download the specified resource. received status code: {}",
-                        status.as_str()
-                    );
-                    Err(ImageDownloadFailed)
                 }
-            } else {
-                read_file(format!("{}/{}", self.public_img_path, resource).as_str()).await
             }
+            probe_origins(&self.client, self.origins.base_urls()).await
         }
     }
 }
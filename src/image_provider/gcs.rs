@@ -0,0 +1,236 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod gcs {
+
+    use std::env;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use async_trait::async_trait;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use log::*;
+    use reqwest::{Client, StatusCode, Url};
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::Mutex;
+
+    use crate::commons::config::Configuration;
+    use crate::image_provider::ImageProcessingError::{
+        ClientReturnedErrorStatusCode, ImageDownloadFailed, ImageDownloadTimedOut,
+        InvalidResourceUriProvided,
+    };
+    use crate::image_provider::{http_client_builder, read_body, ImageBuffer, ImageProvider};
+    use crate::routes::image::ImageProcessingError;
+
+    const STORAGE_API_URL: &str = "https://storage.googleapis.com/storage/v1/b";
+    const READ_ONLY_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+    const METADATA_TOKEN_URL: &str =
+        "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+    // the token is refreshed a bit before it expires so that in-flight requests don't fail
+    const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+    #[derive(Deserialize)]
+    struct ServiceAccountKey {
+        client_email: String,
+        private_key: String,
+        token_uri: String,
+    }
+
+    #[derive(Serialize)]
+    struct Claims<'a> {
+        iss: &'a str,
+        scope: &'a str,
+        aud: &'a str,
+        iat: u64,
+        exp: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+        expires_in: u64,
+    }
+
+    struct AccessToken {
+        value: String,
+        expires_at: Instant,
+    }
+
+    pub struct GcsImageProvider {
+        client: Client,
+        bucket: String,
+        prefix: Option<String>,
+        max_source_size_bytes: Option<u64>,
+        // without a service account key the token is requested from the GCE metadata server
+        service_account: Option<ServiceAccountKey>,
+        token: Mutex<Option<AccessToken>>,
+    }
+
+    impl GcsImageProvider {
+        pub async fn new(config: &Configuration) -> GcsImageProvider {
+            let bucket = config
+                .gcs_bucket
+                .clone()
+                .expect("the gcs_bucket configuration is required by the GCS image provider");
+            let key_path = config
+                .gcs_service_account_key_path
+                .clone()
+                .or_else(|| env::var("GOOGLE_APPLICATION_CREDENTIALS").ok());
+            let service_account = key_path.map(|path| {
+                let key = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                    panic!("failed to read the service account key '{}': {}", path, e)
+                });
+                serde_json::from_str::<ServiceAccountKey>(&key).unwrap_or_else(|e| {
+                    panic!("the service account key '{}' is not valid: {}", path, e)
+                })
+            });
+            Self {
+                client: http_client_builder(config).build().unwrap(),
+                bucket,
+                prefix: config.gcs_prefix.clone(),
+                max_source_size_bytes: config.max_source_size_bytes,
+                service_account,
+                token: Mutex::new(None),
+            }
+        }
+
+        fn object_url(&self, resource: &str) -> Result<Url, ImageProcessingError> {
+            let resource = resource.trim_start_matches('/');
+            let object = match &self.prefix {
+                Some(prefix) => format!("{}/{}", prefix.trim_end_matches('/'), resource),
+                None => resource.to_string(),
+            };
+            let mut url = Url::parse(STORAGE_API_URL).unwrap();
+            // the object name is a single, percent encoded, path segment in the JSON API
+            url.path_segments_mut()
+                .map_err(|_| InvalidResourceUriProvided(String::from(resource)))?
+                .extend([self.bucket.as_str(), "o", object.as_str()]);
+            url.set_query(Some("alt=media"));
+            Ok(url)
+        }
+
+        async fn access_token(&self) -> Result<String, ImageProcessingError> {
+            let mut token = self.token.lock().await;
+            if let Some(token) = token.as_ref() {
+                if Instant::now() + TOKEN_EXPIRY_MARGIN < token.expires_at {
+                    return Ok(token.value.clone());
+                }
+            }
+            let response = self.request_token().await.map_err(|e| {
+                error!("failed to obtain an access token for GCS. error: {}", e);
+                ImageDownloadFailed
+            })?;
+            let value = response.access_token.clone();
+            *token = Some(AccessToken {
+                value: response.access_token,
+                expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+            });
+            Ok(value)
+        }
+
+        async fn request_token(&self) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+            let response = match &self.service_account {
+                Some(key) => {
+                    let iat = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                    let claims = Claims {
+                        iss: &key.client_email,
+                        scope: READ_ONLY_SCOPE,
+                        aud: &key.token_uri,
+                        iat,
+                        exp: iat + 3600,
+                    };
+                    let assertion = jsonwebtoken::encode(
+                        &Header::new(Algorithm::RS256),
+                        &claims,
+                        &EncodingKey::from_rsa_pem(key.private_key.as_bytes())?,
+                    )?;
+                    self.client
+                        .post(&key.token_uri)
+                        .form(&[
+                            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                            ("assertion", assertion.as_str()),
+                        ])
+                        .send()
+                        .await?
+                }
+                None => {
+                    self.client
+                        .get(METADATA_TOKEN_URL)
+                        .header("Metadata-Flavor", "Google")
+                        .send()
+                        .await?
+                }
+            };
+            let body = response.error_for_status()?.bytes().await?;
+            Ok(serde_json::from_slice(&body)?)
+        }
+    }
+
+    #[async_trait]
+    impl ImageProvider for GcsImageProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            let url = self.object_url(resource)?;
+            let token = self.access_token().await?;
+            let response = self
+                .client
+                .get(url)
+                .bearer_auth(token)
+                .send()
+                .await
+                .map_err(|e| {
+                    if e.is_timeout() {
+                        error!(
+                            "request for downloading the image '{}' timed out. error: {}",
+                            resource, e
+                        );
+                        ImageDownloadTimedOut
+                    } else {
+                        error!("error downloading the image: '{}'. error: {}", resource, e);
+                        ImageDownloadFailed
+                    }
+                })?;
+
+            let status = response.status();
+            if status == StatusCode::UNAUTHORIZED {
+                // the token might have been revoked, the next request fetches a new one
+                self.token.lock().await.take();
+            }
+            if !status.is_success() {
+                error!(
+                    "the requested image '{}' couldn't be downloaded from the bucket '{}'. received status code: {}",
+                    resource, self.bucket, status
+                );
+                return Err(if status.is_client_error() {
+                    ClientReturnedErrorStatusCode(status.as_u16(), String::from(resource))
+                } else {
+                    ImageDownloadFailed
+                });
+            }
+
+            let body = read_body(response, self.max_source_size_bytes, resource).await?;
+            Ok(ImageBuffer::from(body))
+        }
+
+        async fn ready(&self) -> Result<(), String> {
+            let token = self
+                .access_token()
+                .await
+                .map_err(|e| format!("no access token for GCS: {}", e))?;
+            // listing the objects is granted along with reading them, unlike the bucket metadata
+            let url = format!("{}/{}/o", STORAGE_API_URL, self.bucket);
+            let mut query = vec![("maxResults", "1")];
+            query.extend(self.prefix.as_deref().map(|prefix| ("prefix", prefix)));
+            let request = self.client.get(url).query(&query).bearer_auth(token);
+            match request.send().await {
+                Ok(response) if response.status().is_success() => Ok(()),
+                Ok(response) => Err(format!(
+                    "the bucket '{}' answered with the status code {}",
+                    self.bucket,
+                    response.status()
+                )),
+                Err(e) => Err(format!(
+                    "the bucket '{}' can't be reached: {}",
+                    self.bucket, e
+                )),
+            }
+        }
+    }
+}
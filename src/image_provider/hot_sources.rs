@@ -0,0 +1,99 @@
+// (c) Copyright 2019-2024 OLX
+
+// counts how often every mirrored image is served, so that the most requested ones can be
+// revalidated in the background shortly before `mirror_revalidate_after_secs` runs out, instead
+// of making the first request after it wait for the origin. the counts start over on every
+// refresh round, so only the images which are still requested keep being refreshed.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+
+use crate::commons::config::Configuration;
+
+const DEFAULT_MAX_SOURCES: usize = 100;
+const DEFAULT_AHEAD_SECS: u64 = 60;
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+pub struct HotSources {
+    min_hits: u64,
+    max_sources: usize,
+    pub ahead: Duration,
+    pub interval: Duration,
+    hits: Mutex<HashMap<String, u64>>,
+}
+
+impl HotSources {
+    pub fn new(config: &Configuration) -> Option<HotSources> {
+        let min_hits = config.hot_refresh_min_hits?;
+        if config.mirror_revalidate_after_secs.is_none() {
+            warn!(
+                "hot_refresh_min_hits is ignored as mirror_revalidate_after_secs is not configured"
+            );
+            return None;
+        }
+        Some(Self {
+            min_hits: min_hits.max(1),
+            max_sources: config
+                .hot_refresh_max_sources
+                .unwrap_or(DEFAULT_MAX_SOURCES),
+            ahead: Duration::from_secs(config.hot_refresh_ahead_secs.unwrap_or(DEFAULT_AHEAD_SECS)),
+            interval: Duration::from_secs(
+                config
+                    .hot_refresh_interval_secs
+                    .unwrap_or(DEFAULT_INTERVAL_SECS),
+            ),
+            hits: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn record_hit(&self, resource: &str) {
+        let mut hits = self.hits.lock().unwrap();
+        match hits.get_mut(resource) {
+            Some(count) => *count += 1,
+            None => {
+                hits.insert(String::from(resource), 1);
+            }
+        }
+    }
+
+    // the resources requested at least `min_hits` times since the previous round, the most
+    // requested first
+    pub fn take_hottest(&self) -> Vec<String> {
+        let hits = std::mem::take(&mut *self.hits.lock().unwrap());
+        let mut hottest: Vec<(String, u64)> = hits
+            .into_iter()
+            .filter(|(_, count)| *count >= self.min_hits)
+            .collect();
+        hottest.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hottest.truncate(self.max_sources);
+        hottest.into_iter().map(|(resource, _)| resource).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_hottest() {
+        let hot_sources = HotSources {
+            min_hits: 2,
+            max_sources: 2,
+            ahead: Duration::from_secs(DEFAULT_AHEAD_SECS),
+            interval: Duration::from_secs(DEFAULT_INTERVAL_SECS),
+            hits: Mutex::new(HashMap::new()),
+        };
+        for resource in [
+            "a.jpg", "b.jpg", "b.jpg", "c.jpg", "c.jpg", "c.jpg", "d.jpg", "d.jpg",
+        ] {
+            hot_sources.record_hit(resource);
+        }
+        assert_eq!(hot_sources.take_hottest(), vec!["c.jpg", "b.jpg"]);
+        // the counts start over
+        hot_sources.record_hit("a.jpg");
+        assert!(hot_sources.take_hottest().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+// (c) Copyright 2019-2024 OLX
+
+// keeps the most recently fetched originals and watermarks in memory, bounded by the sum of their
+// sizes rather than by their number, as a handful of big originals could otherwise take all the
+// memory of the instance. the entries expire after `memory_cache_ttl_secs`, never later than the
+// mirrored images are revalidated, so that the changes of the origins still come through, and the
+// purged images are evicted right away.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use lru::LruCache;
+
+use super::{ImageBuffer, ImageProvider};
+use crate::commons::clock::Clock;
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+
+const DEFAULT_TTL_SECS: u64 = 300;
+
+struct ByteWeightedLru {
+    entries: LruCache<String, (Bytes, SystemTime)>,
+    used_bytes: usize,
+    max_bytes: usize,
+}
+
+impl ByteWeightedLru {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            used_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&mut self, key: &str, now: SystemTime) -> Option<Bytes> {
+        match self.entries.get(key) {
+            Some((value, expires_at)) if *expires_at > now => Some(value.clone()),
+            Some(_) => {
+                self.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, value: Bytes, expires_at: SystemTime) {
+        if value.len() > self.max_bytes {
+            return;
+        }
+        self.used_bytes += value.len();
+        if let Some((previous, _)) = self.entries.put(key, (value, expires_at)) {
+            self.used_bytes -= previous.len();
+        }
+        while self.used_bytes > self.max_bytes {
+            match self.entries.pop_lru() {
+                Some((_, (evicted, _))) => self.used_bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((removed, _)) = self.entries.pop(key) {
+            self.used_bytes -= removed.len();
+        }
+    }
+}
+
+pub struct MemoryCachedProvider {
+    inner: Box<dyn ImageProvider>,
+    cache: Mutex<ByteWeightedLru>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl MemoryCachedProvider {
+    pub fn new(
+        inner: Box<dyn ImageProvider>,
+        max_bytes: u64,
+        config: &Configuration,
+        clock: Arc<dyn Clock>,
+    ) -> MemoryCachedProvider {
+        let ttl_secs = config
+            .memory_cache_ttl_secs
+            .unwrap_or(DEFAULT_TTL_SECS)
+            .min(config.mirror_revalidate_after_secs.unwrap_or(u64::MAX));
+        Self {
+            inner,
+            cache: Mutex::new(ByteWeightedLru::new(max_bytes as usize)),
+            ttl: Duration::from_secs(ttl_secs),
+            clock,
+        }
+    }
+}
+
+#[async_trait]
+impl ImageProvider for MemoryCachedProvider {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        let now = self.clock.now();
+        if let Some(cached) = self.cache.lock().unwrap().get(resource, now) {
+            return Ok(ImageBuffer::Shared(cached));
+        }
+        let bytes = self.inner.get_file(resource).await?.into_bytes();
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(resource.to_string(), bytes.clone(), now + self.ttl);
+        Ok(ImageBuffer::Shared(bytes))
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        self.inner.ready().await
+    }
+
+    async fn evict(&self, resource: &str) {
+        self.cache.lock().unwrap().remove(resource);
+        self.inner.evict(resource).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    fn bytes(len: usize) -> Bytes {
+        Bytes::from(vec![0; len])
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_byte_weighted_eviction() {
+        let (now, later) = (at(0), at(60));
+        let mut lru = ByteWeightedLru::new(100);
+        lru.insert("a".to_string(), bytes(40), later);
+        lru.insert("b".to_string(), bytes(40), later);
+        // reading `a` makes `b` the least recently used entry
+        assert!(lru.get("a", now).is_some());
+        lru.insert("c".to_string(), bytes(40), later);
+        assert!(lru.get("b", now).is_none());
+        assert!(lru.get("a", now).is_some() && lru.get("c", now).is_some());
+        assert_eq!(lru.used_bytes, 80);
+
+        lru.insert("a".to_string(), bytes(10), later);
+        assert_eq!(lru.used_bytes, 50);
+        lru.insert("huge".to_string(), bytes(101), later);
+        assert!(lru.get("huge", now).is_none());
+        assert_eq!(lru.used_bytes, 50);
+    }
+
+    #[test]
+    fn test_expiry_and_removal() {
+        let mut lru = ByteWeightedLru::new(100);
+        lru.insert("a".to_string(), bytes(40), at(60));
+        lru.insert("b".to_string(), bytes(20), at(120));
+        assert!(lru.get("a", at(59)).is_some());
+        assert!(lru.get("a", at(60)).is_none());
+        assert_eq!(lru.used_bytes, 20);
+
+        lru.remove("b");
+        assert!(lru.get("b", at(0)).is_none());
+        assert_eq!(lru.used_bytes, 0);
+    }
+}
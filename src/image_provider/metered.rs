@@ -0,0 +1,135 @@
+// (c) Copyright 2019-2024 OLX
+
+// records the latency, the fetched bytes and the failures of a provider, labeled by the provider
+// and the host of the origin, so that a slow or failing storage backend or origin stands out.
+// the relative addresses aren't tied to a single origin, so they are labeled with `-`, and the
+// hosts which are neither configured origins nor part of `origin_allowlist` with `other`, as every
+// host the clients make up would otherwise be a new series.
+
+use std::time::Instant;
+
+use async_trait::async_trait;
+use reqwest::Url;
+
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+use crate::routes::image::ImageProcessingError::*;
+use crate::routes::metric::{
+    PROVIDER_FETCHED_BYTES_VEC, PROVIDER_FETCH_DURATION_VEC, PROVIDER_FETCH_ERRORS_VEC,
+};
+
+use super::origin_guard::OriginGuard;
+use super::{ImageBuffer, ImageProvider};
+
+const NO_HOST: &str = "-";
+const OTHER_HOST: &str = "other";
+
+pub struct MeteredProvider {
+    name: String,
+    inner: Box<dyn ImageProvider>,
+    origin_guard: OriginGuard,
+}
+
+impl MeteredProvider {
+    pub fn new(
+        name: &str,
+        inner: Box<dyn ImageProvider>,
+        config: &Configuration,
+    ) -> MeteredProvider {
+        Self {
+            name: name.to_string(),
+            inner,
+            origin_guard: OriginGuard::new(config),
+        }
+    }
+}
+
+fn host_label(resource: &str, origin_guard: &OriginGuard) -> String {
+    if !resource.starts_with("http://") && !resource.starts_with("https://") {
+        return NO_HOST.to_string();
+    }
+    match Url::parse(resource)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+    {
+        Some(host) if origin_guard.is_allowed_host(&host) => host,
+        Some(_) => OTHER_HOST.to_string(),
+        None => NO_HOST.to_string(),
+    }
+}
+
+fn error_class(error: &ImageProcessingError) -> &'static str {
+    match error {
+        ImageDownloadTimedOut => "timeout",
+        ClientReturnedErrorStatusCode(status, _) if *status >= 500 => "server_error",
+        ClientReturnedErrorStatusCode(..) => "client_error",
+        SourceTooLarge(_) => "too_large",
+        UnsupportedMediaType(_) => "unsupported_media_type",
+        InvalidResourceUriProvided(_) | PathOutsideRoot(_) => "invalid_address",
+        ForbiddenOrigin(_) => "forbidden_origin",
+        OriginUnavailable(_) => "origin_unavailable",
+        _ => "failed",
+    }
+}
+
+#[async_trait]
+impl ImageProvider for MeteredProvider {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        let host = host_label(resource, &self.origin_guard);
+        let labels = [self.name.as_str(), host.as_str()];
+        let started = Instant::now();
+        let result = self.inner.get_file(resource).await;
+        PROVIDER_FETCH_DURATION_VEC
+            .with_label_values(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        match &result {
+            Ok(image) => PROVIDER_FETCHED_BYTES_VEC
+                .with_label_values(&labels)
+                .inc_by(image.len() as u64),
+            Err(e) => PROVIDER_FETCH_ERRORS_VEC
+                .with_label_values(&[labels[0], labels[1], error_class(e)])
+                .inc(),
+        }
+        result
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        self.inner.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_labels() {
+        let config = Configuration::for_tests(json!({
+            "origin_base_urls": ["https://origin.example.com"],
+            "origin_allowlist": ["img.example.com", "10.0.0.0/8"],
+        }));
+        let guard = OriginGuard::new(&config);
+        assert_eq!(
+            host_label("https://img.example.com/a.jpg", &guard),
+            "img.example.com"
+        );
+        assert_eq!(
+            host_label("https://origin.example.com/a.jpg", &guard),
+            "origin.example.com"
+        );
+        assert_eq!(host_label("https://made.up.host/a.jpg", &guard), OTHER_HOST);
+        assert_eq!(host_label("http://10.0.0.1/a.jpg", &guard), OTHER_HOST);
+        assert_eq!(host_label("photos/a.jpg", &guard), NO_HOST);
+        assert_eq!(
+            error_class(&ClientReturnedErrorStatusCode(503, String::from("a.jpg"))),
+            "server_error"
+        );
+        assert_eq!(
+            error_class(&ClientReturnedErrorStatusCode(404, String::from("a.jpg"))),
+            "client_error"
+        );
+        assert_eq!(error_class(&ImageDownloadTimedOut), "timeout");
+    }
+}
@@ -1,19 +1,289 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use self::coalescing::CoalescingProvider;
+use self::data_uri::DataUriProvider;
+use self::memory_cache::MemoryCachedProvider;
+use self::metered::MeteredProvider;
+use self::reqwest::reqwest::ReqwestImageProvider;
+use self::router::ProviderRouter;
+use ::reqwest::{Client, ClientBuilder, Response};
 use async_trait::async_trait;
+use axum::body::Bytes;
 use file::file::FileImageProvider;
+use log::*;
+use memmap2::Mmap;
 
-use crate::{commons::config::Configuration, routes::image::ImageProcessingError};
+use crate::{
+    commons::{clock::SystemClock, config::Configuration, filesystem::FileSystem},
+    routes::image::ImageProcessingError,
+};
+pub mod circuit_breaker;
+pub mod coalescing;
+pub mod data_uri;
+pub mod disk_cache;
 pub mod file;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+pub mod hot_sources;
+pub mod memory_cache;
+pub mod metered;
+pub mod origin;
+pub mod origin_guard;
+pub mod origin_headers;
+pub mod proxy;
+pub mod rate_limit;
+#[cfg(feature = "redis-cache")]
+pub mod redis_cache;
+pub mod reqwest;
+pub mod router;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod sigv4;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod validators;
+
+// the bytes of a source image, either held in memory or mapped from a local file so that large
+// originals don't have to be copied to the heap before libvips decodes them. the shared buffers
+// are the ones also held by the in-memory cache.
+pub enum ImageBuffer {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+    Shared(Bytes),
+}
+
+impl Deref for ImageBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ImageBuffer::Owned(buffer) => buffer,
+            ImageBuffer::Mapped(mmap) => mmap,
+            ImageBuffer::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl ImageBuffer {
+    // the mapped files are wrapped rather than copied to the heap
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            ImageBuffer::Owned(buffer) => Bytes::from(buffer),
+            ImageBuffer::Mapped(mmap) => Bytes::from_owner(mmap),
+            ImageBuffer::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl From<Vec<u8>> for ImageBuffer {
+    fn from(buffer: Vec<u8>) -> Self {
+        ImageBuffer::Owned(buffer)
+    }
+}
 
 #[async_trait]
 pub trait ImageProvider: Send + Sync {
-    async fn get_file(&self, resource: &str) -> Result<Vec<u8>, ImageProcessingError>;
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError>;
+
+    // whether the storage backend can be reached, otherwise the reason why it can't
+    async fn ready(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // drops the copies of the purged image kept by the caches in front of the storage
+    async fn evict(&self, _resource: &str) {}
+}
+
+#[async_trait]
+impl<T: ImageProvider + ?Sized> ImageProvider for Arc<T> {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        (**self).get_file(resource).await
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        (**self).ready().await
+    }
+
+    async fn evict(&self, resource: &str) {
+        (**self).evict(resource).await
+    }
+}
+
+// the origins are reachable as long as one of them answers, whatever the status code, as their
+// base urls aren't images
+pub async fn probe_origins(client: &Client, base_urls: &[String]) -> Result<(), String> {
+    let mut last_error = None;
+    for base_url in base_urls {
+        match client.head(base_url).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(format!("the origin '{}' is unreachable: {}", base_url, e)),
+        }
+    }
+    last_error.map_or(Ok(()), Err)
+}
+
+pub fn http_client_builder(config: &Configuration) -> ClientBuilder {
+    let builder = match proxy::origin_proxy(config) {
+        Some(proxy) => Client::builder().proxy(proxy),
+        None => Client::builder(),
+    };
+    builder
+        .timeout(Duration::from_millis(u64::from(
+            config.reqwest_timeout_millis.unwrap_or(2000),
+        )))
+        .connect_timeout(Duration::from_millis(u64::from(
+            config.reqwest_connection_timeout_millis.unwrap_or(2000),
+        )))
+        .pool_max_idle_per_host(usize::from(
+            config.reqwest_pool_max_idle_per_host.unwrap_or(10),
+        ))
+        .pool_idle_timeout(Duration::from_millis(u64::from(
+            config.reqwest_pool_idle_timeout_millis.unwrap_or(60000),
+        )))
+}
+
+// the limit of the downloaded sources when `max_source_size_bytes` isn't configured
+pub const DEFAULT_MAX_SOURCE_SIZE_BYTES: u64 = 100 * 1024 * 1024;
+
+// the media types, announced by the origins or by the data URIs, of the sources which are loaded
+pub fn is_supported_media_type(media_type: &str) -> bool {
+    media_type.starts_with("image/")
+        || media_type.starts_with("application/pdf")
+        || (cfg!(feature = "video") && media_type.starts_with("video/"))
+}
+// the content length is only a hint, the memory is committed as the body actually arrives
+const MAX_PREALLOCATED_BYTES: u64 = 8 * 1024 * 1024;
+
+// reads the body chunk by chunk so that a source exceeding the maximum size is dropped as soon
+// as it crosses the limit, even when the origin doesn't send a content length
+pub async fn read_body(
+    mut response: Response,
+    max_source_size_bytes: Option<u64>,
+    resource: &str,
+) -> Result<Vec<u8>, ImageProcessingError> {
+    let max_size = max_source_size_bytes.unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+    if let Some(length) = response
+        .content_length()
+        .filter(|length| *length > max_size)
+    {
+        error!(
+            "the image '{}' has {} bytes which exceeds the limit of {} bytes",
+            resource, length, max_size
+        );
+        return Err(ImageProcessingError::SourceTooLarge(max_size));
+    }
+    let capacity = response
+        .content_length()
+        .unwrap_or(0)
+        .min(MAX_PREALLOCATED_BYTES);
+    let mut body = Vec::with_capacity(capacity as usize);
+    loop {
+        let chunk = response.chunk().await.map_err(|e| {
+            error!(
+                "failed to read the binary payload of the image '{}'. error: {}",
+                resource, e
+            );
+            ImageProcessingError::ImageDownloadFailed
+        })?;
+        let Some(chunk) = chunk else {
+            return Ok(body);
+        };
+        if (body.len() + chunk.len()) as u64 > max_size {
+            error!(
+                "the image '{}' exceeds the limit of {} bytes",
+                resource, max_size
+            );
+            return Err(ImageProcessingError::SourceTooLarge(max_size));
+        }
+        body.extend_from_slice(&chunk);
+    }
+}
+
+pub async fn create_image_provider(
+    config: &Configuration,
+    filesystem: Arc<dyn FileSystem>,
+) -> Box<dyn ImageProvider> {
+    let provider: Box<dyn ImageProvider> = Box::new(CoalescingProvider::new(
+        create_routed_provider(config, filesystem).await,
+    ));
+    #[cfg(feature = "redis-cache")]
+    let provider: Box<dyn ImageProvider> = match &config.redis_url {
+        Some(redis_url) => {
+            Box::new(redis_cache::RedisCachedProvider::new(provider, redis_url, config).await)
+        }
+        None => provider,
+    };
+    let provider: Box<dyn ImageProvider> = match config.memory_cache_max_bytes {
+        Some(max_bytes) => Box::new(MemoryCachedProvider::new(
+            provider,
+            max_bytes,
+            config,
+            Arc::new(SystemClock),
+        )),
+        None => provider,
+    };
+    Box::new(DataUriProvider::new(provider, config.max_source_size_bytes))
+}
+
+async fn create_routed_provider(
+    config: &Configuration,
+    filesystem: Arc<dyn FileSystem>,
+) -> Box<dyn ImageProvider> {
+    let default_provider = config.provider.as_deref().unwrap_or("file");
+    let Some(routes) = &config.provider_routes else {
+        return create_provider(default_provider, config, filesystem).await;
+    };
+
+    // every provider is instantiated once, even when several prefixes are routed to it
+    let mut providers: HashMap<&str, Arc<dyn ImageProvider>> = HashMap::new();
+    let mut router_routes = vec![];
+    for route in routes {
+        let (prefix, provider) = route.rsplit_once('=').unwrap_or_else(|| {
+            panic!(
+                "the provider route '{}' is not valid, expected 'prefix=provider'",
+                route
+            )
+        });
+        if !providers.contains_key(provider) {
+            let created = create_provider(provider, config, filesystem.clone()).await;
+            providers.insert(provider, Arc::from(created));
+        }
+        router_routes.push((prefix.to_string(), providers[provider].clone()));
+    }
+    let default = match providers.get(default_provider) {
+        Some(provider) => provider.clone(),
+        None => Arc::from(create_provider(default_provider, config, filesystem).await),
+    };
+    Box::new(ProviderRouter::new(router_routes, default))
 }
 
-#[allow(unreachable_code)]
-pub async fn create_image_provider(config: &Configuration) -> Box<dyn ImageProvider> {
-    // #[cfg(feature = "reqwest")]
-    // {
-    //     return Box::new(ReqwestImageProvider::new(config).await);
-    // }
-    Box::new(FileImageProvider::new(config).await)
+async fn create_provider(
+    provider: &str,
+    config: &Configuration,
+    filesystem: Arc<dyn FileSystem>,
+) -> Box<dyn ImageProvider> {
+    let created: Box<dyn ImageProvider> = match provider {
+        #[cfg(feature = "s3")]
+        "s3" => Box::new(s3::s3::S3ImageProvider::new(config).await),
+        #[cfg(feature = "gcs")]
+        "gcs" => Box::new(gcs::gcs::GcsImageProvider::new(config).await),
+        #[cfg(feature = "sftp")]
+        "sftp" => Box::new(sftp::sftp::SftpImageProvider::new(config).await),
+        "http" => Box::new(ReqwestImageProvider::new(config).await),
+        "file" => {
+            let provider = Arc::new(FileImageProvider::new(config, filesystem).await);
+            provider.clone().spawn_refresh();
+            Box::new(provider)
+        }
+        provider => panic!(
+            "the image provider '{}' is not supported by this build of dali",
+            provider
+        ),
+    };
+    Box::new(MeteredProvider::new(provider, created, config))
 }
@@ -0,0 +1,137 @@
+// (c) Copyright 2019-2024 OLX
+
+// the base urls of the origins the source images are downloaded from. the first origin is the
+// primary one unless weights are configured, in which case the primary is picked with a weighted
+// round robin. the remaining origins are tried in their configured order when the primary fails.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::*;
+
+pub struct Origins {
+    base_urls: Vec<String>,
+    weights: Option<Vec<u32>>,
+    total_weight: u64,
+    counter: AtomicU64,
+}
+
+impl Origins {
+    pub fn new(base_urls: Vec<String>, weights: Option<Vec<u32>>) -> Self {
+        let base_urls: Vec<String> = base_urls
+            .into_iter()
+            .map(|url| url.trim_end_matches('/').to_string())
+            .collect();
+        let weights = weights.filter(|weights| {
+            let valid = weights.len() == base_urls.len() && weights.iter().any(|w| *w > 0);
+            if !valid {
+                warn!(
+                    "the origin weights {:?} don't match the origins {:?} and are ignored",
+                    weights, base_urls
+                );
+            }
+            valid
+        });
+        let total_weight = weights
+            .as_ref()
+            .map(|weights| weights.iter().map(|w| u64::from(*w)).sum())
+            .unwrap_or_default();
+        Self {
+            base_urls,
+            weights,
+            total_weight,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn base_urls(&self) -> &[String] {
+        &self.base_urls
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.base_urls.is_empty()
+    }
+
+    // the path of the resource relative to the origin serving it, if the url points to one of
+    // the configured origins
+    pub fn relative_path<'a>(&self, url: &'a str) -> Option<&'a str> {
+        self.base_urls.iter().find_map(|base_url| {
+            url.strip_prefix(base_url.as_str())
+                .filter(|path| path.starts_with('/'))
+        })
+    }
+
+    // the urls of the resource on every origin, in the order in which they have to be tried
+    pub fn candidates(&self, path: &str) -> Vec<String> {
+        let primary = self.primary_index();
+        let path = path.trim_start_matches('/');
+        std::iter::once(primary)
+            .chain((0..self.base_urls.len()).filter(|i| *i != primary))
+            .map(|i| format!("{}/{}", self.base_urls[i], path))
+            .collect()
+    }
+
+    fn primary_index(&self) -> usize {
+        let Some(weights) = &self.weights else {
+            return 0;
+        };
+        let mut slot = self.counter.fetch_add(1, Ordering::Relaxed) % self.total_weight;
+        for (i, weight) in weights.iter().enumerate() {
+            let weight = u64::from(*weight);
+            if slot < weight {
+                return i;
+            }
+            slot -= weight;
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_origins(weights: Option<Vec<u32>>) -> Origins {
+        Origins::new(
+            vec![
+                "https://eu.example.com/".to_string(),
+                "https://us.example.com/bucket".to_string(),
+            ],
+            weights,
+        )
+    }
+
+    #[test]
+    fn test_candidates_failover_order() {
+        let origins = create_origins(None);
+        assert_eq!(
+            origins.candidates("/img/a.jpg"),
+            vec![
+                "https://eu.example.com/img/a.jpg",
+                "https://us.example.com/bucket/img/a.jpg"
+            ]
+        );
+        assert_eq!(
+            origins.relative_path("https://us.example.com/bucket/img/a.jpg"),
+            Some("/img/a.jpg")
+        );
+        assert_eq!(origins.relative_path("https://other.com/img/a.jpg"), None);
+    }
+
+    #[test]
+    fn test_weighted_primary() {
+        let origins = create_origins(Some(vec![1, 3]));
+        let primaries: Vec<String> = (0..4)
+            .map(|_| origins.candidates("a.jpg").remove(0))
+            .collect();
+        assert_eq!(
+            primaries,
+            vec![
+                "https://eu.example.com/a.jpg",
+                "https://us.example.com/bucket/a.jpg",
+                "https://us.example.com/bucket/a.jpg",
+                "https://us.example.com/bucket/a.jpg"
+            ]
+        );
+        assert_eq!(create_origins(Some(vec![1])).candidates("a.jpg").len(), 2);
+    }
+}
@@ -0,0 +1,357 @@
+// (c) Copyright 2019-2024 OLX
+
+// keeps the image addresses sent by the clients from turning dali into a proxy for the internal
+// network. the hosts of those addresses are rejected when they point at a loopback, private or
+// link-local address, or when they aren't part of `origin_allowlist` if one is configured. the
+// entries of the allowlist are either host names, which are trusted whatever they resolve to, or
+// networks in the CIDR notation. the host names are checked by the resolver of the http client,
+// against the addresses actually connected to, which covers the redirects as well and leaves no
+// room for a DNS rebinding between the check and the download.
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use ::reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use ::reqwest::redirect::{Attempt, Policy};
+use ::reqwest::Url;
+use log::*;
+
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+use crate::routes::image::ImageProcessingError::{ForbiddenOrigin, InvalidResourceUriProvided};
+
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+enum AllowedOrigin {
+    Host(String),
+    Network(IpAddr, u8),
+}
+
+impl AllowedOrigin {
+    fn parse(entry: &str) -> AllowedOrigin {
+        let network = entry.split_once('/').and_then(|(address, prefix)| {
+            let address = address.parse::<IpAddr>().ok()?;
+            let prefix = prefix.parse::<u8>().ok()?;
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            (prefix <= max_prefix).then_some(AllowedOrigin::Network(address, prefix))
+        });
+        match (network, entry.parse::<IpAddr>()) {
+            (Some(network), _) => network,
+            (None, Ok(IpAddr::V4(address))) => AllowedOrigin::Network(IpAddr::V4(address), 32),
+            (None, Ok(IpAddr::V6(address))) => AllowedOrigin::Network(IpAddr::V6(address), 128),
+            (None, Err(_)) => AllowedOrigin::Host(entry.to_ascii_lowercase()),
+        }
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self, address) {
+            (AllowedOrigin::Network(IpAddr::V4(network), prefix), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(address) & mask
+            }
+            (AllowedOrigin::Network(IpAddr::V6(network), prefix), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_internal(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_internal_v4(address),
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => is_internal_v4(address),
+            None => is_internal_v6(address),
+        },
+    }
+}
+
+fn is_internal_v4(address: Ipv4Addr) -> bool {
+    let [first, second, ..] = address.octets();
+    address.is_loopback()
+        || address.is_private()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || address.is_broadcast()
+        // the shared address space used by carrier-grade NATs
+        || (first == 100 && (64..128).contains(&second))
+}
+
+fn is_internal_v6(address: Ipv6Addr) -> bool {
+    address.is_loopback()
+        || address.is_unspecified()
+        || address.is_unique_local()
+        || address.is_unicast_link_local()
+}
+
+// the error of the resolutions to a forbidden address, told apart from the other download failures
+#[derive(Debug)]
+struct BlockedAddress(IpAddr);
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the address '{}' is not allowed", self.0)
+    }
+}
+
+impl Error for BlockedAddress {}
+
+// whether the request failed because its host resolves to a forbidden address
+pub fn is_blocked(error: &::reqwest::Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if error.is::<BlockedAddress>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+#[derive(Clone)]
+pub struct OriginGuard {
+    allowlist: Vec<AllowedOrigin>,
+    block_internal: bool,
+    // the configured origins and proxies, which are trusted like the hosts of the allowlist
+    trusted_hosts: Vec<String>,
+}
+
+impl OriginGuard {
+    pub fn new(config: &Configuration) -> OriginGuard {
+        let configured_urls = config
+            .origin_base_urls
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .chain(config.origin_proxy_url.as_deref())
+            .chain(
+                config
+                    .origin_proxy_overrides
+                    .iter()
+                    .flatten()
+                    .filter_map(|entry| entry.split_once('=').map(|(_, url)| url)),
+            );
+        Self {
+            allowlist: config
+                .origin_allowlist
+                .iter()
+                .flatten()
+                .map(|entry| AllowedOrigin::parse(entry.trim()))
+                .collect(),
+            block_internal: config.block_internal_origins.unwrap_or(true),
+            trusted_hosts: configured_urls
+                .filter_map(|url| Url::parse(url.trim()).ok()?.host_str().map(str::to_owned))
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.trusted_hosts.contains(&host)
+            || self
+                .allowlist
+                .iter()
+                .any(|entry| matches!(entry, AllowedOrigin::Host(allowed) if *allowed == host))
+    }
+
+    fn is_allowed_address(&self, address: IpAddr) -> bool {
+        if self.allowlist.iter().any(|entry| entry.contains(address)) {
+            return true;
+        }
+        self.allowlist.is_empty() && !(self.block_internal && is_internal(address))
+    }
+
+    // the url's host as an address, without the brackets around the IPv6 ones
+    fn address_of(url: &Url) -> Option<IpAddr> {
+        url.host_str()?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()
+    }
+
+    // the addresses written in the urls aren't resolved, so they're checked before downloading,
+    // while the host names are left to the resolver
+    pub async fn check(&self, url: &Url) -> Result<(), ImageProcessingError> {
+        let Some(host) = url.host_str() else {
+            return Err(InvalidResourceUriProvided(url.to_string()));
+        };
+        match Self::address_of(url) {
+            Some(address) if !self.is_allowed_address(address) => {
+                error!(
+                    "refusing to download the image '{}' from the address '{}'",
+                    url, address
+                );
+                Err(ForbiddenOrigin(host.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn permits_redirect(&self, url: &Url) -> bool {
+        !matches!(Self::address_of(url), Some(address) if !self.is_allowed_address(address))
+    }
+
+    fn check_addresses(&self, host: &str, addresses: &[SocketAddr]) -> Result<(), BlockedAddress> {
+        if self.is_allowed_host(host) {
+            return Ok(());
+        }
+        match addresses
+            .iter()
+            .find(|address| !self.is_allowed_address(address.ip()))
+        {
+            Some(address) => {
+                error!(
+                    "refusing to connect to '{}' as it resolves to '{}'",
+                    host,
+                    address.ip()
+                );
+                Err(BlockedAddress(address.ip()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // the resolver of the http clients downloading the images, which fails the resolutions to a
+    // forbidden address
+    pub fn resolver(&self) -> Arc<GuardedResolver> {
+        Arc::new(GuardedResolver(Arc::new(self.clone())))
+    }
+
+    pub fn redirect_policy(&self) -> Policy {
+        let guard = self.clone();
+        Policy::custom(move |attempt: Attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if guard.permits_redirect(attempt.url()) {
+                attempt.follow()
+            } else {
+                error!(
+                    "refusing to follow the redirect to '{}' while downloading '{}'",
+                    attempt.url(),
+                    attempt.previous()[0]
+                );
+                attempt.stop()
+            }
+        })
+    }
+}
+
+pub struct GuardedResolver(Arc<OriginGuard>);
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let guard = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            // the port is replaced by the one of the url
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+            guard.check_addresses(host, &addresses)?;
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_guard(allowlist: &[&str]) -> OriginGuard {
+        OriginGuard {
+            allowlist: allowlist
+                .iter()
+                .map(|entry| AllowedOrigin::parse(entry))
+                .collect(),
+            block_internal: true,
+            trusted_hosts: vec!["origin.internal".to_string()],
+        }
+    }
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_internal_addresses_are_blocked_by_default() {
+        let guard = create_guard(&[]);
+        for url in [
+            "http://127.0.0.1/image.jpg",
+            "http://10.1.2.3/image.jpg",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/image.jpg",
+            "http://[::ffff:192.168.0.1]/image.jpg",
+            "http://[fd00::1]/image.jpg",
+        ] {
+            assert!(
+                matches!(
+                    guard.check(&Url::parse(url).unwrap()).await,
+                    Err(ForbiddenOrigin(_))
+                ),
+                "{}",
+                url
+            );
+        }
+        assert!(guard
+            .check(&Url::parse("http://93.184.216.34/image.jpg").unwrap())
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let guard = create_guard(&["images.example.com", "10.0.0.0/8", "2001:db8::/32"]);
+        assert!(guard.is_allowed_host("Images.Example.com"));
+        assert!(!guard.is_allowed_host("example.com"));
+        assert!(guard.is_allowed_address(address("10.20.30.40")));
+        assert!(guard.is_allowed_address(address("2001:db8::1")));
+        assert!(!guard.is_allowed_address(address("11.0.0.1")));
+        assert!(!guard.is_allowed_address(address("93.184.216.34")));
+        assert!(guard.is_allowed_host("origin.internal"));
+        assert!(!guard.permits_redirect(&Url::parse("http://11.0.0.1/").unwrap()));
+        assert!(guard.permits_redirect(&Url::parse("http://10.0.0.1/").unwrap()));
+    }
+
+    #[test]
+    fn test_resolved_addresses() {
+        let guard = create_guard(&["images.example.com", "10.0.0.0/8"]);
+        let resolved = |address: &str| [SocketAddr::new(self::address(address), 0)];
+        assert!(guard
+            .check_addresses("cdn.example.com", &resolved("10.1.1.1"))
+            .is_ok());
+        assert!(guard
+            .check_addresses("cdn.example.com", &resolved("93.184.216.34"))
+            .is_err());
+        assert!(guard
+            .check_addresses("images.example.com", &resolved("93.184.216.34"))
+            .is_ok());
+
+        let guard = create_guard(&[]);
+        assert!(guard
+            .check_addresses("rebound.example.com", &resolved("169.254.169.254"))
+            .is_err());
+        assert!(guard
+            .check_addresses("origin.internal", &resolved("10.1.1.1"))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_requests_to_internal_hosts_are_blocked() {
+        let guard = create_guard(&[]);
+        let client = ::reqwest::Client::builder()
+            .dns_resolver(guard.resolver())
+            .build()
+            .unwrap();
+        let error = client
+            .get("http://localhost:9/image.jpg")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_blocked(&error), "{:?}", error);
+    }
+}
@@ -0,0 +1,80 @@
+// (c) Copyright 2019-2024 OLX
+
+// the request headers attached to the downloads from specific origin hosts, so that the origins
+// protected by a token or by a SigV4 signature can be used without a proxy in front of them.
+// every entry of `origin_headers` is written as `host=Header-Name: value`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ::reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use ::reqwest::{Client, Method, RequestBuilder, Url};
+
+use crate::commons::clock::SystemClock;
+use crate::commons::config::Configuration;
+use crate::image_provider::sigv4::Sigv4Signer;
+
+pub struct OriginHeaders {
+    headers: HashMap<String, HeaderMap>,
+    signer: Option<Sigv4Signer>,
+}
+
+impl OriginHeaders {
+    pub fn new(config: &Configuration) -> OriginHeaders {
+        let mut headers: HashMap<String, HeaderMap> = HashMap::new();
+        for entry in config.origin_headers.iter().flatten() {
+            let (host, name, value) = parse_entry(entry).unwrap_or_else(|| {
+                panic!(
+                    "the origin header '{}' is not valid, expected 'host=Header-Name: value'",
+                    entry
+                )
+            });
+            headers.entry(host).or_default().append(name, value);
+        }
+        Self {
+            headers,
+            signer: Sigv4Signer::new(config, Arc::new(SystemClock)),
+        }
+    }
+
+    pub async fn request(&self, client: &Client, method: Method, url: &Url) -> RequestBuilder {
+        let mut request = client.request(method.clone(), url.clone());
+        let headers = url
+            .host_str()
+            .and_then(|host| self.headers.get(&host.to_ascii_lowercase()));
+        if let Some(headers) = headers {
+            request = request.headers(headers.clone());
+        }
+        match &self.signer {
+            Some(signer) if signer.signs(url) => request.headers(signer.sign(&method, url).await),
+            _ => request,
+        }
+    }
+}
+
+fn parse_entry(entry: &str) -> Option<(String, HeaderName, HeaderValue)> {
+    let (host, header) = entry.split_once('=')?;
+    let (name, value) = header.split_once(':')?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+    let mut value = HeaderValue::from_str(value.trim()).ok()?;
+    // keeps the credentials out of the debug output of the requests
+    value.set_sensitive(true);
+    Some((host.trim().to_ascii_lowercase(), name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry() {
+        let (host, name, value) =
+            parse_entry("Images.example.com=Authorization: Bearer dG9rZW4=").unwrap();
+        assert_eq!(host, "images.example.com");
+        assert_eq!(name, "authorization");
+        assert_eq!(value, "Bearer dG9rZW4=");
+        assert!(value.is_sensitive());
+        assert!(parse_entry("images.example.com").is_none());
+        assert!(parse_entry("images.example.com=Authorization").is_none());
+    }
+}
@@ -0,0 +1,109 @@
+// (c) Copyright 2019-2024 OLX
+
+// routes the downloads from the origins through an HTTP or SOCKS proxy, for the deployments which
+// can only reach the external origins through one. `origin_proxy_overrides` entries, written as
+// `host=proxy-url`, send specific hosts through another proxy, while the hosts matching an entry
+// of `origin_no_proxy` (a host or a parent domain, or `*`) are requested directly.
+
+use std::collections::HashMap;
+
+use ::reqwest::{Proxy, Url};
+
+use crate::commons::config::Configuration;
+
+struct ProxyRules {
+    default: Option<Url>,
+    overrides: HashMap<String, Url>,
+    no_proxy: Vec<String>,
+}
+
+impl ProxyRules {
+    fn new(config: &Configuration) -> ProxyRules {
+        let parse = |url: &str| {
+            Url::parse(url.trim())
+                .unwrap_or_else(|e| panic!("the proxy url '{}' is not valid: {}", url, e))
+        };
+        let overrides = config
+            .origin_proxy_overrides
+            .iter()
+            .flatten()
+            .map(|entry| {
+                let (host, url) = entry.split_once('=').unwrap_or_else(|| {
+                    panic!(
+                        "the proxy override '{}' is not valid, expected 'host=proxy-url'",
+                        entry
+                    )
+                });
+                (host.trim().to_ascii_lowercase(), parse(url))
+            })
+            .collect();
+        Self {
+            default: config.origin_proxy_url.as_deref().map(parse),
+            overrides,
+            no_proxy: config
+                .origin_no_proxy
+                .iter()
+                .flatten()
+                .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.default.is_none() && self.overrides.is_empty()
+    }
+
+    fn proxy_for(&self, host: &str) -> Option<Url> {
+        let host = host.to_ascii_lowercase();
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        });
+        if bypassed {
+            return None;
+        }
+        self.overrides.get(&host).or(self.default.as_ref()).cloned()
+    }
+}
+
+// returns `None` when no proxy is configured, which keeps the proxy of the environment, if any
+pub fn origin_proxy(config: &Configuration) -> Option<Proxy> {
+    let rules = ProxyRules::new(config);
+    if rules.is_empty() {
+        return None;
+    }
+    Some(Proxy::custom(move |url| {
+        url.host_str().and_then(|host| rules.proxy_for(host))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_for() {
+        let rules = ProxyRules {
+            default: Some(Url::parse("http://proxy.corp:3128").unwrap()),
+            overrides: HashMap::from([(
+                String::from("images.example.com"),
+                Url::parse("socks5://socks.corp:1080").unwrap(),
+            )]),
+            no_proxy: vec![String::from("internal"), String::from("localhost")],
+        };
+        assert_eq!(
+            rules.proxy_for("cdn.example.com").unwrap().as_str(),
+            "http://proxy.corp:3128/"
+        );
+        assert_eq!(
+            rules.proxy_for("Images.Example.com").unwrap().as_str(),
+            "socks5://socks.corp:1080"
+        );
+        assert_eq!(rules.proxy_for("origin.internal"), None);
+        assert_eq!(rules.proxy_for("localhost"), None);
+        assert!(rules.proxy_for("notinternal").is_some());
+    }
+}
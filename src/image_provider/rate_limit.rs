@@ -0,0 +1,258 @@
+// (c) Copyright 2019-2024 OLX
+
+// caps the requests sent to every origin host, so that a burst of requests for images which
+// aren't mirrored or cached yet doesn't hammer the upstream image servers. the requests per second
+// go through a token bucket, which lets a burst of up to a second worth of requests through right
+// away, and the concurrent ones through a semaphore. the requests over the limits wait for their
+// turn, and fail once they would wait longer than `origin_max_wait_secs`. the token of a request
+// which gave up waiting is given back to the following ones.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+
+const DEFAULT_MAX_WAIT_SECS: u64 = 5;
+// the idle hosts are only looked for once there are that many, so that a handful of busy ones
+// aren't scanned on every request
+const MAX_HOSTS_BEFORE_EVICTION: usize = 1024;
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> TokenBucket {
+        Self {
+            tokens: rate,
+            refilled_at: now,
+        }
+    }
+
+    // takes a token and returns how long to wait for it. the tokens go negative while requests
+    // are waiting, which keeps them in line for the next refills.
+    fn reserve(&mut self, rate: f64, now: Instant) -> Duration {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled_at = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    // gives back the token of a request which didn't go through
+    fn refund(&mut self, rate: f64) {
+        self.tokens = (self.tokens + 1.0).min(rate);
+    }
+
+    fn is_full(&self, rate: f64, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens + elapsed * rate >= rate
+    }
+}
+
+#[derive(Default)]
+struct HostLimits {
+    bucket: Option<TokenBucket>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl HostLimits {
+    // the limits of a host nobody is waiting for nor requesting are the same as new ones
+    fn is_idle(&self, rate: Option<f64>, now: Instant) -> bool {
+        let bucket_full = match (&self.bucket, rate) {
+            (Some(bucket), Some(rate)) => bucket.is_full(rate, now),
+            _ => true,
+        };
+        bucket_full
+            && !matches!(&self.concurrency, Some(semaphore) if Arc::strong_count(semaphore) > 1)
+    }
+}
+
+// refunds the reserved token unless the request went through
+struct Reservation<'a> {
+    limiter: &'a OriginRateLimiter,
+    host: &'a str,
+    reserved: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let (Some(rate), true) = (self.limiter.max_requests_per_sec, self.reserved) else {
+            return;
+        };
+        let mut hosts = self.limiter.hosts.lock().unwrap();
+        if let Some(bucket) = hosts
+            .get_mut(self.host)
+            .and_then(|limits| limits.bucket.as_mut())
+        {
+            bucket.refund(rate);
+        }
+    }
+}
+
+// held for as long as the request to the origin is in flight
+pub struct OriginPermit {
+    _concurrency: Option<OwnedSemaphorePermit>,
+}
+
+pub struct OriginRateLimiter {
+    max_requests_per_sec: Option<f64>,
+    max_concurrent_requests: Option<usize>,
+    max_wait: Duration,
+    hosts: Mutex<HashMap<String, HostLimits>>,
+}
+
+impl OriginRateLimiter {
+    pub fn new(config: &Configuration) -> Option<OriginRateLimiter> {
+        let max_requests_per_sec = config
+            .origin_max_requests_per_sec
+            .map(|rate| f64::from(rate.max(1)));
+        let max_concurrent_requests = config.origin_max_concurrent_requests.map(|max| max.max(1));
+        if max_requests_per_sec.is_none() && max_concurrent_requests.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_requests_per_sec,
+            max_concurrent_requests,
+            max_wait: Duration::from_secs(
+                config.origin_max_wait_secs.unwrap_or(DEFAULT_MAX_WAIT_SECS),
+            ),
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn acquire(&self, host: &str) -> Result<OriginPermit, ImageProcessingError> {
+        let started = Instant::now();
+        let (wait, concurrency) = {
+            let mut hosts = self.hosts.lock().unwrap();
+            if hosts.len() >= MAX_HOSTS_BEFORE_EVICTION && !hosts.contains_key(host) {
+                hosts.retain(|_, limits| !limits.is_idle(self.max_requests_per_sec, started));
+            }
+            let limits = hosts.entry(String::from(host)).or_default();
+            let wait = match self.max_requests_per_sec {
+                Some(rate) => limits
+                    .bucket
+                    .get_or_insert_with(|| TokenBucket::new(rate, started))
+                    .reserve(rate, started),
+                None => Duration::ZERO,
+            };
+            let concurrency = self.max_concurrent_requests.map(|max| {
+                limits
+                    .concurrency
+                    .get_or_insert_with(|| Arc::new(Semaphore::new(max)))
+                    .clone()
+            });
+            (wait, concurrency)
+        };
+        // dropped along with the request when the client goes away while it's waiting
+        let mut reservation = Reservation {
+            limiter: self,
+            host,
+            reserved: true,
+        };
+        if wait > self.max_wait {
+            warn!(
+                "the origin '{}' is over its request rate for longer than {:?}",
+                host, self.max_wait
+            );
+            return Err(ImageProcessingError::OriginUnavailable(String::from(host)));
+        }
+        if !wait.is_zero() {
+            debug!(
+                "the origin '{}' is over its request rate, waiting {:?}",
+                host, wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+        let permit = match concurrency {
+            Some(semaphore) => {
+                let deadline = started + self.max_wait;
+                match tokio::time::timeout_at(deadline, semaphore.acquire_owned()).await {
+                    // the semaphores are never closed
+                    Ok(permit) => Some(permit.unwrap()),
+                    Err(_) => {
+                        warn!(
+                            "the origin '{}' is over its concurrent requests for longer than {:?}",
+                            host, self.max_wait
+                        );
+                        return Err(ImageProcessingError::OriginUnavailable(String::from(host)));
+                    }
+                }
+            }
+            None => None,
+        };
+        reservation.reserved = false;
+        Ok(OriginPermit {
+            _concurrency: permit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert_eq!(bucket.reserve(2.0, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(2.0, start), Duration::ZERO);
+        // the burst is spent, the next requests wait for the refills in turn
+        assert_eq!(bucket.reserve(2.0, start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(2.0, start), Duration::from_secs(1));
+        // after a while without requests the bucket is full again, but not fuller
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(2.0, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(2.0, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(2.0, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_token_refund() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        bucket.reserve(2.0, start);
+        bucket.reserve(2.0, start);
+        assert_eq!(bucket.reserve(2.0, start), Duration::from_millis(500));
+        // the request which gave up hands its turn over to the next one
+        bucket.refund(2.0);
+        assert_eq!(bucket.reserve(2.0, start), Duration::from_millis(500));
+        assert!(!bucket.is_full(2.0, start));
+        assert!(bucket.is_full(2.0, start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_idle_hosts() {
+        let start = Instant::now();
+        let semaphore = Arc::new(Semaphore::new(1));
+        let mut limits = HostLimits {
+            bucket: Some(TokenBucket::new(2.0, start)),
+            concurrency: Some(semaphore.clone()),
+        };
+        limits.bucket.as_mut().unwrap().reserve(2.0, start);
+        let later = start + Duration::from_secs(1);
+        // a request is still holding the semaphore
+        assert!(!limits.is_idle(Some(2.0), later));
+        drop(semaphore);
+        assert!(!limits.is_idle(Some(2.0), start));
+        assert!(limits.is_idle(Some(2.0), later));
+        limits.concurrency = None;
+        assert!(limits.is_idle(None, start));
+    }
+}
@@ -0,0 +1,98 @@
+// (c) Copyright 2019-2024 OLX
+
+// keeps the downloaded originals in redis, so the instances sharing it download a remote source
+// only once per ttl. redis being unavailable never fails a request, the images are then fetched
+// from the wrapped provider as if they weren't cached.
+
+use async_trait::async_trait;
+use log::*;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use super::{ImageBuffer, ImageProvider};
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+
+const DEFAULT_TTL_SECS: u64 = 3600;
+const DEFAULT_KEY_PREFIX: &str = "dali:source:";
+const DEFAULT_MAX_OBJECT_BYTES: usize = 8 * 1024 * 1024;
+
+pub struct RedisCachedProvider {
+    inner: Box<dyn ImageProvider>,
+    connection: ConnectionManager,
+    ttl_secs: u64,
+    key_prefix: String,
+    max_object_bytes: usize,
+}
+
+impl RedisCachedProvider {
+    pub async fn new(
+        inner: Box<dyn ImageProvider>,
+        redis_url: &str,
+        config: &Configuration,
+    ) -> RedisCachedProvider {
+        let client = redis::Client::open(redis_url)
+            .unwrap_or_else(|e| panic!("the redis url '{}' is not valid: {}", redis_url, e));
+        let connection = client
+            .get_connection_manager()
+            .await
+            .unwrap_or_else(|e| panic!("failed to connect to redis at '{}': {}", redis_url, e));
+        Self {
+            inner,
+            connection,
+            ttl_secs: config.redis_ttl_secs.unwrap_or(DEFAULT_TTL_SECS),
+            key_prefix: config
+                .redis_key_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KEY_PREFIX.to_string()),
+            max_object_bytes: config
+                .redis_max_object_bytes
+                .map(|max| max as usize)
+                .unwrap_or(DEFAULT_MAX_OBJECT_BYTES),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageProvider for RedisCachedProvider {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        let key = format!("{}{}", self.key_prefix, resource);
+        // the connection manager is a cheap handle over a multiplexed connection
+        let mut connection = self.connection.clone();
+        match connection.get::<_, Option<Vec<u8>>>(&key).await {
+            Ok(Some(cached)) => return Ok(ImageBuffer::from(cached)),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "failed to read the image '{}' from redis. error: {}",
+                key, e
+            ),
+        }
+
+        let image = self.inner.get_file(resource).await?;
+        if image.len() <= self.max_object_bytes {
+            if let Err(e) = connection
+                .set_ex::<_, _, ()>(&key, &image[..], self.ttl_secs)
+                .await
+            {
+                warn!("failed to store the image '{}' in redis. error: {}", key, e);
+            }
+        }
+        Ok(image)
+    }
+
+    // the images are fetched without the cache while redis is down, so it doesn't matter here
+    async fn ready(&self) -> Result<(), String> {
+        self.inner.ready().await
+    }
+
+    async fn evict(&self, resource: &str) {
+        let key = format!("{}{}", self.key_prefix, resource);
+        if let Err(e) = self.connection.clone().del::<_, ()>(&key).await {
+            warn!(
+                "failed to evict the image '{}' from redis. error: {}",
+                key, e
+            );
+        }
+        self.inner.evict(resource).await
+    }
+}
@@ -0,0 +1,185 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod reqwest {
+
+    use std::sync::Arc;
+
+    use ::reqwest::{Client, Method, Url};
+    use async_trait::async_trait;
+    use log::*;
+
+    use crate::commons::clock::SystemClock;
+    use crate::commons::config::Configuration;
+    use crate::image_provider::circuit_breaker::CircuitBreaker;
+    use crate::image_provider::origin::Origins;
+    use crate::image_provider::origin_guard::{self, OriginGuard};
+    use crate::image_provider::origin_headers::OriginHeaders;
+    use crate::image_provider::rate_limit::OriginRateLimiter;
+    use crate::image_provider::ImageProcessingError::{
+        ClientReturnedErrorStatusCode, ForbiddenOrigin, ImageDownloadFailed, ImageDownloadTimedOut,
+        InvalidResourceUriProvided, OriginUnavailable,
+    };
+    use crate::image_provider::{
+        http_client_builder, probe_origins, read_body, ImageBuffer, ImageProvider,
+    };
+    use crate::routes::image::ImageProcessingError;
+
+    // downloads the images straight from the origins, without mirroring them to the public path.
+    // relative image addresses are resolved against `origin_base_urls`.
+    pub struct ReqwestImageProvider {
+        pub client: Client,
+        pub origins: Origins,
+        pub origin_guard: OriginGuard,
+        pub origin_headers: OriginHeaders,
+        pub max_source_size_bytes: Option<u64>,
+        pub circuit_breaker: Option<CircuitBreaker>,
+        pub rate_limiter: Option<OriginRateLimiter>,
+    }
+
+    impl ReqwestImageProvider {
+        pub async fn new(config: &Configuration) -> ReqwestImageProvider {
+            let origin_guard = OriginGuard::new(config);
+            Self {
+                client: http_client_builder(config)
+                    .redirect(origin_guard.redirect_policy())
+                    .dns_resolver(origin_guard.resolver())
+                    .build()
+                    .unwrap(),
+                origin_guard,
+                origin_headers: OriginHeaders::new(config),
+                origins: Origins::new(
+                    config.origin_base_urls.clone().unwrap_or_default(),
+                    config.origin_weights.clone(),
+                ),
+                max_source_size_bytes: config.max_source_size_bytes,
+                circuit_breaker: CircuitBreaker::new(config, Arc::new(SystemClock)),
+                rate_limiter: OriginRateLimiter::new(config),
+            }
+        }
+
+        async fn download(
+            &self,
+            url: &str,
+            resource: &str,
+        ) -> Result<Vec<u8>, ImageProcessingError> {
+            let url = Url::parse(url).map_err(|_| {
+                error!(
+                    "the provided resource uri is not a valid http url: '{}'",
+                    url
+                );
+                InvalidResourceUriProvided(String::from(resource))
+            })?;
+            let host = url.host_str().unwrap_or_default().to_string();
+            if let Some(circuit_breaker) = &self.circuit_breaker {
+                circuit_breaker.check(&host)?;
+            }
+            let _permit = match &self.rate_limiter {
+                Some(rate_limiter) => Some(rate_limiter.acquire(&host).await?),
+                None => None,
+            };
+            let result = self.request(url, resource).await;
+            if let Some(circuit_breaker) = &self.circuit_breaker {
+                circuit_breaker.record(&host, &result);
+            }
+            result
+        }
+
+        async fn request(&self, url: Url, resource: &str) -> Result<Vec<u8>, ImageProcessingError> {
+            let response = self
+                .origin_headers
+                .request(&self.client, Method::GET, &url)
+                .await
+                .send()
+                .await
+                .map_err(|e| {
+                    if e.is_timeout() {
+                        error!(
+                            "request for downloading the image '{}' timed out. error: {}",
+                            url, e
+                        );
+                        ImageDownloadTimedOut
+                    } else if origin_guard::is_blocked(&e) {
+                        ForbiddenOrigin(url.host_str().unwrap_or_default().to_string())
+                    } else {
+                        error!("error downloading the image: '{}'. error: {}", url, e);
+                        ImageDownloadFailed
+                    }
+                })?;
+
+            let status = response.status();
+            if status.is_success() {
+                read_body(response, self.max_source_size_bytes, url.as_str()).await
+            } else if status.is_client_error() {
+                error!(
+                    "the requested image '{}' couldn't be downloaded. received status code: {}",
+                    url, status
+                );
+                Err(ClientReturnedErrorStatusCode(
+                    status.as_u16(),
+                    String::from(resource),
+                ))
+            } else {
+                error!(
+                    "failed to download the image '{}'. received status code: {}",
+                    url,
+                    status.as_str()
+                );
+                Err(ImageDownloadFailed)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ImageProvider for ReqwestImageProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            let urls = if resource.starts_with("http://") || resource.starts_with("https://") {
+                match self.origins.relative_path(resource) {
+                    Some(path) => self.origins.candidates(path),
+                    None => {
+                        let url = Url::parse(resource).map_err(|_| {
+                            error!(
+                                "the provided resource uri is not a valid http url: '{}'",
+                                resource
+                            );
+                            InvalidResourceUriProvided(String::from(resource))
+                        })?;
+                        self.origin_guard.check(&url).await?;
+                        vec![String::from(resource)]
+                    }
+                }
+            } else if !self.origins.is_empty() {
+                self.origins.candidates(resource)
+            } else {
+                error!(
+                    "the relative resource '{}' can't be downloaded without origin_base_urls",
+                    resource
+                );
+                return Err(InvalidResourceUriProvided(String::from(resource)));
+            };
+
+            let mut last_error = ImageDownloadFailed;
+            for (i, url) in urls.iter().enumerate() {
+                match self.download(url, resource).await {
+                    Ok(body) => return Ok(ImageBuffer::from(body)),
+                    Err(
+                        e @ (ImageDownloadTimedOut | ImageDownloadFailed | OriginUnavailable(_)),
+                    ) => {
+                        if i + 1 < urls.len() {
+                            warn!(
+                                "failed to download the image '{}' from '{}', falling back to the next origin. error: {}",
+                                resource, url, e
+                            );
+                        }
+                        last_error = e;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(last_error)
+        }
+
+        async fn ready(&self) -> Result<(), String> {
+            probe_origins(&self.client, self.origins.base_urls()).await
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// (c) Copyright 2019-2024 OLX
+
+// dispatches every resource to the provider routed for the longest matching prefix of its
+// address, so that a single instance can serve images from several storage backends. the scheme
+// prefixes like `s3://` are removed before handing the address to the provider, while the http
+// schemes and the path prefixes are kept as they are part of the address.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::{ImageBuffer, ImageProvider};
+use crate::routes::image::ImageProcessingError;
+
+pub struct ProviderRouter {
+    routes: Vec<(String, Arc<dyn ImageProvider>)>,
+    default: Arc<dyn ImageProvider>,
+}
+
+impl ProviderRouter {
+    pub fn new(
+        mut routes: Vec<(String, Arc<dyn ImageProvider>)>,
+        default: Arc<dyn ImageProvider>,
+    ) -> Self {
+        routes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        Self { routes, default }
+    }
+
+    fn route<'a>(&self, resource: &'a str) -> (&Arc<dyn ImageProvider>, &'a str) {
+        for (prefix, provider) in &self.routes {
+            if let Some(rest) = resource.strip_prefix(prefix.as_str()) {
+                let is_stripped_scheme =
+                    prefix.ends_with("://") && prefix != "http://" && prefix != "https://";
+                return (provider, if is_stripped_scheme { rest } else { resource });
+            }
+        }
+        (&self.default, resource)
+    }
+}
+
+#[async_trait]
+impl ImageProvider for ProviderRouter {
+    async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+        let (provider, resource) = self.route(resource);
+        provider.get_file(resource).await
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        for (_, provider) in &self.routes {
+            provider.ready().await?;
+        }
+        self.default.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers with its name followed by the resource it was asked for
+    struct NamedProvider(&'static str);
+
+    #[async_trait]
+    impl ImageProvider for NamedProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            Ok(ImageBuffer::from(
+                format!("{}:{}", self.0, resource).into_bytes(),
+            ))
+        }
+    }
+
+    async fn fetch(router: &ProviderRouter, resource: &str) -> String {
+        let buffer = router.get_file(resource).await.unwrap();
+        String::from_utf8(buffer.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let router = ProviderRouter::new(
+            vec![
+                ("s3://".to_string(), Arc::new(NamedProvider("s3"))),
+                ("https://".to_string(), Arc::new(NamedProvider("http"))),
+                ("/gcs/".to_string(), Arc::new(NamedProvider("gcs"))),
+                ("/gcs/local/".to_string(), Arc::new(NamedProvider("file"))),
+            ],
+            Arc::new(NamedProvider("default")),
+        );
+        assert_eq!(fetch(&router, "s3://img/a.jpg").await, "s3:img/a.jpg");
+        assert_eq!(
+            fetch(&router, "https://cdn.com/a.jpg").await,
+            "http:https://cdn.com/a.jpg"
+        );
+        assert_eq!(fetch(&router, "/gcs/a.jpg").await, "gcs:/gcs/a.jpg");
+        assert_eq!(
+            fetch(&router, "/gcs/local/a.jpg").await,
+            "file:/gcs/local/a.jpg"
+        );
+        assert_eq!(fetch(&router, "a.jpg").await, "default:a.jpg");
+    }
+}
@@ -0,0 +1,167 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod s3 {
+
+    use async_trait::async_trait;
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+    use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+    use aws_sdk_s3::Client;
+    use log::*;
+
+    use crate::commons::config::Configuration;
+    use crate::image_provider::ImageProcessingError::{
+        ClientReturnedErrorStatusCode, ImageDownloadFailed, ImageDownloadTimedOut, SourceTooLarge,
+    };
+    use crate::image_provider::{ImageBuffer, ImageProvider, DEFAULT_MAX_SOURCE_SIZE_BYTES};
+    use crate::routes::image::ImageProcessingError;
+
+    // the object fetched by the readiness probe, which isn't expected to exist
+    const READINESS_PROBE_KEY: &str = ".dali-readyz";
+
+    pub struct S3ImageProvider {
+        pub client: Client,
+        pub bucket: String,
+        pub prefix: Option<String>,
+        pub max_source_size_bytes: Option<u64>,
+    }
+
+    impl S3ImageProvider {
+        pub async fn new(config: &Configuration) -> S3ImageProvider {
+            let bucket = config
+                .s3_bucket
+                .clone()
+                .expect("the s3_bucket configuration is required by the S3 image provider");
+            Self {
+                client: create_client(config).await,
+                bucket,
+                prefix: config.s3_prefix.clone(),
+                max_source_size_bytes: config.max_source_size_bytes,
+            }
+        }
+
+        fn object_key(&self, resource: &str) -> String {
+            let resource = resource.trim_start_matches('/');
+            match &self.prefix {
+                Some(prefix) => format!("{}/{}", prefix.trim_end_matches('/'), resource),
+                None => resource.to_string(),
+            }
+        }
+    }
+
+    // the client is shared with the S3 image sink. without the static keys of the configuration,
+    // the credentials come from the default AWS chain (environment, profile, web identity, ECS or
+    // EC2 instance metadata), whose temporary credentials are refreshed before they expire
+    pub async fn create_client(config: &Configuration) -> Client {
+        let region = config
+            .s3_region
+            .clone()
+            .expect("the s3_region configuration is required by the S3 image provider");
+        let mut loader =
+            aws_config::defaults(BehaviorVersion::latest()).region(Region::new(region));
+        if let (Some(key), Some(secret)) = (&config.s3_key, &config.s3_secret) {
+            loader = loader.credentials_provider(Credentials::new(
+                key,
+                secret,
+                None,
+                None,
+                "dali-configuration",
+            ));
+        }
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&loader.load().await);
+        if let Some(endpoint) = &config.s3_endpoint {
+            // MinIO only serves the path style urls
+            s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Client::from_conf(s3_config.build())
+    }
+
+    #[async_trait]
+    impl ImageProvider for S3ImageProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            let key = self.object_key(resource);
+            let output = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(|e| match e {
+                    SdkError::ServiceError(ref service_error)
+                        if service_error.err().is_no_such_key() =>
+                    {
+                        error!(
+                            "the requested image '{}' doesn't exist in the bucket '{}'",
+                            key, self.bucket
+                        );
+                        ClientReturnedErrorStatusCode(404, String::from(resource))
+                    }
+                    SdkError::TimeoutError(_) => {
+                        error!("request for downloading the image '{}' timed out", key);
+                        ImageDownloadTimedOut
+                    }
+                    e => {
+                        error!("error downloading the image: '{}'. error: {}", key, e);
+                        ImageDownloadFailed
+                    }
+                })?;
+
+            let max_size = self
+                .max_source_size_bytes
+                .unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+            if let Some(length) = output.content_length() {
+                if length > 0 && length as u64 > max_size {
+                    error!(
+                        "the image '{}' has {} bytes which exceeds the limit of {} bytes",
+                        key, length, max_size
+                    );
+                    return Err(SourceTooLarge(max_size));
+                }
+            }
+
+            // the body is streamed so that an object without a content length which exceeds the
+            // limit is dropped as soon as it crosses it
+            let mut stream = output.body;
+            let mut body = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| {
+                    error!(
+                        "failed to read the binary payload of the image '{}'. error: {}",
+                        key, e
+                    );
+                    ImageDownloadFailed
+                })?;
+                if (body.len() + chunk.len()) as u64 > max_size {
+                    error!(
+                        "the image '{}' exceeds the limit of {} bytes",
+                        key, max_size
+                    );
+                    return Err(SourceTooLarge(max_size));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(ImageBuffer::from(body))
+        }
+
+        // only reading the objects may be granted, so a missing object is fetched: S3 answers
+        // that it doesn't exist, or that it's denied without the permission to list the bucket,
+        // only when the credentials are valid
+        async fn ready(&self) -> Result<(), String> {
+            let result = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(READINESS_PROBE_KEY))
+                .send()
+                .await;
+            match result {
+                Ok(_) => Ok(()),
+                Err(e) if matches!(e.code(), Some("NoSuchKey") | Some("AccessDenied")) => Ok(()),
+                Err(e) => Err(format!(
+                    "the bucket '{}' can't be reached: {}",
+                    self.bucket, e
+                )),
+            }
+        }
+    }
+}
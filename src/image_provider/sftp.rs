@@ -0,0 +1,362 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod sftp {
+
+    use std::error::Error;
+    use std::io::Read;
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use log::*;
+    use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+    use tokio::sync::Semaphore;
+
+    type SshResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+    use crate::commons::config::Configuration;
+    use crate::image_provider::ImageProcessingError::{
+        ClientReturnedErrorStatusCode, ImageDownloadFailed, InvalidResourceUriProvided,
+        ProcessingWorkerJoinError, SourceTooLarge,
+    };
+    use crate::image_provider::{ImageBuffer, ImageProvider, DEFAULT_MAX_SOURCE_SIZE_BYTES};
+    use crate::routes::image::ImageProcessingError;
+
+    // the status code of the SFTP protocol for the files which don't exist
+    const SFTP_NO_SUCH_FILE: i32 = 2;
+    const DEFAULT_POOL_SIZE: usize = 4;
+
+    struct Connection {
+        // the session has to outlive the sftp channel opened through it
+        _session: Session,
+        sftp: Sftp,
+    }
+
+    struct Settings {
+        host: String,
+        port: u16,
+        username: String,
+        password: Option<String>,
+        private_key_path: Option<String>,
+        known_hosts_path: Option<String>,
+        timeout_millis: u32,
+    }
+
+    // reads the images from an SFTP server. libssh2 is blocking, so the reads run on the blocking
+    // thread pool, each one over a connection of its own. up to `sftp_pool_size` connections are
+    // open at a time, the idle ones are reused and the broken ones are dropped.
+    pub struct SftpImageProvider {
+        settings: Arc<Settings>,
+        base_path: PathBuf,
+        max_source_size_bytes: Option<u64>,
+        idle_connections: Arc<Mutex<Vec<Connection>>>,
+        permits: Arc<Semaphore>,
+    }
+
+    impl SftpImageProvider {
+        pub async fn new(config: &Configuration) -> SftpImageProvider {
+            let settings = Settings {
+                host: config
+                    .sftp_host
+                    .clone()
+                    .expect("the sftp_host configuration is required by the SFTP image provider"),
+                port: config.sftp_port.unwrap_or(22),
+                username: config.sftp_username.clone().expect(
+                    "the sftp_username configuration is required by the SFTP image provider",
+                ),
+                password: config.sftp_password.clone(),
+                private_key_path: config.sftp_private_key_path.clone(),
+                known_hosts_path: config.sftp_known_hosts_path.clone(),
+                timeout_millis: config.sftp_timeout_millis.unwrap_or(5000),
+            };
+            if settings.password.is_none() && settings.private_key_path.is_none() {
+                panic!("either sftp_password or sftp_private_key_path is required by the SFTP image provider");
+            }
+            if settings.known_hosts_path.is_none() {
+                warn!("sftp_known_hosts_path is not configured, the host key of the SFTP server won't be verified");
+            }
+            Self {
+                settings: Arc::new(settings),
+                base_path: PathBuf::from(config.sftp_base_path.clone().unwrap_or_default()),
+                max_source_size_bytes: config.max_source_size_bytes,
+                idle_connections: Arc::new(Mutex::new(vec![])),
+                permits: Arc::new(Semaphore::new(
+                    config.sftp_pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1),
+                )),
+            }
+        }
+
+        // the resources are always resolved within the base path
+        fn remote_path(&self, resource: &str) -> Result<PathBuf, ImageProcessingError> {
+            let relative = Path::new(resource.trim_start_matches('/'));
+            if relative
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                error!("the resource '{}' is not a valid SFTP path", resource);
+                return Err(InvalidResourceUriProvided(String::from(resource)));
+            }
+            Ok(self.base_path.join(relative))
+        }
+    }
+
+    // the session timeout only applies once the TCP connection is established, an unresponsive
+    // server would otherwise hold a connection of the pool for as long as the system lets it
+    fn connect_tcp(settings: &Settings) -> SshResult<TcpStream> {
+        let timeout = Duration::from_millis(u64::from(settings.timeout_millis.max(1)));
+        let mut last_error = None;
+        for address in (settings.host.as_str(), settings.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) => e.into(),
+            None => format!("'{}' doesn't resolve to any address", settings.host).into(),
+        })
+    }
+
+    fn connect(settings: &Settings) -> SshResult<Connection> {
+        let tcp = connect_tcp(settings)?;
+        let mut session = Session::new()?;
+        session.set_timeout(settings.timeout_millis);
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        if let Some(known_hosts_path) = &settings.known_hosts_path {
+            let mut known_hosts = session.known_hosts()?;
+            known_hosts.read_file(Path::new(known_hosts_path), KnownHostFileKind::OpenSSH)?;
+            let (key, _) = session
+                .host_key()
+                .ok_or("the SFTP server didn't send its host key")?;
+            match known_hosts.check_port(&settings.host, settings.port, key) {
+                CheckResult::Match => {}
+                result => {
+                    return Err(format!(
+                        "the host key of '{}' can't be verified: {:?}",
+                        settings.host, result
+                    )
+                    .into())
+                }
+            }
+        }
+
+        match &settings.private_key_path {
+            Some(private_key_path) => session.userauth_pubkey_file(
+                &settings.username,
+                None,
+                Path::new(private_key_path),
+                settings.password.as_deref(),
+            )?,
+            None => session.userauth_password(
+                &settings.username,
+                settings.password.as_deref().unwrap_or_default(),
+            )?,
+        }
+        let sftp = session.sftp()?;
+        Ok(Connection {
+            _session: session,
+            sftp,
+        })
+    }
+
+    fn read(
+        sftp: &Sftp,
+        path: &Path,
+        max_size: u64,
+    ) -> SshResult<Result<Vec<u8>, ImageProcessingError>> {
+        let mut file = match sftp.open(path) {
+            Ok(file) => file,
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => {
+                error!("the requested image '{}' doesn't exist", path.display());
+                return Ok(Err(ClientReturnedErrorStatusCode(
+                    404,
+                    path.display().to_string(),
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(size) = file.stat()?.size.filter(|size| *size > max_size) {
+            error!(
+                "the image '{}' has {} bytes which exceeds the limit of {} bytes",
+                path.display(),
+                size,
+                max_size
+            );
+            return Ok(Err(SourceTooLarge(max_size)));
+        }
+        // reading one byte past the limit tells apart the files which grew since the stat
+        let mut body = vec![];
+        (&mut file)
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut body)?;
+        if body.len() as u64 > max_size {
+            return Ok(Err(SourceTooLarge(max_size)));
+        }
+        Ok(Ok(body))
+    }
+
+    // a broken connection is reopened once before giving up on the image
+    fn read_with_reconnect(
+        settings: &Settings,
+        idle_connections: &Mutex<Vec<Connection>>,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<Vec<u8>, ImageProcessingError> {
+        let mut idle = idle_connections.lock().unwrap().pop();
+        for attempt in 0..2 {
+            let connection = match idle.take() {
+                Some(connection) => connection,
+                None => match connect(settings) {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!(
+                            "failed to connect to the SFTP server '{}'. error: {}",
+                            settings.host, e
+                        );
+                        return Err(ImageDownloadFailed);
+                    }
+                },
+            };
+            match read(&connection.sftp, path, max_size) {
+                Ok(result) => {
+                    idle_connections.lock().unwrap().push(connection);
+                    return result;
+                }
+                Err(e) => warn!(
+                    "failed to read the image '{}' over SFTP (attempt {}). error: {}",
+                    path.display(),
+                    attempt + 1,
+                    e
+                ),
+            }
+        }
+        Err(ImageDownloadFailed)
+    }
+
+    #[async_trait]
+    impl ImageProvider for SftpImageProvider {
+        async fn get_file(&self, resource: &str) -> Result<ImageBuffer, ImageProcessingError> {
+            let path = self.remote_path(resource)?;
+            let settings = self.settings.clone();
+            let idle_connections = self.idle_connections.clone();
+            let max_size = self
+                .max_source_size_bytes
+                .unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+            let permit = self.permits.clone().acquire_owned().await.map_err(|e| {
+                error!("failed to wait for an SFTP connection. error: {}", e);
+                ImageDownloadFailed
+            })?;
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                read_with_reconnect(&settings, &idle_connections, &path, max_size)
+            })
+            .await
+            .map_err(|e| {
+                error!("failed to join the SFTP read. error: {}", e);
+                ProcessingWorkerJoinError
+            })?
+            .map(ImageBuffer::from)
+        }
+
+        async fn ready(&self) -> Result<(), String> {
+            let settings = self.settings.clone();
+            let idle_connections = self.idle_connections.clone();
+            let base_path = self.base_path.clone();
+            let permit = self
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|e| format!("failed to wait for an SFTP connection: {}", e))?;
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let idle = idle_connections.lock().unwrap().pop();
+                let connection = match idle {
+                    Some(connection) => connection,
+                    None => connect(&settings).map_err(|e| {
+                        format!(
+                            "the SFTP server '{}' can't be reached: {}",
+                            settings.host, e
+                        )
+                    })?,
+                };
+                let path = if base_path.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    base_path.as_path()
+                };
+                // a failing connection isn't returned to the pool, the next read opens a new one
+                connection.sftp.stat(path).map_err(|e| {
+                    format!("'{}' can't be accessed over SFTP: {}", path.display(), e)
+                })?;
+                idle_connections.lock().unwrap().push(connection);
+                Ok(())
+            })
+            .await
+            .map_err(|e| format!("failed to join the SFTP probe: {}", e))?
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::net::TcpListener;
+        use std::time::Instant;
+
+        use serde_json::json;
+
+        use super::*;
+
+        async fn provider() -> SftpImageProvider {
+            let config = Configuration::for_tests(json!({
+                "sftp_host": "127.0.0.1",
+                "sftp_username": "dali",
+                "sftp_password": "secret",
+                "sftp_base_path": "/images",
+                "sftp_timeout_millis": 200,
+                "sftp_pool_size": 2,
+            }));
+            SftpImageProvider::new(&config).await
+        }
+
+        #[tokio::test]
+        async fn test_config_and_paths() {
+            let provider = provider().await;
+            assert_eq!(provider.settings.port, 22);
+            assert_eq!(provider.settings.timeout_millis, 200);
+            assert_eq!(provider.permits.available_permits(), 2);
+            assert_eq!(
+                provider.remote_path("/a/b.jpg").unwrap(),
+                PathBuf::from("/images/a/b.jpg")
+            );
+            assert!(provider.remote_path("a/../../etc/passwd").is_err());
+            assert!(provider.remote_path("./a.jpg").is_err());
+        }
+
+        #[tokio::test]
+        async fn test_unreachable_server() {
+            let provider = provider().await;
+            // nothing listens on the port once the listener is dropped
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let settings = Settings {
+                port,
+                host: provider.settings.host.clone(),
+                username: provider.settings.username.clone(),
+                password: None,
+                private_key_path: None,
+                known_hosts_path: None,
+                timeout_millis: provider.settings.timeout_millis,
+            };
+            let started = Instant::now();
+            assert!(connect_tcp(&settings).is_err());
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+}
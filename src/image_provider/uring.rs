@@ -0,0 +1,99 @@
+// (c) Copyright 2019-2024 OLX
+
+// io_uring needs its own runtime, so every file operation is shipped to a dedicated thread
+// that runs `tokio_uring` and the result is sent back over a oneshot channel.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use axum::body::Bytes;
+use lazy_static::lazy_static;
+use log::*;
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::fs::File;
+
+enum Job {
+    Read(PathBuf, oneshot::Sender<io::Result<Vec<u8>>>),
+    Write(PathBuf, Bytes, oneshot::Sender<io::Result<()>>),
+}
+
+lazy_static! {
+    static ref JOBS: mpsc::UnboundedSender<Job> = spawn_worker();
+}
+
+fn spawn_worker() -> mpsc::UnboundedSender<Job> {
+    let (send, mut recv) = mpsc::unbounded_channel::<Job>();
+    std::thread::Builder::new()
+        .name("dali-io-uring".to_string())
+        .spawn(move || {
+            tokio_uring::start(async move {
+                while let Some(job) = recv.recv().await {
+                    tokio_uring::spawn(async move {
+                        match job {
+                            Job::Read(path, reply) => {
+                                let _ = reply.send(read(path).await);
+                            }
+                            Job::Write(path, bytes, reply) => {
+                                let _ = reply.send(write(path, bytes).await);
+                            }
+                        }
+                    });
+                }
+            })
+        })
+        .expect("failed to spawn the io_uring worker thread");
+    info!("io_uring worker thread started");
+    send
+}
+
+fn worker_unavailable() -> io::Error {
+    io::Error::other("the io_uring worker is not running")
+}
+
+async fn read(path: PathBuf) -> io::Result<Vec<u8>> {
+    let len = std::fs::metadata(&path)?.len() as usize;
+    let file = File::open(&path).await?;
+    let mut buffer = Vec::with_capacity(len);
+    let mut read = 0;
+    while read < len {
+        let (result, slice) = file.read_at(buffer.slice(read..), read as u64).await;
+        buffer = slice.into_inner();
+        match result? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    file.close().await?;
+    Ok(buffer)
+}
+
+async fn write(path: PathBuf, bytes: Bytes) -> io::Result<()> {
+    let file = File::create(&path).await?;
+    let len = bytes.len();
+    let mut buffer = bytes;
+    let mut written = 0;
+    while written < len {
+        let (result, slice) = file.write_at(buffer.slice(written..), written as u64).await;
+        buffer = slice.into_inner();
+        match result? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    file.close().await
+}
+
+pub async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let (send, recv) = oneshot::channel();
+    JOBS.send(Job::Read(path.to_path_buf(), send))
+        .map_err(|_| worker_unavailable())?;
+    recv.await.map_err(|_| worker_unavailable())?
+}
+
+pub async fn write_file(path: &Path, bytes: Bytes) -> io::Result<()> {
+    let (send, recv) = oneshot::channel();
+    JOBS.send(Job::Write(path.to_path_buf(), bytes, send))
+        .map_err(|_| worker_unavailable())?;
+    recv.await.map_err(|_| worker_unavailable())?
+}
@@ -0,0 +1,161 @@
+// (c) Copyright 2019-2024 OLX
+
+// the ETag and Last-Modified validators an origin sent along with an image are kept in a file
+// next to its mirrored copy. once the copy is older than `mirror_revalidate_after_secs` it's
+// revalidated with a conditional request, and the modification time of the validators file tells
+// when that last happened.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ::reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use ::reqwest::RequestBuilder;
+
+use crate::commons::clock::Clock;
+use crate::commons::filesystem::FileSystem;
+
+const ETAG_PREFIX: &str = "etag: ";
+const LAST_MODIFIED_PREFIX: &str = "last-modified: ";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub fn validators_path(path: &Path) -> PathBuf {
+    let mut validators = OsString::from(path.as_os_str());
+    validators.push(".validators");
+    PathBuf::from(validators)
+}
+
+// the mirrored copies without validators, e.g. the ones mirrored before the revalidation was
+// enabled, were last validated when they were written
+pub async fn needs_revalidation(
+    filesystem: &dyn FileSystem,
+    clock: &dyn Clock,
+    path: &Path,
+    revalidate_after: Duration,
+) -> bool {
+    let validated_at = match filesystem.modified(&validators_path(path)).await {
+        Ok(validated_at) => validated_at,
+        Err(_) => match filesystem.modified(path).await {
+            Ok(written_at) => written_at,
+            Err(_) => return true,
+        },
+    };
+    clock.now() >= validated_at + revalidate_after
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Validators {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    fn parse(content: &str) -> Validators {
+        let mut validators = Validators::default();
+        for line in content.lines() {
+            if let Some(etag) = line.strip_prefix(ETAG_PREFIX) {
+                validators.etag = Some(String::from(etag));
+            } else if let Some(last_modified) = line.strip_prefix(LAST_MODIFIED_PREFIX) {
+                validators.last_modified = Some(String::from(last_modified));
+            }
+        }
+        validators
+    }
+
+    fn serialize(&self) -> String {
+        let mut content = String::new();
+        if let Some(etag) = &self.etag {
+            content.push_str(&format!("{}{}\n", ETAG_PREFIX, etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            content.push_str(&format!("{}{}\n", LAST_MODIFIED_PREFIX, last_modified));
+        }
+        content
+    }
+
+    pub async fn load(path: &Path) -> Option<Validators> {
+        let content = tokio::fs::read_to_string(validators_path(path))
+            .await
+            .ok()?;
+        Some(Validators::parse(&content))
+    }
+
+    // (re)writing the file also marks the mirrored copy as just validated
+    pub async fn store(&self, path: &Path) -> io::Result<()> {
+        tokio::fs::write(validators_path(path), self.serialize()).await
+    }
+
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::clock::FixedClock;
+    use crate::commons::filesystem::InMemoryFileSystem;
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_serialize_and_parse() {
+        let validators = Validators {
+            etag: Some(String::from("\"5d8c72a5edda8\"")),
+            last_modified: Some(String::from("Tue, 15 Nov 1994 12:45:26 GMT")),
+        };
+        assert_eq!(Validators::parse(&validators.serialize()), validators);
+        assert_eq!(Validators::parse(""), Validators::default());
+    }
+
+    #[tokio::test]
+    async fn test_needs_revalidation() {
+        let filesystem = InMemoryFileSystem {
+            files: HashMap::from([
+                (PathBuf::from("/public/validated.jpg"), at(100)),
+                (PathBuf::from("/public/validated.jpg.validators"), at(1_000)),
+                (PathBuf::from("/public/legacy.jpg"), at(100)),
+            ]),
+        };
+        let clock = FixedClock(at(1_500));
+        let after = Duration::from_secs(600);
+        assert!(
+            !needs_revalidation(
+                &filesystem,
+                &clock,
+                Path::new("/public/validated.jpg"),
+                after
+            )
+            .await
+        );
+        assert!(
+            needs_revalidation(&filesystem, &clock, Path::new("/public/legacy.jpg"), after).await
+        );
+        assert!(
+            needs_revalidation(&filesystem, &clock, Path::new("/public/missing.jpg"), after).await
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod file {
+
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use async_trait::async_trait;
+    use axum::body::Bytes;
+    use log::*;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::commons::config::Configuration;
+    use crate::commons::local_path::contained_path;
+    use crate::image_sink::{location, ImageSink};
+    use crate::routes::image::ImageProcessingError;
+    use crate::routes::image::ImageProcessingError::{PathOutsideRoot, StoreFailed};
+
+    pub struct FileImageSink {
+        pub path: String,
+        pub base_url: Option<String>,
+    }
+
+    impl FileImageSink {
+        // the sink must not share its files with the sources, which anyone able to store an
+        // image would otherwise overwrite, along with the validators and the tombstones
+        pub fn new(config: &Configuration) -> FileImageSink {
+            let path = config
+                .sink_path
+                .clone()
+                .expect("the `file` sink requires `sink_path` to be configured");
+            if overlaps(&path, &config.public_img_path) {
+                panic!(
+                    "the `sink_path` '{}' overlaps the `public_img_path` '{}'",
+                    path, config.public_img_path
+                );
+            }
+            Self {
+                path,
+                base_url: config.sink_base_url.clone(),
+            }
+        }
+    }
+
+    // whether either directory is within the other one, once the symlinks are resolved
+    fn overlaps(a: &str, b: &str) -> bool {
+        let resolve =
+            |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let (a, b) = (resolve(a), resolve(b));
+        a.starts_with(&b) || b.starts_with(&a)
+    }
+
+    static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // the image is written aside then renamed into place, as the files may be memory mapped by
+    // the readers, which a truncation in place would crash
+    async fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}.{}.tmp",
+            file_name,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+                .await?;
+            file.write_all(bytes).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp_path, path).await
+        };
+        let result = written.await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+
+    #[async_trait]
+    impl ImageSink for FileImageSink {
+        async fn store(
+            &self,
+            key: &str,
+            bytes: Bytes,
+            _content_type: &str,
+        ) -> Result<String, ImageProcessingError> {
+            let path = contained_path(&self.path, key).ok_or_else(|| {
+                error!("the key '{}' resolves outside of '{}'", key, self.path);
+                PathOutsideRoot(String::from(key))
+            })?;
+            let written = async {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                write_atomically(&path, &bytes).await
+            };
+            if let Err(e) = written.await {
+                error!(
+                    "failed to store the processed image to '{}'. error: {}",
+                    path.display(),
+                    e
+                );
+                return Err(StoreFailed(String::from(key)));
+            }
+            Ok(location(
+                self.base_url.as_deref(),
+                key.trim_start_matches('/'),
+                path.display().to_string(),
+            ))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_overlaps() {
+            assert!(overlaps("/data/public", "/data/public"));
+            assert!(overlaps("/data/public/variants", "/data/public"));
+            assert!(overlaps("/data", "/data/public"));
+            assert!(!overlaps("/data/variants", "/data/public"));
+            assert!(!overlaps("/data/public-variants", "/data/public"));
+        }
+
+        #[tokio::test]
+        async fn test_write_atomically() {
+            let dir = std::env::temp_dir().join(format!("dali-sink-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("a.jpg");
+            write_atomically(&path, b"first").await.unwrap();
+            write_atomically(&path, b"second").await.unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), b"second");
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// (c) Copyright 2019-2024 OLX
+
+// the storages the processed images are written to by the `/store` endpoint, which turns dali
+// into a tool generating the variants of the images ahead of time.
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+
+pub mod file;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+#[async_trait]
+pub trait ImageSink: Send + Sync {
+    // stores the image under the key and returns where it can be found
+    async fn store(
+        &self,
+        key: &str,
+        bytes: Bytes,
+        content_type: &str,
+    ) -> Result<String, ImageProcessingError>;
+}
+
+// the location of a stored image, relative to the public base url when there's one
+pub fn location(base_url: Option<&str>, key: &str, fallback: String) -> String {
+    match base_url {
+        Some(base_url) => format!("{}/{}", base_url.trim_end_matches('/'), key),
+        None => fallback,
+    }
+}
+
+pub async fn create_image_sink(config: &Configuration) -> Option<Box<dyn ImageSink>> {
+    match config.sink.as_deref()? {
+        #[cfg(feature = "s3")]
+        "s3" => Some(Box::new(s3::s3::S3ImageSink::new(config).await)),
+        "file" => Some(Box::new(file::file::FileImageSink::new(config))),
+        sink => panic!(
+            "the image sink '{}' is not supported by this build of dali",
+            sink
+        ),
+    }
+}
@@ -0,0 +1,79 @@
+// (c) Copyright 2019-2024 OLX
+
+pub mod s3 {
+
+    use async_trait::async_trait;
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::Client;
+    use axum::body::Bytes;
+    use log::*;
+
+    use crate::commons::config::Configuration;
+    use crate::image_provider::s3::s3::create_client;
+    use crate::image_sink::{location, ImageSink};
+    use crate::routes::image::ImageProcessingError;
+    use crate::routes::image::ImageProcessingError::StoreFailed;
+
+    pub struct S3ImageSink {
+        pub client: Client,
+        pub bucket: String,
+        pub prefix: Option<String>,
+        pub base_url: Option<String>,
+    }
+
+    impl S3ImageSink {
+        pub async fn new(config: &Configuration) -> S3ImageSink {
+            let bucket = config
+                .sink_s3_bucket
+                .clone()
+                .or_else(|| config.s3_bucket.clone())
+                .expect("either sink_s3_bucket or s3_bucket is required by the S3 image sink");
+            Self {
+                client: create_client(config).await,
+                bucket,
+                prefix: config.sink_s3_prefix.clone(),
+                base_url: config.sink_base_url.clone(),
+            }
+        }
+
+        fn object_key(&self, key: &str) -> String {
+            let key = key.trim_start_matches('/');
+            match &self.prefix {
+                Some(prefix) => format!("{}/{}", prefix.trim_end_matches('/'), key),
+                None => key.to_string(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ImageSink for S3ImageSink {
+        async fn store(
+            &self,
+            key: &str,
+            bytes: Bytes,
+            content_type: &str,
+        ) -> Result<String, ImageProcessingError> {
+            let object_key = self.object_key(key);
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&object_key)
+                .content_type(content_type)
+                .body(ByteStream::from(bytes))
+                .send()
+                .await
+                .map_err(|e| {
+                    error!(
+                        "failed to store the processed image '{}' in the bucket '{}'. error: {}",
+                        object_key, self.bucket, e
+                    );
+                    StoreFailed(String::from(key))
+                })?;
+            Ok(location(
+                self.base_url.as_deref(),
+                &object_key,
+                format!("s3://{}/{}", self.bucket, object_key),
+            ))
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// (c) Copyright 2019-2024 OLX
+
+// dali-core: the request parameter model and the geometry computations shared with the service,
+// free of libvips so that it can be compiled to WebAssembly. with the `pure-rust` feature it also
+// provides a pure Rust implementation of a subset of the processing pipeline.
+//
+// cargo build --lib --target wasm32-unknown-unknown --no-default-features --features pure-rust
+
+#[path = "commons/errors.rs"]
+pub mod errors;
+#[path = "commons/model.rs"]
+pub mod model;
+#[cfg(feature = "pure-rust")]
+pub mod pure;
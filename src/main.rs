@@ -2,33 +2,65 @@ use std::env;
 use std::sync::Arc;
 use std::time::SystemTime;
 
-use axum::extract::Request;
+use axum::extract::{DefaultBodyLimit, Request};
 use axum::http::StatusCode;
 use axum::middleware::{self, Next};
 use axum::response::IntoResponse;
-use axum::{routing::get, Router};
+use axum::{
+    routing::{delete, get, post},
+    Router,
+};
 use image_provider::{create_image_provider, ImageProvider};
+use image_sink::{create_image_sink, ImageSink};
 use libvips::VipsApp;
+use log::*;
 
+use commons::clock::{Clock, SystemClock};
 use commons::config::Configuration;
+use commons::filesystem::{FileSystem, LocalFileSystem};
+use commons::hardware::HardwareInfo;
 use routes::metric::HTTP_DURATION;
 
 // (c) Copyright 2019-2024 OLX
 mod commons;
 mod image_processor;
 mod image_provider;
+mod image_sink;
 mod routes;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+const DEFAULT_MAX_UPLOAD_SIZE_BYTES: usize = 20 * 1024 * 1024;
+
 #[tokio::main(worker_threads = 1)]
 async fn main() {
-    let config = Configuration::new().expect("Failed to load application configuration.");
+    let config = Arc::new(Configuration::new().expect("Failed to load application configuration."));
     println!(r#"{{"configuration": {}}}"#, config);
 
     set_up_logging(&config);
-    let (_, _) = tokio::join!(start_main_server(&config), start_management_server(&config));
+    let vips_app = Arc::new(create_vips_app(&config).unwrap());
+    let hardware_info = Arc::new(commons::hardware::detect(&vips_app));
+    let filesystem: Arc<dyn FileSystem> = Arc::new(LocalFileSystem);
+    let image_provider = Arc::new(create_image_provider(&config, filesystem.clone()).await);
+    // an unreachable storage backend doesn't prevent the startup, the readiness probe reports it
+    match image_provider.ready().await {
+        Ok(()) => info!("the image provider is ready"),
+        Err(reason) => warn!("the image provider is not ready. reason: {}", reason),
+    }
+    let app_state = AppState {
+        vips_app,
+        image_provider: image_provider.clone(),
+        image_sink: create_image_sink(&config).await.map(Arc::from),
+        public_img_path: Arc::new(config.public_img_path.clone()),
+        config: config.clone(),
+        filesystem,
+        clock: Arc::new(SystemClock),
+    };
+    let (_, _) = tokio::join!(
+        start_main_server(config.clone(), app_state.clone()),
+        start_management_server(config.clone(), hardware_info, image_provider, app_state)
+    );
 }
 
 fn set_up_logging(config: &Configuration) {
@@ -72,10 +104,39 @@ fn create_vips_app(config: &Configuration) -> Option<VipsApp> {
     Some(app)
 }
 
-async fn start_management_server(config: &Configuration) {
+// the endpoints which mustn't be exposed to the public, such as the ones writing to the storages
+async fn start_management_server(
+    config: Arc<Configuration>,
+    hardware_info: Arc<HardwareInfo>,
+    image_provider: Arc<Box<dyn ImageProvider>>,
+    app_state: AppState,
+) {
+    let purge = Router::new()
+        .route("/purge", delete(routes::purge::handle_purge))
+        .with_state(app_state.clone());
+    let readiness = Router::new()
+        .route("/readyz", get(routes::readiness::handle_readiness))
+        .with_state(image_provider);
+    let mut store = Router::new();
+    if app_state.image_sink.is_some() {
+        let upload_limit = DefaultBodyLimit::max(
+            config
+                .max_upload_size_bytes
+                .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE_BYTES),
+        );
+        store = store.route(
+            "/store",
+            post(routes::store::store_upload).layer(upload_limit),
+        );
+    }
     let app = Router::new()
         .route("/health", get(|| async { StatusCode::OK }))
-        .route("/metrics", get(routes::metric::handle_prometheus_scrapping));
+        .route("/metrics", get(routes::metric::handle_prometheus_scrapping))
+        .route("/version", get(routes::version::handle_version))
+        .with_state(hardware_info)
+        .merge(purge)
+        .merge(readiness)
+        .merge(store.with_state(app_state));
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.health_port))
         .await
         .unwrap();
@@ -86,7 +147,11 @@ async fn start_management_server(config: &Configuration) {
 pub struct AppState {
     vips_app: Arc<VipsApp>,
     image_provider: Arc<Box<dyn ImageProvider>>,
+    image_sink: Option<Arc<dyn ImageSink>>,
     public_img_path: Arc<String>,
+    config: Arc<Configuration>,
+    filesystem: Arc<dyn FileSystem>,
+    clock: Arc<dyn Clock>,
 }
 
 async fn measure_request_handling_duration(
@@ -108,16 +173,34 @@ async fn measure_request_handling_duration(
     Ok(res)
 }
 
-async fn start_main_server(config: &Configuration) {
-    let app_state = AppState {
-        vips_app: Arc::new(create_vips_app(config).unwrap()),
-        image_provider: Arc::new(create_image_provider(config).await),
-        public_img_path: Arc::new(config.public_img_path.clone()),
-    };
-
-    let app = Router::new()
+async fn start_main_server(config: Arc<Configuration>, app_state: AppState) {
+    let upload_limit = DefaultBodyLimit::max(
+        config
+            .max_upload_size_bytes
+            .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE_BYTES),
+    );
+    let mut app = Router::new()
         .route("/", get(routes::image::process_image))
-        .with_state(app_state);
+        .route("/info", get(routes::info::handle_info))
+        .route("/stats", get(routes::stats::handle_stats))
+        .route("/collage", get(routes::collage::handle_collage))
+        .route(
+            "/process",
+            post(routes::upload::process_upload).layer(upload_limit),
+        );
+    if let Some(prefix) = &config.imgproxy_prefix {
+        app = app.route(
+            &format!("{}/*path", prefix.trim_end_matches('/')),
+            get(routes::imgproxy::handle_imgproxy),
+        );
+    }
+    if let Some(prefix) = &config.thumbor_prefix {
+        app = app.route(
+            &format!("{}/*path", prefix.trim_end_matches('/')),
+            get(routes::thumbor::handle_thumbor),
+        );
+    }
+    let app = app.with_state(app_state);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.app_port))
         .await
@@ -0,0 +1,142 @@
+// (c) Copyright 2019-2024 OLX
+
+// pure Rust implementation of a subset of the processing pipeline: resizing, rotation and the
+// encoding to JPEG, PNG or (lossless) WebP. it's the fallback used where libvips isn't available.
+
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::{overlay, FilterType};
+use image::{DynamicImage, ImageError, Rgba, RgbaImage};
+use thiserror::Error;
+
+use crate::errors::InvalidSizeError;
+use crate::model::{
+    get_fit_size, get_focal_crop_origin, Fit, FitSize, ImageFormat, Kernel, ProcessImageRequest,
+    Quality, Rotation,
+};
+
+#[derive(Error, Debug)]
+pub enum PureProcessingError {
+    #[error("the image couldn't be decoded or encoded. error: {0}")]
+    Image(#[from] ImageError),
+    #[error("{0}")]
+    InvalidSize(#[from] InvalidSizeError),
+    #[error("the format `{0}` is not supported without libvips")]
+    UnsupportedFormat(ImageFormat),
+}
+
+pub fn process_image(
+    buffer: &[u8],
+    parameters: &ProcessImageRequest,
+) -> Result<Vec<u8>, PureProcessingError> {
+    let mut image = image::load_from_memory(buffer)?;
+
+    let (original_width, original_height) = (image.width() as i32, image.height() as i32);
+    let FitSize {
+        scaled: (width, height),
+        target: (target_width, target_height),
+    } = get_fit_size(
+        original_width,
+        original_height,
+        &parameters.size,
+        parameters.fit,
+        parameters.enlarge.unwrap_or(true),
+    )?;
+    if (width, height) != (original_width, original_height) {
+        // the image crate has no Mitchell filter, the Catmull-Rom cubic is the closest
+        let filter = match parameters.kernel {
+            Kernel::Lanczos3 => FilterType::Lanczos3,
+            Kernel::Mitchell | Kernel::Cubic => FilterType::CatmullRom,
+            Kernel::Nearest => FilterType::Nearest,
+        };
+        image = image.resize_exact(width as u32, height as u32, filter);
+    }
+    match parameters.fit {
+        Fit::Cover if (width, height) != (target_width, target_height) => {
+            let focus = (parameters.fx.unwrap_or(0.5), parameters.fy.unwrap_or(0.5));
+            let (left, top) =
+                get_focal_crop_origin(width, height, target_width, target_height, focus);
+            image = image.crop_imm(
+                left as u32,
+                top as u32,
+                target_width as u32,
+                target_height as u32,
+            );
+        }
+        Fit::Contain if (width, height) != (target_width, target_height) => {
+            let mut canvas = RgbaImage::from_pixel(
+                target_width as u32,
+                target_height as u32,
+                Rgba(parameters.background.0),
+            );
+            overlay(
+                &mut canvas,
+                &image,
+                i64::from((target_width - width) / 2),
+                i64::from((target_height - height) / 2),
+            );
+            image = DynamicImage::ImageRgba8(canvas);
+        }
+        _ => {}
+    }
+
+    // the rotations are anti-clockwise, the same as in the libvips pipeline
+    image = match parameters.rotation {
+        Some(Rotation::R90) => image.rotate270(),
+        Some(Rotation::R180) => image.rotate180(),
+        Some(Rotation::R270) => image.rotate90(),
+        None => image,
+    };
+    if parameters.flip {
+        image = image.flipv();
+    }
+    if parameters.flop {
+        image = image.fliph();
+    }
+
+    let mut output = Cursor::new(Vec::new());
+    match parameters.format {
+        ImageFormat::Jpeg => {
+            // the details of the image aren't measured without libvips
+            let quality = match parameters.quality {
+                Some(Quality::Value(quality)) => quality.clamp(1, 100) as u8,
+                Some(Quality::Auto) | None => 80,
+            };
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))?
+        }
+        ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut output))?,
+        ImageFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut output))?,
+        format => return Err(PureProcessingError::UnsupportedFormat(format)),
+    }
+    Ok(output.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn request(query: &str) -> ProcessImageRequest {
+        serde_qs::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn test_resize_and_rotate() {
+        let mut source = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(200, 100))
+            .write_with_encoder(PngEncoder::new(&mut source))
+            .unwrap();
+
+        let output = process_image(
+            source.get_ref(),
+            &request("image_address=a&size[width]=100&rotation=R90&format=Png"),
+        )
+        .unwrap();
+        let processed = image::load_from_memory(&output).unwrap();
+        assert_eq!((processed.width(), processed.height()), (50, 100));
+    }
+}
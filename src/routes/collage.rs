@@ -0,0 +1,69 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::VARY, HeaderValue, Response},
+};
+use futures::future::try_join_all;
+
+use crate::{commons::CollageRequest, image_processor::collage::process_collage, AppState};
+
+use super::image::{
+    local_image_path, reject_purged, respond, run_on_worker, validate_pixels, validate_source,
+    ImageProcessingError, ProcessImageRequestExtractor,
+};
+
+const MAX_COLLAGE_IMAGES: usize = 16;
+
+// composites the `images` into a single one according to the `layout`, every image covering a
+// cell of `size`. the images are all fetched, any of them failing fails the collage.
+pub async fn handle_collage(
+    State(state): State<AppState>,
+    ProcessImageRequestExtractor {
+        params,
+        negotiated_format,
+        ..
+    }: ProcessImageRequestExtractor<CollageRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let max_images = params
+        .layout
+        .max_images()
+        .unwrap_or(MAX_COLLAGE_IMAGES)
+        .min(MAX_COLLAGE_IMAGES);
+    if params.images.is_empty() || params.images.len() > max_images {
+        return Err(ImageProcessingError::InvalidCollage(format!(
+            "the {:?} layout takes from 1 to {} images, {} were given",
+            params.layout,
+            max_images,
+            params.images.len()
+        )));
+    }
+    for address in &params.images {
+        let filepath = local_image_path(&state.public_img_path, address)?;
+        reject_purged(&state, address, &filepath).await?;
+    }
+    let sources = try_join_all(
+        params
+            .images
+            .iter()
+            .map(|address| state.image_provider.get_file(address)),
+    )
+    .await?;
+    for (source, address) in sources.iter().zip(&params.images) {
+        validate_source(source, address)?;
+        validate_pixels(source, address, &state.config, None, false)?;
+    }
+
+    let config = state.config.clone();
+    let processed_image = run_on_worker(&state, String::from("collage"), move |kill_switch| {
+        process_collage(sources, params, &config, kill_switch)
+    })
+    .await?;
+    let mut response = respond(&state.config, state.clock.now(), processed_image)?;
+    // as for `/`, the negotiated format depends on the `Accept` header
+    if negotiated_format {
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept"));
+    }
+    Ok(response)
+}
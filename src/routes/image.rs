@@ -2,32 +2,89 @@ use async_trait::async_trait;
 use axum::{
     body::Body,
     extract::{FromRequest, Request, State},
-    http::{self, HeaderValue, Response, StatusCode},
+    http::{self, Response, StatusCode},
     response::IntoResponse,
 };
 use futures::future::join_all;
-use log::{error, warn};
+use log::{debug, error, warn};
 use reqwest::{
-    header::{CONTENT_TYPE, LAST_MODIFIED},
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, EXPIRES, LAST_MODIFIED, VARY},
     Url,
 };
 use serde::de::DeserializeOwned;
 use serde_json::json;
-use std::{error::Error, path::PathBuf};
-use std::{path::Path, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
-use tokio::fs;
 
 use crate::{
-    commons::{ImageFormat, ProcessImageRequest},
-    image_processor, AppState,
+    commons::{
+        apply_client_hints,
+        cache_control::{cache_control, expires},
+        config::Configuration,
+        last_modified::{get_last_modified, is_not_modified},
+        local_path::contained_path,
+        negotiate_format,
+        provenance::provenance_token,
+        signature::{is_svg, sniff_image_format},
+        tombstone::{is_purged, DEFAULT_TOMBSTONE_TTL_SECS},
+        ImageFormat, ProcessImageRequest, Quality,
+    },
+    image_processor::{self, ImageInfo, KillSwitch, ProcessedImage},
+    image_provider::ImageBuffer,
+    AppState,
 };
 
+#[cfg(feature = "video")]
+use crate::commons::video;
+
 use super::metric::{FETCH_DURATION, INPUT_SIZE, OUTPUT_SIZE};
 
+const DEFAULT_MAX_DPR: f64 = 3.0;
+// the same limit as the one of sharp, a 16383x16383 image
+const DEFAULT_MAX_SOURCE_MEGAPIXELS: u64 = 268;
+const X_IMAGE_WIDTH: &str = "X-Image-Width";
+const X_IMAGE_HEIGHT: &str = "X-Image-Height";
+const X_IMAGE_ORIGINAL_WIDTH: &str = "X-Image-Original-Width";
+const X_IMAGE_ORIGINAL_HEIGHT: &str = "X-Image-Original-Height";
+const X_IMAGE_PROVENANCE: &str = "X-Image-Provenance";
+const X_DALI_TRACE: &str = "X-Dali-Trace";
+const ACCEPT_CH: &str = "Accept-CH";
+const ACCEPT_CH_LIFETIME: &str = "Accept-CH-Lifetime";
+const CRITICAL_CH: &str = "Critical-CH";
+
 pub struct ProcessImageRequestExtractor<T> {
     pub params: T,
     pub if_modified: Option<String>,
+    pub client_hints: ClientHints,
+    // whether the format was picked after the `Accept` header, which the response varies on
+    pub negotiated_format: bool,
+}
+
+#[derive(Default)]
+pub struct ClientHints {
+    pub width: Option<i32>,
+    pub dpr: Option<f64>,
+}
+
+impl ClientHints {
+    fn from_headers(headers: &http::HeaderMap) -> Self {
+        // the unprefixed headers are the legacy names still sent by older browsers
+        let hint = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name))
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_owned())
+        };
+        Self {
+            width: hint(&["Sec-CH-Width", "Width"]).and_then(|w| w.parse().ok()),
+            dpr: hint(&["Sec-CH-DPR", "DPR"]).and_then(|dpr| dpr.parse().ok()),
+        }
+    }
 }
 
 #[async_trait]
@@ -39,17 +96,33 @@ where
     type Rejection = (StatusCode, String);
 
     async fn from_request(req: Request, _state: &B) -> Result<Self, Self::Rejection> {
-        let query = req.uri().query();
-        let if_modified = req
-            .headers()
+        Self::from_query(req.uri().query(), req.headers())
+    }
+}
+
+impl<T: DeserializeOwned> ProcessImageRequestExtractor<T> {
+    // the parameters of the query string, along with the request headers they depend on
+    pub(crate) fn from_query(
+        query: Option<&str>,
+        headers: &http::HeaderMap,
+    ) -> Result<Self, (StatusCode, String)> {
+        let if_modified = headers
             .get(http::header::IF_MODIFIED_SINCE)
             .map(|m| m.to_str().unwrap().to_owned());
+        let client_hints = ClientHints::from_headers(headers);
         if let Some(query) = query {
-            let extracted_params = serde_qs::from_str(query);
+            let accept = headers
+                .get(http::header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .unwrap_or_default();
+            let (query, negotiated_format) = negotiate_query_format(query, accept);
+            let extracted_params = serde_qs::from_str(&query);
             if extracted_params.is_ok() {
                 Ok(Self {
                     params: extracted_params.unwrap(),
                     if_modified,
+                    client_hints,
+                    negotiated_format,
                 })
             } else {
                 Err((
@@ -66,6 +139,22 @@ where
     }
 }
 
+// replaces `format=auto` with the format negotiated with the client, before the query is parsed
+fn negotiate_query_format(query: &str, accept: &str) -> (String, bool) {
+    let mut negotiated = false;
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("format", value)) if value.eq_ignore_ascii_case("auto") => {
+                negotiated = true;
+                format!("format={:?}", negotiate_format(accept))
+            }
+            _ => pair.to_string(),
+        })
+        .collect();
+    (pairs.join("&"), negotiated)
+}
+
 #[derive(Error, Debug)]
 pub enum ImageProcessingError {
     #[error("the provded resource uri is not valid: `{0}`")]
@@ -76,6 +165,31 @@ pub enum ImageProcessingError {
     ClientReturnedErrorStatusCode(u16, String),
     #[error("the download of the image has failed")]
     ImageDownloadFailed,
+    #[error("the source image exceeds the maximum allowed size of `{0}` bytes")]
+    SourceTooLarge(u64),
+    #[error("the source image exceeds the maximum allowed size of `{0}` megapixels")]
+    TooManyPixels(u64),
+    #[error("the source has the unsupported media type `{0}`")]
+    UnsupportedMediaType(String),
+    #[error("downloading from the origin `{0}` is not allowed")]
+    ForbiddenOrigin(String),
+    #[error("the origin `{0}` is unavailable")]
+    OriginUnavailable(String),
+    #[error("the collage is not valid: {0}")]
+    InvalidCollage(String),
+    #[error("the uploaded image is not valid: {0}")]
+    InvalidUpload(String),
+    #[error("the image address `{0}` resolves outside of the public images path")]
+    PathOutsideRoot(String),
+    #[error("failed to store the processed image `{0}`")]
+    StoreFailed(String),
+    #[error("the image `{0}` has been purged")]
+    Purged(String),
+    #[cfg(feature = "video")]
+    #[error("the poster frame of the video couldn't be extracted: {0}")]
+    VideoFrameUnavailable(String),
+    #[error("the processing of the image timed out after `{0}` milliseconds")]
+    ProcessingTimedOut(u64),
     #[error("failed to join the thread that was doing the processing")]
     ProcessingWorkerJoinError,
     #[error("the image processing with libvips has failed")]
@@ -108,6 +222,51 @@ impl IntoResponse for ImageProcessingError {
                 StatusCode::BAD_REQUEST,
                 format!("The provided resource URI is not valid: '{}'", resource_uri)
             ),
+            ImageProcessingError::SourceTooLarge(max_size) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("The image requested to be processed exceeds the maximum allowed size of {} bytes.", max_size)
+            ),
+            ImageProcessingError::TooManyPixels(max_megapixels) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("The image requested to be processed exceeds the maximum allowed size of {} megapixels.", max_megapixels)
+            ),
+            ImageProcessingError::UnsupportedMediaType(media_type) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("The image requested to be processed has an unsupported media type: '{}'", media_type)
+            ),
+            ImageProcessingError::ForbiddenOrigin(host) => (
+                StatusCode::FORBIDDEN,
+                format!("Downloading the image requested to be processed from '{}' is not allowed.", host)
+            ),
+            ImageProcessingError::OriginUnavailable(host) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("The origin of the image requested to be processed is unavailable: '{}'", host)
+            ),
+            ImageProcessingError::InvalidCollage(reason) => (
+                StatusCode::BAD_REQUEST,
+                format!("The collage can't be processed: {}", reason)
+            ),
+            ImageProcessingError::InvalidUpload(reason) => (
+                StatusCode::BAD_REQUEST,
+                format!("The uploaded image can't be processed: {}", reason)
+            ),
+            ImageProcessingError::PathOutsideRoot(resource) => (
+                StatusCode::FORBIDDEN,
+                format!("The image requested to be processed is not allowed to be read: '{}'", resource)
+            ),
+            #[cfg(feature = "video")]
+            ImageProcessingError::VideoFrameUnavailable(reason) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The poster frame of the video requested to be processed can't be extracted: {}", reason)
+            ),
+            ImageProcessingError::ProcessingTimedOut(timeout) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("The processing of the image timed out after {} milliseconds.", timeout)
+            ),
+            ImageProcessingError::Purged(resource) => (
+                StatusCode::GONE,
+                format!("The image requested to be processed has been purged: '{}'", resource)
+            ),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 String::from("Something went wrong on our side."),
@@ -122,68 +281,159 @@ impl IntoResponse for ImageProcessingError {
     }
 }
 
-async fn get_metadata(real_filepath: &str) -> Result<HeaderValue, Box<dyn Error>> {
-    let metadata = fs::metadata(PathBuf::from(real_filepath))
-        .await
-        .expect("failed to read file metadata");
-    let last_modified = metadata.modified()?; // 获取文件最后修改时间
-    let last_modified_header =
-        http::HeaderValue::from_str(httpdate::fmt_http_date(last_modified).as_str())?;
-    Ok(last_modified_header)
-}
-
+// the request handling is split in stages: resolve -> validate -> fetch -> process -> respond.
+// every stage returns early with an `ImageProcessingError`, which is the only error boundary of
+// the handler. dropping the handler future (e.g. when the client disconnects) cancels the pending
+// downloads, and the processing is skipped if the request is gone before a worker picks it up.
 pub async fn process_image(
-    State(AppState {
-        vips_app,
-        image_provider,
-        public_img_path,
-    }): State<AppState>,
+    State(state): State<AppState>,
     ProcessImageRequestExtractor {
-        mut params,
+        params,
         if_modified,
+        client_hints,
+        negotiated_format,
     }: ProcessImageRequestExtractor<ProcessImageRequest>,
 ) -> Result<Response<Body>, ImageProcessingError> {
-    let real_filepath: String;
-    if params.image_address.starts_with("http://") || params.image_address.starts_with("https://") {
-        let url = Url::parse(&params.image_address)
-            .map_err(|_| {
-                error!(
-                    "the provided resource uri is not a valid http url: '{}'",
-                    &params.image_address
-                );
-            })
-            .unwrap();
-        let filepathstr = format!("{}{}", public_img_path, url.clone().path());
-        let filepath = Path::new(filepathstr.as_str());
-        real_filepath = filepath.to_str().unwrap().to_owned();
-    } else {
-        real_filepath = format!("{}/{}", public_img_path, params.image_address)
-            .as_str()
-            .to_string();
+    let mut response = handle(&state, params, if_modified, client_hints).await?;
+    // the 304 responses refresh the cached image, so they carry the same freshness
+    if matches!(response.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+        with_cache_headers(&mut response, &state);
     }
-    if params.image_address.ends_with("400X400.jpg") {
-        params.quality = 68;
+    // the caches must not serve the negotiated format to the clients accepting other ones
+    if negotiated_format {
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept"));
     }
+    Ok(response)
+}
 
-    let filepath = Path::new(real_filepath.as_str());
-    let now = SystemTime::now();
+async fn handle(
+    state: &AppState,
+    params: ProcessImageRequest,
+    if_modified: Option<String>,
+    client_hints: ClientHints,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let request = resolve(state, params, client_hints)?;
+    if let Some(response) = validate(state, &request, if_modified.as_deref()).await? {
+        return Ok(response);
+    }
+    let sources = fetch(state, &request).await?;
+    let last_modified = sources.last_modified;
+    if let Some(info) = passthrough_info(&state.config, &request.params, &sources.main_img)? {
+        return respond_original(
+            &state.config,
+            last_modified,
+            &request.params,
+            sources.main_img,
+            info,
+        );
+    }
+    let processed_image = process(state, request.params, sources).await?;
+    respond(&state.config, last_modified, processed_image)
+}
+
+// the request after being resolved against the configuration
+struct ResolvedRequest {
+    params: ProcessImageRequest,
+    filepath: PathBuf,
+}
 
-    if filepath.exists() {
-        let last_modified_header = get_metadata(real_filepath.as_str()).await.unwrap();
+pub(crate) struct FetchedSources {
+    pub main_img: ImageBuffer,
+    // one per watermark of the request, none for the ones which couldn't be downloaded
+    pub watermarks: Vec<Option<ImageBuffer>>,
+    pub last_modified: SystemTime,
+    pub total_input_size: usize,
+}
 
+fn resolve(
+    state: &AppState,
+    mut params: ProcessImageRequest,
+    client_hints: ClientHints,
+) -> Result<ResolvedRequest, ImageProcessingError> {
+    let filepath = local_image_path(&state.public_img_path, &params.image_address)?;
+    // only the uploaded watermarks go without an address, which would be fetched as is otherwise
+    if params
+        .watermarks
+        .iter()
+        .any(|wm| wm.image_address.is_empty())
+    {
+        return Err(ImageProcessingError::InvalidResourceUriProvided(
+            String::from("watermarks[][image_address]"),
+        ));
+    }
+    if params.image_address.ends_with("400X400.jpg") {
+        params.quality = Some(Quality::Value(68));
+    }
+    // the hints are only honored when they are advertised, as the responses have to vary on them.
+    // the explicit `dpr` takes precedence, it's applied along with the processing.
+    if state.config.accept_ch.is_some() && params.dpr.is_none() {
+        params.size = apply_client_hints(&params.size, client_hints.width, client_hints.dpr);
+    }
+    Ok(ResolvedRequest { params, filepath })
+}
+
+// returns the response to send right away when the image doesn't have to be processed
+async fn validate(
+    state: &AppState,
+    request: &ResolvedRequest,
+    if_modified: Option<&str>,
+) -> Result<Option<Response<Body>>, ImageProcessingError> {
+    let (filesystem, clock) = (state.filesystem.as_ref(), state.clock.as_ref());
+    reject_purged(state, &request.params.image_address, &request.filepath).await?;
+
+    if let Some(last_modified) = get_last_modified(filesystem, clock, &request.filepath).await {
         // 检查 If-Modified-Since 请求头
-        if let Some(if_modified_since) = if_modified {
-            if if_modified_since == last_modified_header {
-                return Ok(Response::builder()
+        if is_not_modified(if_modified, last_modified) {
+            return Ok(Some(
+                Response::builder()
                     .status(StatusCode::NOT_MODIFIED)
-                    .body(Body::empty())?);
-            }
+                    .body(Body::empty())?,
+            ));
         }
     }
+    Ok(None)
+}
+
+// the purged images are answered with `410 Gone` by every route until their tombstone expires
+pub(crate) async fn reject_purged(
+    state: &AppState,
+    image_address: &str,
+    filepath: &Path,
+) -> Result<(), ImageProcessingError> {
+    let tombstone_ttl = Duration::from_secs(
+        state
+            .config
+            .purge_tombstone_ttl_secs
+            .unwrap_or(DEFAULT_TOMBSTONE_TTL_SECS),
+    );
+    let (filesystem, clock) = (state.filesystem.as_ref(), state.clock.as_ref());
+    if is_purged(filesystem, clock, filepath, tombstone_ttl).await {
+        return Err(ImageProcessingError::Purged(image_address.to_string()));
+    }
+    Ok(())
+}
+
+async fn fetch(
+    state: &AppState,
+    request: &ResolvedRequest,
+) -> Result<FetchedSources, ImageProcessingError> {
+    let now = SystemTime::now();
+    let image_provider = &state.image_provider;
+    let params = &request.params;
 
     let main_img = image_provider.get_file(&params.image_address).await?;
+    #[cfg(feature = "video")]
+    let main_img = poster_frame(state, main_img, params.t).await?;
 
-    let last_modified_header = get_metadata(real_filepath.as_str()).await.unwrap();
+    let last_modified = get_last_modified(
+        state.filesystem.as_ref(),
+        state.clock.as_ref(),
+        &request.filepath,
+    )
+    .await
+    .unwrap_or_else(|| state.clock.now());
     let mut total_input_size = main_img.len();
 
     let mut watermarks = vec![];
@@ -195,19 +445,15 @@ pub async fn process_image(
         watermarks = join_all(watermarks_futures)
             .await
             .into_iter()
-            .filter(|r| {
-                if r.is_err() {
-                    warn!(
-                        "failed to download watermark with error {}",
-                        r.as_ref().err().unwrap()
-                    );
+            .map(|r| match r {
+                Ok(watermark) => {
+                    total_input_size += watermark.len();
+                    Some(watermark)
+                }
+                Err(e) => {
+                    warn!("failed to download watermark with error {}", e);
+                    None
                 }
-                r.is_ok()
-            })
-            .map(|r| {
-                let watermark = r.unwrap();
-                total_input_size += watermark.len();
-                watermark
             })
             .collect();
     }
@@ -218,17 +464,81 @@ pub async fn process_image(
         FETCH_DURATION.success.observe(duration);
     }
 
-    let format = params.format;
+    Ok(FetchedSources {
+        main_img,
+        watermarks,
+        last_modified,
+        total_input_size,
+    })
+}
+
+// the video sources are processed as the image of one of their frames
+#[cfg(feature = "video")]
+async fn poster_frame(
+    state: &AppState,
+    source: ImageBuffer,
+    seconds: Option<f64>,
+) -> Result<ImageBuffer, ImageProcessingError> {
+    if video::sniff_video_format(&source).is_none() {
+        return Ok(source);
+    }
+    let seconds = seconds
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .unwrap_or(0.0);
+    let ffmpeg = state
+        .config
+        .ffmpeg_path
+        .as_deref()
+        .unwrap_or(video::DEFAULT_FFMPEG_PATH);
+    let timeout = Duration::from_secs(
+        state
+            .config
+            .ffmpeg_timeout_secs
+            .unwrap_or(video::DEFAULT_FFMPEG_TIMEOUT_SECS),
+    );
+    video::extract_poster_frame(&source, seconds, ffmpeg, timeout)
+        .await
+        .map(ImageBuffer::from)
+        .map_err(|e| {
+            error!(
+                "failed to extract the poster frame of the video. error: {}",
+                e
+            );
+            ImageProcessingError::VideoFrameUnavailable(e)
+        })
+}
 
-    // processing the image is a blocking operation and originally I've use the tokio::spawn_blocking option to process the image.
-    // it was decently performing, but I've benchmarked rayon as well and the performance improved a lot in terms of
-    // response time and memory used
+// processing the image is a blocking operation and originally I've use the tokio::spawn_blocking option to process the image.
+// it was decently performing, but I've benchmarked rayon as well and the performance improved a lot in terms of
+// response time and memory used
+pub(crate) async fn run_on_worker<T: Send + 'static>(
+    state: &AppState,
+    image_address: String,
+    job: impl FnOnce(&KillSwitch) -> libvips::Result<T> + Send + 'static,
+) -> Result<T, ImageProcessingError> {
     let (send, recv) = tokio::sync::oneshot::channel();
+    let kill_switch = KillOnDrop(Arc::new(KillSwitch::default()));
+    let worker_kill_switch = kill_switch.0.clone();
     rayon::spawn(move || {
-        let image = image_processor::process_image(main_img, watermarks, params);
-        let _ = send.send(image);
+        if send.is_closed() {
+            debug!(
+                "skipping the processing of '{}' as the request was cancelled",
+                image_address
+            );
+            return;
+        }
+        let _ = send.send(job(&worker_kill_switch));
     });
-    let processed_image = recv.await.map_err(|e| {
+    let received = match state.config.processing_timeout_millis {
+        Some(timeout) => tokio::time::timeout(Duration::from_millis(timeout), recv)
+            .await
+            .map_err(|_| {
+                error!("the processing of the image timed out after {} ms", timeout);
+                ImageProcessingError::ProcessingTimedOut(timeout)
+            })?,
+        None => recv.await,
+    };
+    received.map_err(|e| {
         error!(
             "failed to join the thread which process the image. error: {}",
             e
@@ -238,17 +548,318 @@ pub async fn process_image(
     .map_err(|e| {
         error!(
             "the image processing has failed for the resource with the error: {}. libvips raw error is: {}",
-            e, vips_app.error_buffer().unwrap_or("").replace("\n", ". ")
+            e, state.vips_app.error_buffer().unwrap_or("").replace("\n", ". ")
         );
         ImageProcessingError::LibvipsProcessingFailed(e)
-    })?;
+    })
+}
+
+// runs the libvips pipeline, encoding included, as libvips only computes the pixels while encoding
+pub(crate) async fn process(
+    state: &AppState,
+    mut params: ProcessImageRequest,
+    sources: FetchedSources,
+) -> Result<ProcessedImage, ImageProcessingError> {
+    if let Some(widths) = &params.widths {
+        params.size = widths.largest_size(&params.size);
+    } else if let Some(dpr) = params.dpr.filter(|dpr| dpr.is_finite()) {
+        let max_dpr = state.config.max_dpr.unwrap_or(DEFAULT_MAX_DPR).max(1.0);
+        params.size = apply_client_hints(&params.size, None, Some(dpr.clamp(1.0, max_dpr)));
+    }
+    let FetchedSources {
+        main_img,
+        watermarks,
+        total_input_size,
+        ..
+    } = sources;
+    validate_source(&main_img, &params.image_address)?;
+    let page = image_processor::page_to_load(&main_img, params.page);
+    let animated =
+        image_processor::keeps_animation(&main_img, params.format, params.animated, page);
+    validate_pixels(
+        &main_img,
+        &params.image_address,
+        &state.config,
+        page,
+        animated,
+    )?;
+    for watermark in watermarks.iter().flatten() {
+        if !is_svg(watermark) {
+            validate_source(watermark, "watermark")?;
+            validate_pixels(watermark, "watermark", &state.config, None, false)?;
+        }
+    }
+    let config = state.config.clone();
+    let processed_image = run_on_worker(state, params.image_address.clone(), move |kill_switch| {
+        image_processor::process_image(main_img, watermarks, params, &config, kill_switch)
+    })
+    .await?;
+
+    debug!(
+        "processed {} input bytes into a {}x{} image with the operations {:?}. timings: {:?}",
+        total_input_size,
+        processed_image.width,
+        processed_image.height,
+        processed_image.applied_ops,
+        processed_image.timings
+    );
+    // log_size_metrics(&processed_image.format, total_input_size, processed_image.bytes.len());
+    Ok(processed_image)
+}
+
+// kills the processing once the request doesn't wait for it anymore, because it timed out or
+// because the client went away. it's harmless once the processing is over.
+struct KillOnDrop(Arc<KillSwitch>);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        self.0.kill();
+    }
+}
+
+// only the sources which look like a supported image are handed to libvips
+pub(crate) fn validate_source(source: &[u8], name: &str) -> Result<(), ImageProcessingError> {
+    if sniff_image_format(source).is_none() {
+        error!(
+            "the source '{}' is not a supported image, it starts with {:02x?}",
+            name,
+            &source[..source.len().min(8)]
+        );
+        return Err(ImageProcessingError::UnsupportedMediaType(String::from(
+            "unrecognized image format",
+        )));
+    }
+    Ok(())
+}
+
+// the dimensions are read from the header, before the pixels of a decompression bomb are decoded.
+// they're the ones of the page which is loaded, or of all the frames of an animation kept as such.
+pub(crate) fn validate_pixels(
+    source: &[u8],
+    name: &str,
+    config: &Configuration,
+    page: Option<i32>,
+    animated: bool,
+) -> Result<(), ImageProcessingError> {
+    let max_megapixels = config
+        .max_source_megapixels
+        .unwrap_or(DEFAULT_MAX_SOURCE_MEGAPIXELS);
+    let (width, height) = image_processor::read_loaded_size(source, config, page, animated)
+        .map_err(ImageProcessingError::LibvipsProcessingFailed)?;
+    let pixels = width as u64 * height as u64;
+    if pixels > max_megapixels * 1_000_000 {
+        error!(
+            "the source '{}' is {}x{} which exceeds the limit of {} megapixels",
+            name, width, height, max_megapixels
+        );
+        return Err(ImageProcessingError::TooManyPixels(max_megapixels));
+    }
+    Ok(())
+}
+
+pub(crate) fn respond(
+    config: &Configuration,
+    last_modified: SystemTime,
+    processed_image: ProcessedImage,
+) -> Result<Response<Body>, ImageProcessingError> {
+    if !processed_image.variants.is_empty() {
+        return respond_variants(config, last_modified, processed_image);
+    }
+    Ok(with_headers(
+        Response::builder(),
+        config,
+        processed_image.trace.as_deref(),
+    )
+    .status(StatusCode::OK)
+    .header(CONTENT_TYPE, format!("image/{}", processed_image.format))
+    .header(LAST_MODIFIED, httpdate::fmt_http_date(last_modified))
+    .header(X_IMAGE_WIDTH, processed_image.width)
+    .header(X_IMAGE_HEIGHT, processed_image.height)
+    .header(X_IMAGE_ORIGINAL_WIDTH, processed_image.original_width)
+    .header(X_IMAGE_ORIGINAL_HEIGHT, processed_image.original_height)
+    .header(X_IMAGE_PROVENANCE, processed_image.provenance)
+    .body(Body::from(Into::<Vec<u8>>::into(processed_image.bytes)))?)
+}
+
+// the source is served as it is when the request doesn't change it, which spares a lossy decoding
+// and encoding. its header tells whether the colors or the orientation would be changed anyway.
+fn passthrough_info(
+    config: &Configuration,
+    params: &ProcessImageRequest,
+    source: &[u8],
+) -> Result<Option<ImageInfo>, ImageProcessingError> {
+    if !config.passthrough_unchanged_sources.unwrap_or(false)
+        || !params.is_passthrough(sniff_image_format(source))
+        || is_raw(source)
+        || params.strip.or(config.strip_metadata).unwrap_or(false)
+        || config.embed_provenance.unwrap_or(false)
+    {
+        return Ok(None);
+    }
+    let info = image_processor::read_image_info(source, config)
+        .map_err(ImageProcessingError::LibvipsProcessingFailed)?;
+    let converted = info.interpretation == "cmyk" || (info.has_profile && !params.keep_profile);
+    let rotated = params.autorotate && info.orientation > 1;
+    Ok((!converted && !rotated).then_some(info))
+}
+
+// the RAW photos are TIFF files too, whose sensor data has to be developed before being served
+#[cfg(feature = "raw")]
+fn is_raw(source: &[u8]) -> bool {
+    crate::commons::signature::sniff_raw_format(source).is_some()
+}
+
+#[cfg(not(feature = "raw"))]
+fn is_raw(_source: &[u8]) -> bool {
+    false
+}
 
-    // log_size_metrics(&format, total_input_size, processed_image.len());
-    Ok(Response::builder()
+fn respond_original(
+    config: &Configuration,
+    last_modified: SystemTime,
+    params: &ProcessImageRequest,
+    source: ImageBuffer,
+    info: ImageInfo,
+) -> Result<Response<Body>, ImageProcessingError> {
+    debug!(
+        "serving the source of '{}' as it is, the request doesn't change it",
+        params.image_address
+    );
+    Ok(with_client_hints_headers(Response::builder(), config)
         .status(StatusCode::OK)
-        .header(CONTENT_TYPE, format!("image/{}", format))
-        .header(LAST_MODIFIED, last_modified_header)
-        .body(Body::from(Into::<Vec<u8>>::into(processed_image)))?)
+        .header(CONTENT_TYPE, format!("image/{}", params.format))
+        .header(LAST_MODIFIED, httpdate::fmt_http_date(last_modified))
+        .header(X_IMAGE_WIDTH, info.width)
+        .header(X_IMAGE_HEIGHT, info.height)
+        .header(X_IMAGE_ORIGINAL_WIDTH, info.width)
+        .header(X_IMAGE_ORIGINAL_HEIGHT, info.height)
+        .header(X_IMAGE_PROVENANCE, provenance_token(&source, params))
+        .body(Body::from(source.into_bytes()))?)
+}
+
+// the image and its variants as the parts of a multipart/mixed body, from the largest to the
+// smallest, every part with its own size headers
+fn respond_variants(
+    config: &Configuration,
+    last_modified: SystemTime,
+    processed_image: ProcessedImage,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let boundary = format!("dali-variants-{}", processed_image.provenance);
+    let content_type = format!("image/{}", processed_image.format);
+    let parts = std::iter::once((
+        processed_image.width,
+        processed_image.height,
+        processed_image.bytes,
+    ))
+    .chain(
+        processed_image
+            .variants
+            .into_iter()
+            .map(|variant| (variant.width, variant.height, variant.bytes)),
+    );
+    let mut body = vec![];
+    for (width, height, bytes) in parts {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\n{}: {}\r\n{}: {}\r\n{}: {}\r\n\r\n",
+                boundary, CONTENT_TYPE, content_type, X_IMAGE_WIDTH, width, X_IMAGE_HEIGHT, height
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&Into::<Vec<u8>>::into(bytes));
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Ok(with_headers(
+        Response::builder(),
+        config,
+        processed_image.trace.as_deref(),
+    )
+    .status(StatusCode::OK)
+    .header(
+        CONTENT_TYPE,
+        format!("multipart/mixed; boundary={}", boundary),
+    )
+    .header(LAST_MODIFIED, httpdate::fmt_http_date(last_modified))
+    .header(X_IMAGE_ORIGINAL_WIDTH, processed_image.original_width)
+    .header(X_IMAGE_ORIGINAL_HEIGHT, processed_image.original_height)
+    .header(X_IMAGE_PROVENANCE, processed_image.provenance)
+    .body(Body::from(body))?)
+}
+
+// the client hints, and the steps of the pipeline when the request asks to debug it
+fn with_headers(
+    builder: http::response::Builder,
+    config: &Configuration,
+    trace: Option<&str>,
+) -> http::response::Builder {
+    let builder = with_client_hints_headers(builder, config);
+    match trace {
+        Some(trace) => builder.header(X_DALI_TRACE, trace),
+        None => builder,
+    }
+}
+
+// lets the browsers and the CDNs cache the processed images, as configured
+fn with_cache_headers(response: &mut Response<Body>, state: &AppState) {
+    let headers = response.headers_mut();
+    if let Some(value) = cache_control(&state.config).and_then(|v| HeaderValue::from_str(&v).ok()) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+    if let Some(expires) = expires(&state.config, state.clock.now()) {
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(expires)) {
+            headers.insert(EXPIRES, value);
+        }
+    }
+}
+
+// advertises the client hints the browsers should send along the subsequent image requests
+fn with_client_hints_headers(
+    mut builder: http::response::Builder,
+    config: &Configuration,
+) -> http::response::Builder {
+    if let Some(accept_ch) = &config.accept_ch {
+        let accept_ch = accept_ch.join(", ");
+        builder = builder
+            .header(ACCEPT_CH, &accept_ch)
+            .header(VARY, &accept_ch);
+        if let Some(lifetime) = config.accept_ch_lifetime_secs {
+            builder = builder.header(ACCEPT_CH_LIFETIME, lifetime);
+        }
+    }
+    if let Some(critical_ch) = &config.critical_ch {
+        builder = builder.header(CRITICAL_CH, critical_ch.join(", "));
+    }
+    builder
+}
+
+// the path of the local copy of the image, where the images downloaded from http origins are mirrored.
+// it's always within public_img_path, whatever the image address.
+pub fn local_image_path(
+    public_img_path: &str,
+    image_address: &str,
+) -> Result<PathBuf, ImageProcessingError> {
+    let relative = if image_address.starts_with("http://") || image_address.starts_with("https://")
+    {
+        let url = Url::parse(image_address).map_err(|_| {
+            error!(
+                "the provided resource uri is not a valid http url: '{}'",
+                image_address
+            );
+            ImageProcessingError::InvalidResourceUriProvided(image_address.to_string())
+        })?;
+        url.path().to_string()
+    } else {
+        image_address.to_string()
+    };
+    contained_path(public_img_path, &relative).ok_or_else(|| {
+        error!(
+            "the image address '{}' resolves outside of '{}'",
+            image_address, public_img_path
+        );
+        ImageProcessingError::PathOutsideRoot(image_address.to_string())
+    })
 }
 
 fn log_size_metrics(format: &ImageFormat, input_size: usize, response_length: usize) {
@@ -269,5 +880,21 @@ fn log_size_metrics(format: &ImageFormat, input_size: usize, response_length: us
             INPUT_SIZE.png.observe(input_size as f64);
             OUTPUT_SIZE.png.observe(response_length as f64);
         }
+        ImageFormat::Avif => {
+            INPUT_SIZE.avif.observe(input_size as f64);
+            OUTPUT_SIZE.avif.observe(response_length as f64);
+        }
+        ImageFormat::Gif => {
+            INPUT_SIZE.gif.observe(input_size as f64);
+            OUTPUT_SIZE.gif.observe(response_length as f64);
+        }
+        ImageFormat::Jxl => {
+            INPUT_SIZE.jxl.observe(input_size as f64);
+            OUTPUT_SIZE.jxl.observe(response_length as f64);
+        }
+        ImageFormat::Tiff => {
+            INPUT_SIZE.tiff.observe(input_size as f64);
+            OUTPUT_SIZE.tiff.observe(response_length as f64);
+        }
     }
 }
@@ -0,0 +1,421 @@
+// the imgproxy url syntax, `/<signature>/<option>:<arg>:.../plain/<source url>@<extension>` or
+// `/<signature>/<option>:<arg>:.../<base64 encoded source url>.<extension>`, translated into the
+// query of `/`, so that the imgproxy clients can be pointed at dali without changing their urls
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, Response, StatusCode, Uri},
+    response::IntoResponse,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha2::Sha256;
+
+use crate::{commons::config::Configuration, AppState};
+
+use super::image::{self, ProcessImageRequestExtractor};
+
+pub async fn handle_imgproxy(
+    State(state): State<AppState>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let prefix = state.config.imgproxy_prefix.as_deref().unwrap_or_default();
+    let path = uri
+        .path()
+        .strip_prefix(prefix.trim_end_matches('/'))
+        .unwrap_or_default();
+    let Some((signature, path)) = path.trim_start_matches('/').split_once('/') else {
+        return (StatusCode::NOT_FOUND, "missing imgproxy processing path").into_response();
+    };
+    if !has_valid_signature(&state.config, signature, path) {
+        warn!("the imgproxy signature of '{}' doesn't match", path);
+        return (StatusCode::FORBIDDEN, "invalid imgproxy signature").into_response();
+    }
+    let query = match imgproxy_query(path) {
+        Ok(query) => query,
+        Err(e) => {
+            warn!("failed to translate the imgproxy path '{}': {}", path, e);
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    match ProcessImageRequestExtractor::from_query(Some(&query), &headers) {
+        Ok(request) => image::process_image(State(state), request)
+            .await
+            .into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// the paths are signed like imgproxy does when both `imgproxy_key` and `imgproxy_salt` are set,
+// and accepted whatever their signature otherwise
+fn has_valid_signature(config: &Configuration, signature: &str, path: &str) -> bool {
+    let (Some(key), Some(salt)) = (&config.imgproxy_key, &config.imgproxy_salt) else {
+        return true;
+    };
+    let (Some(key), Some(salt)) = (unhex(key), unhex(salt)) else {
+        return false;
+    };
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature.trim_end_matches('=')) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("hmac accepts keys of any size");
+    mac.update(&salt);
+    mac.update(b"/");
+    mac.update(path.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+// the query of `/` matching the processing options and the source of an imgproxy path
+pub fn imgproxy_query(path: &str) -> Result<String, String> {
+    let mut query = vec![];
+    let mut segments = path.split('/');
+    let (image_address, extension) = loop {
+        let Some(segment) = segments.next() else {
+            return Err("missing source url".to_string());
+        };
+        if segment == "plain" {
+            // the plain source url is already percent-encoded, only the characters meaningful in
+            // a query string are left to escape
+            let url = segments.collect::<Vec<_>>().join("/");
+            let (url, extension) = match url.rsplit_once('@') {
+                Some((url, extension)) if !extension.contains('/') => {
+                    (url.to_string(), Some(extension.to_string()))
+                }
+                _ => (url, None),
+            };
+            break (escape_query_delimiters(&url), extension);
+        } else if segment == "enc" {
+            return Err("encrypted source urls aren't supported".to_string());
+        } else if let Some((name, args)) = segment.split_once(':') {
+            let args: Vec<&str> = args.split(':').collect();
+            push_option(&mut query, name, &args)?;
+        } else {
+            let encoded = std::iter::once(segment).chain(segments).collect::<String>();
+            let (encoded, extension) = match encoded.rsplit_once('.') {
+                Some((encoded, extension)) => (encoded, Some(extension.to_string())),
+                None => (encoded.as_str(), None),
+            };
+            let url = URL_SAFE_NO_PAD
+                .decode(encoded.trim_end_matches('='))
+                .ok()
+                .and_then(|url| String::from_utf8(url).ok())
+                .ok_or_else(|| format!("invalid base64 source url `{}`", encoded))?;
+            break (encode_query_value(&url), extension);
+        }
+    };
+    // the images of public_img_path are the `local://` sources of imgproxy
+    let image_address = image_address
+        .strip_prefix("local%3A%2F%2F%2F")
+        .or_else(|| image_address.strip_prefix("local://"))
+        .map(|path| path.trim_start_matches('/').to_string())
+        .unwrap_or(image_address);
+    query.insert(0, format!("image_address={}", image_address));
+    if let Some(extension) = extension {
+        query.push(format!("format={}", format_of_extension(&extension)?));
+    }
+    Ok(query.join("&"))
+}
+
+fn push_option(query: &mut Vec<String>, name: &str, args: &[&str]) -> Result<(), String> {
+    let arg = |i: usize| args.get(i).copied().filter(|arg| !arg.is_empty());
+    let mut push = |key: &str, value: String| query.push(format!("{}={}", key, value));
+    match name {
+        "resize" | "rs" | "size" | "s" => {
+            let offset = if matches!(name, "resize" | "rs") {
+                if let Some(fit) = arg(0) {
+                    push("fit", fit_of_resizing_type(fit)?.to_string());
+                }
+                1
+            } else {
+                0
+            };
+            // a dimension of 0 is computed after the other one, as when it's missing
+            for (i, key) in [(offset, "size[width]"), (offset + 1, "size[height]")] {
+                if let Some(dimension) = arg(i).filter(|dimension| *dimension != "0") {
+                    push(key, parse::<i32>(name, dimension)?.to_string());
+                }
+            }
+            if let Some(enlarge) = arg(offset + 2) {
+                push("enlarge", parse_bool(name, enlarge)?.to_string());
+            }
+            if let Some(extend) = arg(offset + 3) {
+                if parse_bool(name, extend)? {
+                    push("fit", "contain".to_string());
+                }
+            }
+        }
+        "resizing_type" | "rt" => push(
+            "fit",
+            fit_of_resizing_type(required(name, arg(0))?)?.to_string(),
+        ),
+        "width" | "w" => push(
+            "size[width]",
+            parse::<i32>(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "height" | "h" => push(
+            "size[height]",
+            parse::<i32>(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "enlarge" | "el" => push(
+            "enlarge",
+            parse_bool(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "extend" | "ex" => {
+            if parse_bool(name, required(name, arg(0))?)? {
+                push("fit", "contain".to_string());
+            }
+        }
+        "dpr" => push(
+            "dpr",
+            parse::<f64>(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "quality" | "q" => {
+            // 0 stands for the default quality
+            let quality = parse::<i32>(name, required(name, arg(0))?)?;
+            if quality > 0 {
+                push("quality", quality.to_string());
+            }
+        }
+        "format" | "f" | "ext" => push(
+            "format",
+            format_of_extension(required(name, arg(0))?)?.to_string(),
+        ),
+        "background" | "bg" => {
+            let color = match args {
+                [r, g, b] => [r, g, b]
+                    .iter()
+                    .map(|channel| parse::<u8>(name, channel).map(|c| format!("{:02x}", c)))
+                    .collect::<Result<String, String>>()?,
+                [hex] => hex.to_string(),
+                _ => return Err(format!("invalid arguments of `{}`", name)),
+            };
+            push("background", color);
+        }
+        "blur" | "bl" => push(
+            "blur",
+            parse::<f64>(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "sharpen" | "sh" => push(
+            "sharpen[sigma]",
+            parse::<f64>(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "rotate" | "rot" => match parse::<i32>(name, required(name, arg(0))?)?.rem_euclid(360) {
+            0 => {}
+            90 => push("rotation", "R90".to_string()),
+            180 => push("rotation", "R180".to_string()),
+            270 => push("rotation", "R270".to_string()),
+            _ => return Err("the rotation angle must be a multiple of 90".to_string()),
+        },
+        "flip" | "fl" => {
+            if let Some(horizontal) = arg(0) {
+                push("flop", parse_bool(name, horizontal)?.to_string());
+            }
+            if let Some(vertical) = arg(1) {
+                push("flip", parse_bool(name, vertical)?.to_string());
+            }
+        }
+        "gravity" | "g" => match required(name, arg(0))? {
+            "ce" => {}
+            "fp" => {
+                push(
+                    "fx",
+                    parse::<f64>(name, required(name, arg(1))?)?.to_string(),
+                );
+                push(
+                    "fy",
+                    parse::<f64>(name, required(name, arg(2))?)?.to_string(),
+                );
+            }
+            gravity => return Err(format!("unsupported gravity `{}`", gravity)),
+        },
+        "crop" | "c" => {
+            if let Some(width) = arg(0).filter(|width| *width != "0") {
+                push("crop[w]", parse::<i32>(name, width)?.to_string());
+            }
+            if let Some(height) = arg(1).filter(|height| *height != "0") {
+                push("crop[h]", parse::<i32>(name, height)?.to_string());
+            }
+        }
+        "trim" | "t" => {
+            push("trim", "true".to_string());
+            if let Some(threshold) = arg(0) {
+                push("trim_threshold", parse::<f64>(name, threshold)?.to_string());
+            }
+            if let Some(color) = arg(1) {
+                push("trim_background", color.to_string());
+            }
+        }
+        "strip_metadata" | "sm" => push(
+            "strip",
+            parse_bool(name, required(name, arg(0))?)?.to_string(),
+        ),
+        "auto_rotate" | "ar" => push(
+            "autorotate",
+            parse_bool(name, required(name, arg(0))?)?.to_string(),
+        ),
+        // only meant to change the urls, the caches being keyed on them
+        "cachebuster" | "cb" => {}
+        _ => return Err(format!("unsupported imgproxy option `{}`", name)),
+    }
+    Ok(())
+}
+
+fn required<'a>(name: &str, arg: Option<&'a str>) -> Result<&'a str, String> {
+    arg.ok_or_else(|| format!("missing argument of `{}`", name))
+}
+
+fn parse<T: std::str::FromStr>(name: &str, arg: &str) -> Result<T, String> {
+    arg.parse()
+        .map_err(|_| format!("invalid argument `{}` of `{}`", arg, name))
+}
+
+fn parse_bool(name: &str, arg: &str) -> Result<bool, String> {
+    match arg {
+        "1" | "t" | "true" => Ok(true),
+        "0" | "f" | "false" => Ok(false),
+        _ => Err(format!("invalid argument `{}` of `{}`", arg, name)),
+    }
+}
+
+fn fit_of_resizing_type(resizing_type: &str) -> Result<&'static str, String> {
+    match resizing_type {
+        "fit" => Ok("inside"),
+        "fill" | "fill-down" | "auto" => Ok("cover"),
+        "force" => Ok("fill"),
+        _ => Err(format!("unsupported resizing type `{}`", resizing_type)),
+    }
+}
+
+// the `format` of `/` matching a file extension
+pub(crate) fn format_of_extension(extension: &str) -> Result<&'static str, String> {
+    match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => Ok("Jpeg"),
+        "png" => Ok("Png"),
+        "webp" => Ok("Webp"),
+        "avif" => Ok("Avif"),
+        "heic" => Ok("Heic"),
+        "gif" => Ok("Gif"),
+        "jxl" => Ok("Jxl"),
+        "tif" | "tiff" => Ok("Tiff"),
+        _ => Err(format!("unsupported format `{}`", extension)),
+    }
+}
+
+// percent-encodes everything but the unreserved characters
+pub(crate) fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+pub(crate) fn escape_query_delimiters(value: &str) -> String {
+    value
+        .replace('&', "%26")
+        .replace('=', "%3D")
+        .replace('+', "%2B")
+        .replace('[', "%5B")
+        .replace(']', "%5D")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::ProcessImageRequest;
+
+    fn request(path: &str) -> ProcessImageRequest {
+        serde_qs::from_str(&imgproxy_query(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_plain_source() {
+        let params = request("rs:fill:300:200/q:70/plain/http://example.com/images/cat.jpg@webp");
+        assert_eq!(params.image_address, "http://example.com/images/cat.jpg");
+        assert_eq!(params.size.width, Some(300));
+        assert_eq!(params.size.height, Some(200));
+        assert_eq!(params.fit, crate::commons::Fit::Cover);
+        assert_eq!(params.format, crate::commons::ImageFormat::Webp);
+        assert!(matches!(
+            params.quality,
+            Some(crate::commons::Quality::Value(70))
+        ));
+
+        let params = request("w:300/plain/http%3A%2F%2Fexample.com%2Fa.jpg%3Fv%3D1%26w%3D2");
+        assert_eq!(params.image_address, "http://example.com/a.jpg?v=1&w=2");
+        assert_eq!(params.size.height, None);
+        assert_eq!(request("plain/local:///img-test").image_address, "img-test");
+    }
+
+    #[test]
+    fn test_base64_source() {
+        let encoded = URL_SAFE_NO_PAD.encode("http://example.com/a b.jpg?v=1");
+        let (head, tail) = encoded.split_at(10);
+        let params = request(&format!("rt:force/s:100:0/{}/{}.png", head, tail));
+        assert_eq!(params.image_address, "http://example.com/a b.jpg?v=1");
+        assert_eq!(params.fit, crate::commons::Fit::Fill);
+        assert_eq!(params.size.width, Some(100));
+        assert_eq!(params.size.height, None);
+        assert_eq!(params.format, crate::commons::ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_options() {
+        let params = request("bg:255:0:16/rot:-90/fl:1:0/g:fp:0.2:0.8/sm:1/cb:123/plain/a.jpg");
+        assert_eq!(params.background.0, [255, 0, 16, 255]);
+        assert_eq!(params.rotation, Some(crate::commons::Rotation::R270));
+        assert!(params.flop && !params.flip);
+        assert_eq!((params.fx, params.fy), (Some(0.2), Some(0.8)));
+        assert_eq!(params.strip, Some(true));
+
+        assert!(imgproxy_query("pr:thumbnail/plain/a.jpg").is_err());
+        assert!(imgproxy_query("g:sm/plain/a.jpg").is_err());
+        assert!(imgproxy_query("rs:fit:300:200").is_err());
+        assert!(imgproxy_query("plain/a.jpg@bmp").is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        let mut config = Configuration::for_tests(serde_json::json!({}));
+        assert!(has_valid_signature(
+            &config,
+            "insecure",
+            "w:300/plain/a.jpg"
+        ));
+
+        // the key and the salt of the imgproxy documentation
+        config.imgproxy_key =
+            Some("943b421c9eb07c830af81030552c86009268de4e532ba2ee2eab8247c6da0881".to_string());
+        config.imgproxy_salt =
+            Some("520f986b998545b4785e0defbc4f3c1203f22de2374a3d53cb7a7fe9fea309c5".to_string());
+        let path =
+            "rs:fill:300:400:0/g:sm/aHR0cDovL2V4YW1w/bGUuY29tL2ltYWdl/cy9jdXJpb3NpdHku/anBn.png";
+        assert!(has_valid_signature(
+            &config,
+            "90UxdwGRAI2bpLSHKkZculJau5ahfxfS0h3fMuQAf40",
+            path
+        ));
+        assert!(!has_valid_signature(&config, "insecure", path));
+    }
+}
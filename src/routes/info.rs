@@ -0,0 +1,69 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{commons::signature::sniff_image_format, image_processor, AppState};
+
+use super::image::{
+    local_image_path, reject_purged, run_on_worker, validate_pixels, validate_source,
+    ImageProcessingError, ProcessImageRequestExtractor,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct InfoRequest {
+    #[serde(alias = "image_address")]
+    pub image: String,
+}
+
+// describes the source image from its header only, which libvips reads without decoding the
+// pixels, so that the clients can decide how to crop and transform it before requesting it. the
+// header is read on the workers all the same, as ImageMagick develops the RAW photos to read it.
+pub async fn handle_info(
+    State(state): State<AppState>,
+    ProcessImageRequestExtractor { params, .. }: ProcessImageRequestExtractor<InfoRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let filepath = local_image_path(&state.public_img_path, &params.image)?;
+    reject_purged(&state, &params.image, &filepath).await?;
+    let source = state.image_provider.get_file(&params.image).await?;
+    validate_source(&source, &params.image)?;
+    validate_pixels(&source, &params.image, &state.config, None, false)?;
+    let (format, file_size) = (sniff_image_format(&source), source.len());
+    let config = state.config.clone();
+    let info = run_on_worker(&state, params.image.clone(), move |_| {
+        image_processor::read_image_info(&source, &config)
+    })
+    .await?;
+
+    let body = json!({
+        "width": info.width,
+        "height": info.height,
+        "pages": info.pages,
+        "format": format,
+        "orientation": info.orientation,
+        "color_space": info.interpretation,
+        "has_alpha": info.has_alpha,
+        "file_size": file_size,
+    })
+    .to_string();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_address_alias() {
+        let params: InfoRequest = serde_qs::from_str("image=a.jpg").unwrap();
+        assert_eq!(params.image, "a.jpg");
+        let params: InfoRequest = serde_qs::from_str("image_address=b.jpg").unwrap();
+        assert_eq!(params.image, "b.jpg");
+    }
+}
@@ -5,7 +5,10 @@ use axum::{
 };
 use lazy_static::lazy_static;
 use log::error;
-use prometheus::{register_histogram_vec, Encoder, HistogramVec, TextEncoder};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
 use prometheus_static_metric::make_static_metric;
 
 make_static_metric! {
@@ -27,6 +30,10 @@ make_static_metric! {
             png,
             webp,
             heic,
+            avif,
+            gif,
+            jxl,
+            tiff,
         }
     }
     pub struct OutputSize: Histogram {
@@ -35,6 +42,10 @@ make_static_metric! {
             png,
             webp,
             heic,
+            avif,
+            gif,
+            jxl,
+            tiff,
         }
     }
 }
@@ -64,6 +75,24 @@ lazy_static! {
         &["format"]
     )
     .expect("Cannot register metric");
+    pub static ref PROVIDER_FETCH_DURATION_VEC: HistogramVec = register_histogram_vec!(
+        "dali_provider_fetch_duration",
+        "Duration of the fetches of the sources per provider and origin host.",
+        &["provider", "host"]
+    )
+    .expect("Cannot register metric");
+    pub static ref PROVIDER_FETCHED_BYTES_VEC: IntCounterVec = register_int_counter_vec!(
+        "dali_provider_fetched_bytes",
+        "Number of bytes fetched per provider and origin host.",
+        &["provider", "host"]
+    )
+    .expect("Cannot register metric");
+    pub static ref PROVIDER_FETCH_ERRORS_VEC: IntCounterVec = register_int_counter_vec!(
+        "dali_provider_fetch_errors",
+        "Number of failed fetches per provider, origin host and class of error.",
+        &["provider", "host", "error"]
+    )
+    .expect("Cannot register metric");
     pub static ref HTTP_DURATION: HttpRequestDuration =
         HttpRequestDuration::from(&HTTP_DURATION_VEC);
     pub static ref FETCH_DURATION: FetchRequestDuration =
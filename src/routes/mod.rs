@@ -1,2 +1,12 @@
+pub mod collage;
+pub mod image;
+pub mod imgproxy;
+pub mod info;
 pub mod metric;
-pub mod image;
\ No newline at end of file
+pub mod purge;
+pub mod readiness;
+pub mod stats;
+pub mod store;
+pub mod thumbor;
+pub mod upload;
+pub mod version;
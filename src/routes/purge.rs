@@ -0,0 +1,42 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+};
+use log::*;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::commons::tombstone;
+use crate::AppState;
+
+use super::image::{local_image_path, ImageProcessingError, ProcessImageRequestExtractor};
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeRequest {
+    pub image_address: String,
+}
+
+pub async fn handle_purge(
+    State(state): State<AppState>,
+    ProcessImageRequestExtractor { params, .. }: ProcessImageRequestExtractor<PurgeRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let path = local_image_path(&state.config.public_img_path, &params.image_address)?;
+    // the cached copies are dropped even when the tombstone can't be written
+    state.image_provider.evict(&params.image_address).await;
+    if let Err(e) = tombstone::purge(&path).await {
+        error!(
+            "failed to purge the image '{}'. error: {}",
+            params.image_address, e
+        );
+        let body = json!({ "error": "The image couldn't be purged." }).to_string();
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))?);
+    }
+    info!("purged the image '{}'", params.image_address);
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?)
+}
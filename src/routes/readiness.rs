@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use log::*;
+use serde_json::json;
+
+use crate::image_provider::ImageProvider;
+
+// answers 503 while the storage backend of the image provider can't be reached, so that the
+// orchestrators don't route traffic to an instance which can't serve any image
+pub async fn handle_readiness(
+    State(image_provider): State<Arc<Box<dyn ImageProvider>>>,
+) -> impl IntoResponse {
+    let (status, body) = match image_provider.ready().await {
+        Ok(()) => (StatusCode::OK, json!({ "ready": true })),
+        Err(reason) => {
+            warn!("the image provider is not ready. reason: {}", reason);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "ready": false, "error": reason }),
+            )
+        }
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,56 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+};
+use serde_json::json;
+
+use crate::{image_processor::stats::read_image_stats, AppState};
+
+use super::image::{
+    local_image_path, reject_purged, run_on_worker, validate_pixels, validate_source,
+    ImageProcessingError, ProcessImageRequestExtractor,
+};
+use super::info::InfoRequest;
+
+// the per band statistics and histograms of the pixels of the source image, which is decoded in
+// full unlike for `/info`
+pub async fn handle_stats(
+    State(state): State<AppState>,
+    ProcessImageRequestExtractor { params, .. }: ProcessImageRequestExtractor<InfoRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let filepath = local_image_path(&state.public_img_path, &params.image)?;
+    reject_purged(&state, &params.image, &filepath).await?;
+    let source = state.image_provider.get_file(&params.image).await?;
+    validate_source(&source, &params.image)?;
+    validate_pixels(&source, &params.image, &state.config, None, false)?;
+    let config = state.config.clone();
+    let stats = run_on_worker(&state, params.image.clone(), move |kill_switch| {
+        read_image_stats(&source, &config, kill_switch)
+    })
+    .await?;
+
+    let bands: Vec<_> = stats
+        .bands
+        .iter()
+        .map(|band| {
+            json!({
+                "min": band.min,
+                "max": band.max,
+                "mean": band.mean,
+                "stddev": band.stddev,
+                "histogram": band.histogram,
+            })
+        })
+        .collect();
+    let body = json!({
+        "width": stats.width,
+        "height": stats.height,
+        "bands": bands,
+    })
+    .to_string();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?)
+}
@@ -0,0 +1,55 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Multipart, State},
+    http::{header::LOCATION, Response, StatusCode, Uri},
+};
+use serde::Deserialize;
+use serde_json::json;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::AppState;
+
+use super::image::{process, ImageProcessingError};
+use super::upload::{parse_params, read_sources};
+
+#[derive(Deserialize, Default)]
+struct StoreParams {
+    key: Option<String>,
+}
+
+// processes an uploaded image like `/process` does, but writes the result to the image sink
+// instead of sending it back. the result is stored under the `key` query parameter, or under the
+// hash of its content when there's none.
+pub async fn store_upload(
+    State(state): State<AppState>,
+    uri: Uri,
+    multipart: Multipart,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let Some(image_sink) = state.image_sink.clone() else {
+        return Err(ImageProcessingError::InvalidUpload(String::from(
+            "no image sink is configured",
+        )));
+    };
+    let params = parse_params(uri.query())?;
+    let store_params: StoreParams =
+        serde_qs::from_str(uri.query().unwrap_or_default()).unwrap_or_default();
+    let sources = read_sources(&state, &params, multipart).await?;
+    let processed_image = process(&state, params, sources).await?;
+
+    let content_type = format!("image/{}", processed_image.format);
+    let bytes: Vec<u8> = processed_image.bytes.into();
+    let key = store_params
+        .key
+        .filter(|key| !key.is_empty())
+        .unwrap_or_else(|| format!("{:016x}.{}", xxh3_64(&bytes), processed_image.format));
+    let location = image_sink
+        .store(&key, Bytes::from(bytes), &content_type)
+        .await?;
+
+    let body = json!({ "key": key, "location": location }).to_string();
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, location)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?)
+}
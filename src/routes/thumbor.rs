@@ -0,0 +1,370 @@
+// the thumbor url syntax, `/<unsafe|signature>/trim/AxB:CxD/fit-in/-Wx-H/halign/valign/smart/
+// filters:name(args):.../<image>`, every part but the image being optional, translated into the
+// query of `/` so that the thumbor clients can be pointed at dali without changing their urls
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, Response, StatusCode, Uri},
+    response::IntoResponse,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha1::Sha1;
+
+use crate::{commons::config::Configuration, AppState};
+
+use super::image::{self, ProcessImageRequestExtractor};
+use super::imgproxy::{escape_query_delimiters, format_of_extension};
+
+pub async fn handle_thumbor(
+    State(state): State<AppState>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let prefix = state.config.thumbor_prefix.as_deref().unwrap_or_default();
+    let path = uri
+        .path()
+        .strip_prefix(prefix.trim_end_matches('/'))
+        .unwrap_or_default();
+    let Some((signature, path)) = path.trim_start_matches('/').split_once('/') else {
+        return (StatusCode::NOT_FOUND, "missing thumbor image path").into_response();
+    };
+    if !has_valid_signature(&state.config, signature, path) {
+        warn!("the thumbor signature of '{}' doesn't match", path);
+        return (StatusCode::FORBIDDEN, "invalid thumbor signature").into_response();
+    }
+    let query = match thumbor_query(path) {
+        Ok(query) => query,
+        Err(e) => {
+            warn!("failed to translate the thumbor path '{}': {}", path, e);
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    match ProcessImageRequestExtractor::from_query(Some(&query), &headers) {
+        Ok(request) => image::process_image(State(state), request)
+            .await
+            .into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// the `unsafe` paths are accepted unless `thumbor_allow_unsafe` is false, the signed ones only
+// when they're signed with `thumbor_security_key`
+fn has_valid_signature(config: &Configuration, signature: &str, path: &str) -> bool {
+    if signature == "unsafe" {
+        return config.thumbor_allow_unsafe.unwrap_or(true);
+    }
+    let Some(key) = &config.thumbor_security_key else {
+        return false;
+    };
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature.trim_end_matches('=')) else {
+        return false;
+    };
+    // thumbor signs the paths with HMAC-SHA1
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(path.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+// the query of `/` matching the options and the image of a thumbor path
+pub fn thumbor_query(path: &str) -> Result<String, String> {
+    let mut query = vec![];
+    let mut segments = path.split('/').peekable();
+    let mut fit_in = None;
+    let mut size = None;
+    while let Some(segment) = segments.peek().copied() {
+        if segment == "meta" {
+            return Err("the metadata endpoint isn't supported".to_string());
+        } else if segment == "trim" || segment.starts_with("trim:") {
+            query.push("trim=true".to_string());
+            // the tolerance comes after the corner the color is picked from
+            if let Some(tolerance) = segment.split(':').nth(2) {
+                query.push(format!(
+                    "trim_threshold={}",
+                    parse::<f64>("trim", tolerance)?
+                ));
+            }
+        } else if let Some(rect) = parse_crop(segment) {
+            query.push(format!("crop={}", rect?));
+        } else if let Some(fit) = fit_of_fit_in(segment) {
+            fit_in = Some(fit);
+        } else if let Some(dimensions) = parse_size(segment) {
+            size = Some(dimensions);
+        } else if let Some(fx) = match segment {
+            "left" => Some("0"),
+            "center" => Some("0.5"),
+            "right" => Some("1"),
+            _ => None,
+        } {
+            query.push(format!("fx={}", fx));
+        } else if let Some(fy) = match segment {
+            "top" => Some("0"),
+            "middle" => Some("0.5"),
+            "bottom" => Some("1"),
+            _ => None,
+        } {
+            query.push(format!("fy={}", fy));
+        } else if segment == "smart" {
+            // accepted for the sake of the existing urls, the resized images being cropped
+            // around their center or their focal point all the same
+        } else if let Some(filters) = segment.strip_prefix("filters:") {
+            // the arguments of the filters may contain slashes, such as the urls of watermarks
+            let mut filters = filters.to_string();
+            segments.next();
+            while filters.matches('(').count() > filters.matches(')').count() {
+                let Some(segment) = segments.next() else {
+                    return Err("unbalanced filters".to_string());
+                };
+                filters.push('/');
+                filters.push_str(segment);
+            }
+            push_filters(&mut query, &filters)?;
+            continue;
+        } else {
+            break;
+        }
+        segments.next();
+    }
+
+    let image_address = segments.collect::<Vec<_>>().join("/");
+    if image_address.is_empty() {
+        return Err("missing image".to_string());
+    }
+    if let Some(((width, flop), (height, flip))) = size {
+        if let Some(width) = width {
+            query.push(format!("size[width]={}", width));
+        }
+        if let Some(height) = height {
+            query.push(format!("size[height]={}", height));
+        }
+        // the image is cropped to the size unless it's fitted in, as long as both are given
+        let fit = match fit_in {
+            Some(fit) => fit,
+            None if width.is_some() && height.is_some() => "cover",
+            None => "inside",
+        };
+        query.push(format!("fit={}", fit));
+        query.push(format!("flop={}", flop));
+        query.push(format!("flip={}", flip));
+    }
+    // the image is already percent-encoded, only the characters meaningful in a query string are
+    // left to escape
+    query.insert(
+        0,
+        format!("image_address={}", escape_query_delimiters(&image_address)),
+    );
+    Ok(query.join("&"))
+}
+
+// `AxB:CxD`, the top left and the bottom right corners of the rectangle
+// `LxT:RxB`, the corners of the crop. a segment which isn't shaped like it is left to the other
+// options, while an empty or reversed rectangle is rejected.
+fn parse_crop(segment: &str) -> Option<Result<String, String>> {
+    let (top_left, bottom_right) = segment.split_once(':')?;
+    let corner = |corner: &str| -> Option<(i32, i32)> {
+        let (x, y) = corner.split_once('x')?;
+        Some((x.parse().ok()?, y.parse().ok()?))
+    };
+    let ((left, top), (right, bottom)) = (corner(top_left)?, corner(bottom_right)?);
+    let width = right.checked_sub(left).filter(|width| *width > 0);
+    let height = bottom.checked_sub(top).filter(|height| *height > 0);
+    Some(match (width, height) {
+        (Some(width), Some(height)) => Ok(format!("{},{},{},{}", left, top, width, height)),
+        _ => Err(format!("invalid crop `{}`", segment)),
+    })
+}
+
+fn fit_of_fit_in(segment: &str) -> Option<&'static str> {
+    match segment.strip_prefix("adaptive-").unwrap_or(segment) {
+        "fit-in" => Some("inside"),
+        "full-fit-in" => Some("outside"),
+        _ => None,
+    }
+}
+
+type Dimension = (Option<i32>, bool);
+
+// `[-]Wx[-]H`, a negative dimension mirroring the image and a missing, `0` or `orig` one being
+// computed after the other
+fn parse_size(segment: &str) -> Option<(Dimension, Dimension)> {
+    let (width, height) = segment.split_once('x')?;
+    let dimension = |dimension: &str| -> Option<Dimension> {
+        let (dimension, mirrored) = match dimension.strip_prefix('-') {
+            Some(dimension) => (dimension, true),
+            None => (dimension, false),
+        };
+        match dimension {
+            "" | "0" | "orig" => Some((None, mirrored)),
+            dimension => Some((Some(dimension.parse().ok()?), mirrored)),
+        }
+    };
+    Some((dimension(width)?, dimension(height)?))
+}
+
+fn push_filters(query: &mut Vec<String>, filters: &str) -> Result<(), String> {
+    let mut rest = filters;
+    while !rest.is_empty() {
+        let (name, after) = rest
+            .split_once('(')
+            .ok_or_else(|| format!("invalid filters `{}`", filters))?;
+        let (args, after) = after
+            .split_once(')')
+            .ok_or_else(|| format!("invalid filters `{}`", filters))?;
+        push_filter(query, name, args)?;
+        rest = after.strip_prefix(':').unwrap_or(after);
+    }
+    Ok(())
+}
+
+fn push_filter(query: &mut Vec<String>, name: &str, args: &str) -> Result<(), String> {
+    let args: Vec<&str> = args.split(',').map(str::trim).collect();
+    let arg = |i: usize| {
+        args.get(i)
+            .copied()
+            .filter(|arg| !arg.is_empty())
+            .ok_or_else(|| format!("missing argument of `{}`", name))
+    };
+    // the adjustments of thumbor go from -100 to 100, 0 keeping the image as is
+    let factor = |amount: &str| parse::<f64>(name, amount).map(|amount| 1.0 + amount / 100.0);
+    match name {
+        "quality" => query.push(format!("quality={}", parse::<i32>(name, arg(0)?)?)),
+        "format" => query.push(format!("format={}", format_of_extension(arg(0)?)?)),
+        "blur" => {
+            // the radius, when the sigma isn't given
+            let sigma = arg(1).or_else(|_| arg(0))?;
+            query.push(format!("blur={}", parse::<f64>(name, sigma)?));
+        }
+        "sharpen" => {
+            query.push(format!("sharpen[amount]={}", parse::<f64>(name, arg(0)?)?));
+            query.push(format!("sharpen[sigma]={}", parse::<f64>(name, arg(1)?)?));
+        }
+        "rotate" => match parse::<i32>(name, arg(0)?)?.rem_euclid(360) {
+            0 => {}
+            90 => query.push("rotation=R90".to_string()),
+            180 => query.push("rotation=R180".to_string()),
+            270 => query.push("rotation=R270".to_string()),
+            _ => return Err("the rotation angle must be a multiple of 90".to_string()),
+        },
+        "grayscale" => query.push("filter=greyscale".to_string()),
+        "brightness" => query.push(format!("brightness={}", factor(arg(0)?)?)),
+        "contrast" => query.push(format!("contrast={}", factor(arg(0)?)?)),
+        "saturation" => query.push(format!("saturation={}", parse::<f64>(name, arg(0)?)?)),
+        "fill" | "background_color" => {
+            query.push(format!("background={}", arg(0)?.trim_start_matches('#')))
+        }
+        "strip_exif" | "strip_icc" => query.push("strip=true".to_string()),
+        "no_upscale" => query.push("enlarge=false".to_string()),
+        "upscale" => query.push("enlarge=true".to_string()),
+        _ => return Err(format!("unsupported thumbor filter `{}`", name)),
+    }
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(name: &str, arg: &str) -> Result<T, String> {
+    arg.parse()
+        .map_err(|_| format!("invalid argument `{}` of `{}`", arg, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::{CropRect, Fit, ImageFormat, ProcessImageRequest, Quality, Rotation};
+
+    fn request(path: &str) -> ProcessImageRequest {
+        serde_qs::from_str(&thumbor_query(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_size() {
+        let params = request("300x200/smart/http://example.com/images/cat.jpg");
+        assert_eq!(params.image_address, "http://example.com/images/cat.jpg");
+        assert_eq!(
+            (params.size.width, params.size.height),
+            (Some(300), Some(200))
+        );
+        assert_eq!(params.fit, Fit::Cover);
+
+        let params = request("fit-in/-300x0/example.com/a%26b.jpg");
+        assert_eq!(params.image_address, "example.com/a&b.jpg");
+        assert_eq!((params.size.width, params.size.height), (Some(300), None));
+        assert_eq!(params.fit, Fit::Inside);
+        assert!(params.flop && !params.flip);
+
+        let params = request("trim/10x20:110x220/full-fit-in/origx-50/left/bottom/a.jpg");
+        assert!(params.trim);
+        assert_eq!(
+            params.crop.rect,
+            Some(CropRect {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 200
+            })
+        );
+        assert_eq!(params.fit, Fit::Outside);
+        assert!(params.flip);
+        assert_eq!((params.fx, params.fy), (Some(0.0), Some(1.0)));
+
+        assert!(thumbor_query("-2147483648x0:2147483647x0/a.jpg").is_err());
+        assert!(thumbor_query("110x220:10x20/a.jpg").is_err());
+        assert!(thumbor_query("10x20:10x220/a.jpg").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        let params = request(
+            "filters:quality(70):format(webp):rotate(-90):brightness(20):fill(ff0010)/a.jpg",
+        );
+        assert!(matches!(params.quality, Some(Quality::Value(70))));
+        assert_eq!(params.format, ImageFormat::Webp);
+        assert_eq!(params.rotation, Some(Rotation::R270));
+        assert_eq!(params.brightness, Some(1.2));
+        assert_eq!(params.background.0, [255, 0, 16, 255]);
+
+        assert!(
+            thumbor_query("filters:watermark(http://example.com/wm.png,0,0,50)/a.jpg")
+                .unwrap_err()
+                .contains("watermark")
+        );
+        assert!(thumbor_query("filters:quality(70)").is_err());
+        assert!(thumbor_query("meta/a.jpg").is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        let mut config = Configuration::for_tests(serde_json::json!({}));
+        let path = "300x200/smart/example.com/a.jpg";
+        assert!(has_valid_signature(&config, "unsafe", path));
+        assert!(!has_valid_signature(
+            &config,
+            "vOFBg7Wjj0K4ffjHVTZ7ZmTKJvc=",
+            path
+        ));
+
+        config.thumbor_security_key = Some("MY_SECURE_KEY".to_string());
+        config.thumbor_allow_unsafe = Some(false);
+        assert!(has_valid_signature(
+            &config,
+            "vOFBg7Wjj0K4ffjHVTZ7ZmTKJvc=",
+            path
+        ));
+        assert!(!has_valid_signature(
+            &config,
+            "wOFBg7Wjj0K4ffjHVTZ7ZmTKJvc=",
+            path
+        ));
+        assert!(!has_valid_signature(&config, "unsafe", path));
+
+        // the keys longer than a block are hashed beforehand
+        config.thumbor_security_key = Some("k".repeat(100));
+        assert!(has_valid_signature(
+            &config,
+            "kae6i-65PdP73QlV9BXubk8pg5M=",
+            path
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{
+    body::Body,
+    extract::{multipart::Field, Multipart, State},
+    http::{Response, Uri},
+};
+use log::*;
+
+use crate::{
+    commons::ProcessImageRequest,
+    image_provider::{ImageBuffer, DEFAULT_MAX_SOURCE_SIZE_BYTES},
+    AppState,
+};
+
+use super::image::{process, respond, FetchedSources, ImageProcessingError};
+
+// the label of the uploaded image wherever an image_address is expected, e.g. in the logs
+const UPLOADED_IMAGE_ADDRESS: &str = "upload";
+const IMAGE_FIELD: &str = "image";
+const WATERMARK_FIELD: &str = "watermark";
+
+// processes an image sent as the `image` field of a multipart/form-data body, along with the
+// usual query parameters. the `watermark` files stand in, in order, for the watermarks of the
+// query string which don't have an image_address, the other ones are fetched as usual.
+pub async fn process_upload(
+    State(state): State<AppState>,
+    uri: Uri,
+    multipart: Multipart,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let params = parse_params(uri.query())?;
+    let sources = read_sources(&state, &params, multipart).await?;
+    let last_modified = sources.last_modified;
+    let processed_image = process(&state, params, sources).await?;
+    respond(&state.config, last_modified, processed_image)
+}
+
+// the uploaded image and watermarks, along with the watermarks fetched from their image_address
+pub(crate) async fn read_sources(
+    state: &AppState,
+    params: &ProcessImageRequest,
+    mut multipart: Multipart,
+) -> Result<FetchedSources, ImageProcessingError> {
+    let max_size = state.config.max_source_size_bytes;
+
+    let mut main_img = None;
+    let mut uploaded_watermarks = vec![];
+    while let Some(field) = multipart.next_field().await.map_err(|e| {
+        error!("failed to read the multipart body. error: {}", e);
+        ImageProcessingError::InvalidUpload(e.body_text())
+    })? {
+        match field.name() {
+            Some(IMAGE_FIELD) => main_img = Some(read_field(field, max_size).await?),
+            Some(WATERMARK_FIELD) => uploaded_watermarks.push(read_field(field, max_size).await?),
+            name => debug!("ignoring the unknown multipart field {:?}", name),
+        }
+    }
+    let Some(main_img) = main_img else {
+        return Err(ImageProcessingError::InvalidUpload(format!(
+            "the '{}' field is missing",
+            IMAGE_FIELD
+        )));
+    };
+
+    let mut uploaded_watermarks = uploaded_watermarks.into_iter();
+    let mut watermarks = vec![];
+    for watermark in &params.watermarks {
+        let fetched = if watermark.image_address.is_empty() {
+            uploaded_watermarks.next().ok_or_else(|| {
+                ImageProcessingError::InvalidUpload(format!(
+                    "a '{}' field is missing for a watermark without an image_address",
+                    WATERMARK_FIELD
+                ))
+            })
+        } else {
+            state
+                .image_provider
+                .get_file(&watermark.image_address)
+                .await
+        };
+        match fetched {
+            Ok(fetched) => watermarks.push(Some(fetched)),
+            Err(e @ ImageProcessingError::InvalidUpload(_)) => return Err(e),
+            Err(e) => {
+                warn!("failed to download watermark with error {}", e);
+                watermarks.push(None);
+            }
+        }
+    }
+
+    let total_input_size =
+        main_img.len() + watermarks.iter().flatten().map(|w| w.len()).sum::<usize>();
+    Ok(FetchedSources {
+        main_img,
+        watermarks,
+        last_modified: state.clock.now(),
+        total_input_size,
+    })
+}
+
+pub(crate) fn parse_params(
+    query: Option<&str>,
+) -> Result<ProcessImageRequest, ImageProcessingError> {
+    let query = query.unwrap_or_default();
+    let has_address = query
+        .split('&')
+        .any(|param| param.starts_with("image_address="));
+    let query = if has_address {
+        query.to_string()
+    } else {
+        format!("image_address={}&{}", UPLOADED_IMAGE_ADDRESS, query)
+    };
+    serde_qs::from_str(&query).map_err(|e| {
+        ImageProcessingError::InvalidUpload(format!(
+            "the provided parameters within the query string aren't valid: {}",
+            e
+        ))
+    })
+}
+
+async fn read_field(
+    mut field: Field<'_>,
+    max_size: Option<u64>,
+) -> Result<ImageBuffer, ImageProcessingError> {
+    let max_size = max_size.unwrap_or(DEFAULT_MAX_SOURCE_SIZE_BYTES);
+    let mut body = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(|e| {
+        error!("failed to read the uploaded image. error: {}", e);
+        ImageProcessingError::InvalidUpload(e.body_text())
+    })? {
+        if (body.len() + chunk.len()) as u64 > max_size {
+            error!("the uploaded image exceeds the limit of {} bytes", max_size);
+            return Err(ImageProcessingError::SourceTooLarge(max_size));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(ImageBuffer::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_params() {
+        let params = parse_params(Some("size[width]=100&watermarks[0][alpha]=0.5")).unwrap();
+        assert_eq!(params.image_address, UPLOADED_IMAGE_ADDRESS);
+        assert_eq!(params.size.width, Some(100));
+        assert!(params.watermarks[0].image_address.is_empty());
+        let params = parse_params(Some("image_address=avatar.png")).unwrap();
+        assert_eq!(params.image_address, "avatar.png");
+        assert_eq!(
+            parse_params(None).unwrap().image_address,
+            UPLOADED_IMAGE_ADDRESS
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use serde_json::json;
+
+use crate::commons::hardware::HardwareInfo;
+
+pub async fn handle_version(State(hardware): State<Arc<HardwareInfo>>) -> impl IntoResponse {
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "libvips": hardware.libvips_version,
+        "vector_enabled": hardware.vector_enabled,
+        "probe_duration_millis": hardware.probe_duration_millis,
+    })
+    .to_string();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}